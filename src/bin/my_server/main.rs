@@ -1,24 +1,28 @@
 use async_std::task;
-use libsip::{Domain, Transport, UriSchema};
+use libsip::{Domain, UriSchema};
 use sip_server::Server;
 use std::env;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 mod my_client;
 mod my_client_factory;
+mod my_config;
 mod my_system;
 
 use my_client_factory::MyClientFactory;
+use my_config::MyConfig;
+use my_system::MySystem;
 
 fn main() {
     let mut args = env::args();
     let _ = args.next();
     let ip = args.next();
     let port = args.next();
+    let config_path = args.next();
     let (ip, port) = if let (Some(ip), Some(port)) = (ip, port) {
         (ip.parse::<Ipv4Addr>(), port.parse::<u16>())
     } else {
-        eprintln!("<ip> <port>");
+        eprintln!("<ip> <port> [config file]");
         return;
     };
     let (ip, port) = if let (Ok(ip), Ok(port)) = (ip, port) {
@@ -28,12 +32,30 @@ fn main() {
         return;
     };
     env_logger::init();
+    let config = if let Some(path) = config_path {
+        match MyConfig::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Failed to load config: {}", e);
+                return;
+            }
+        }
+    } else {
+        MyConfig::default()
+    };
+    let system = match MySystem::new(&config, ip) {
+        Ok(system) => system,
+        Err(e) => {
+            eprintln!("Failed to initialize: {}", e);
+            return;
+        }
+    };
     let address = SocketAddr::new(IpAddr::V4(ip), port);
     let factory = MyClientFactory::new(
-        Transport::Udp,
         UriSchema::Sip,
         Domain::Ipv4(ip, Some(port)),
-        true,
+        config.back_to_back,
+        system,
     );
     let _ = task::block_on(Server::run(factory, address));
 }
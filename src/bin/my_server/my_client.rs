@@ -1,30 +1,67 @@
-use crate::my_system::MySystem;
+use crate::{
+    my_config::ForeignDomainPolicy,
+    my_system::{MySystem, TrunkCall},
+};
 use async_std::net::SocketAddr;
 use async_trait::async_trait;
 use libsip::{
-    Domain, Header, Method, NamedHeader, RegisterRequestExt, RequestGenerator, ResponseGenerator,
-    SipMessage, SipMessageExt, SubscriptionState, Transport, Uri, UriAuth, UriParam, UriSchema,
-    ViaHeader,
+    Domain, Header, Headers, Method, NamedHeader, RegisterRequestExt, RequestGenerator,
+    ResponseGenerator, SipMessage, SipMessageExt, SubscriptionState, Transport, Uri, UriAuth,
+    UriParam, UriSchema, ViaHeader,
 };
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use sip_server::{
-    Client, ClientEvent, ClientEventHandler, DialogInfo, IncompleteDialogInfo, Utils,
+    AuthError, Binding, Client, ClientEvent, ClientEventHandler, Connection, ContactInfo,
+    DialogInfo, Dispatcher, Flow, Forking, IncompleteDialogInfo, MediaRelay, RegInfo,
+    RegSubscription, ResponseAction, Route, SessionDescription, Trunk, TrunkCSeqs, Trunks, Utils,
+    ViaBranchGenerator,
 };
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
+
+/// What a timer set by the client is for
+#[derive(Debug)]
+enum Timer {
+    /// The back-end is probed with OPTIONS
+    Probe,
+    /// The branch of a fork sent to the client rings too long
+    Ring { key: String, branch: String },
+    /// The fork of the client's INVITE may have to give up (Timer C)
+    Fork { key: String },
+    /// A binding of the user may have expired
+    BindingExpiry { aor: String },
+    /// The subscription to registrations with the Call-ID may have expired
+    SubscriptionExpiry { call_id: String },
+}
 
 pub struct MyClient<'a> {
     address: SocketAddr,
     transport: Transport,
+    /// Whether the client is a trusted peer that doesn't have to authenticate
+    trusted: bool,
     schema: UriSchema,
     domain: Domain,
     utils: Arc<Utils>,
     event_handler: Box<dyn ClientEventHandler + 'a>,
     system: Arc<MySystem>,
     back_to_back: bool,
+    /// Timers that haven't expired yet by their IDs
+    timers: HashMap<u64, Timer>,
+    next_timer_id: u64,
+    /// Call-ID of the OPTIONS probes if the client is a back-end of the dispatcher
+    probe_call_id: String,
+    probe_cseq: u32,
+    /// Whether the last probe hasn't been answered yet
+    probe_pending: bool,
 }
 
 #[async_trait]
 impl<'a> Client for MyClient<'a> {
+    async fn on_start(&mut self) {
+        if self.is_backend() {
+            self.probe().await;
+        }
+    }
+
     async fn on_msg(&mut self, msg: SipMessage) {
         let method = if let Some(method) = msg.method() {
             method
@@ -34,6 +71,10 @@ impl<'a> Client for MyClient<'a> {
         };
         if msg.is_request() {
             match method {
+                // A dispatcher passes registrations on to the back-ends
+                Method::Register if self.system.dispatcher.is_some() && !self.is_backend() => {
+                    self.dispatch_register(msg).await;
+                }
                 Method::Register => {
                     self.on_register(msg).await;
                 }
@@ -53,15 +94,39 @@ impl<'a> Client for MyClient<'a> {
                 }
             }
         } else {
+            if method == Method::Notify && self.is_reg_notify_response(&msg).await {
+                return;
+            }
             match method {
-                Method::Invite | Method::Bye | Method::Cancel | Method::Refer | Method::Notify => {
+                Method::Invite
+                | Method::Bye
+                | Method::Cancel
+                | Method::Refer
+                | Method::Notify
+                | Method::Register => {
                     self.route_response(msg).await;
                 }
+                Method::Options => {
+                    self.on_probe_response(&msg);
+                }
                 _ => {}
             }
         }
     }
 
+    async fn on_timer(&mut self, id: u64) {
+        match self.timers.remove(&id) {
+            Some(Timer::Probe) => self.probe().await,
+            Some(Timer::Ring { key, branch }) => self.on_ring_timeout(&key, &branch).await,
+            Some(Timer::Fork { key }) => self.on_fork_timeout(key).await,
+            Some(Timer::BindingExpiry { aor }) => self.expire_bindings(&aor).await,
+            Some(Timer::SubscriptionExpiry { call_id }) => {
+                self.on_reg_subscription_expiry(&call_id).await
+            }
+            None => {}
+        }
+    }
+
     async fn on_routed_msg(&mut self, msg: SipMessage) {
         if msg.is_request() {
             self.on_routed_request(msg).await;
@@ -69,12 +134,64 @@ impl<'a> Client for MyClient<'a> {
             self.on_routed_response(msg).await;
         }
     }
+
+    /// 16.9 Handling Transport Errors: the server acts as if the request had received 503 (Service Unavailable),
+    /// so a fork tries the next server (RFC 3263 4.3) or the best response goes to the caller
+    async fn on_route_failed(&mut self, flow: Flow, mut msg: SipMessage) {
+        if !msg.is_request() {
+            return;
+        }
+        // A REGISTER the dispatcher has passed on already has the server's Via
+        if msg.method() == Some(Method::Register) {
+            error!("on_route_failed: can't reach {}", flow.addr());
+            match self.create_response_generator(&msg, 503).build() {
+                Ok(res) => self.process_response(res, false).await,
+                Err(e) => error!("on_route_failed: failed to generate response: {}", e),
+            }
+            return;
+        }
+        let via_index = msg
+            .headers()
+            .0
+            .iter()
+            .position(|h| matches!(h, Header::Via(_)))
+            .unwrap_or(0);
+        // Nobody waits for responses to CANCEL and ACK the server generates
+        let key = match msg.headers().0.get(via_index) {
+            Some(Header::Via(via)) if via.uri.host != self.domain => Self::via_branch(via),
+            _ => None,
+        };
+        let key = if let Some(key) = key {
+            key
+        } else {
+            debug!("on_route_failed: can't reach {}", flow.addr());
+            return;
+        };
+        error!("on_route_failed: can't reach {}", flow.addr());
+        // The response has to come back like one to the request sent on a branch would
+        let branch = ViaBranchGenerator::derive(&key, flow.addr());
+        msg.headers_mut().0.insert(
+            via_index,
+            Header::Via(self.via_hdr_with_branch(branch.clone())),
+        );
+        if msg.method() == Some(Method::Invite) {
+            let mut forks = self.system.forks.lock().await;
+            if forks.contains(&key) {
+                forks.add_branch(&key, branch, flow, msg.clone());
+            }
+        }
+        match self.create_response_generator(&msg, 503).build() {
+            Ok(res) => self.process_response(res, false).await,
+            Err(e) => error!("on_route_failed: failed to generate response: {}", e),
+        }
+    }
 }
 
 impl<'a> MyClient<'a> {
     pub fn new(
         address: SocketAddr,
         transport: Transport,
+        trusted: bool,
         schema: UriSchema,
         domain: Domain,
         utils: Arc<Utils>,
@@ -82,151 +199,1666 @@ impl<'a> MyClient<'a> {
         system: Arc<MySystem>,
         back_to_back: bool,
     ) -> Self {
+        let probe_call_id = system.dialog_gen.call_id();
         Self {
             address,
             transport,
+            trusted,
             schema,
             domain,
             utils,
             event_handler,
             system,
             back_to_back,
+            timers: HashMap::new(),
+            next_timer_id: 0,
+            probe_call_id,
+            probe_cseq: 0,
+            probe_pending: false,
+        }
+    }
+
+    async fn on_req(&mut self, msg: SipMessage) {
+        self.send_res(&msg, 200).await;
+    }
+
+    async fn set_timer(&mut self, after: Duration, timer: Timer) {
+        let id = self.next_timer_id;
+        self.next_timer_id += 1;
+        self.timers.insert(id, timer);
+        self.event_handler
+            .handle(ClientEvent::SetTimer { after, id })
+            .await;
+    }
+
+    async fn on_register(&mut self, msg: SipMessage) {
+        let username = if let Some(username) = msg.to_header_username() {
+            username
+        } else {
+            error!("on_register: no `username` in `To`");
+            self.send_res(&msg, 400).await;
+            return;
+        };
+        // 10.3 Processing REGISTER Requests (steps 2 and 3): only the user itself may change its bindings
+        if !self.authenticate(&msg, &username, false).await {
+            return;
+        }
+        // Subscribers learn about bindings that expired before this request changes the others
+        self.expire_bindings(&username).await;
+        let call_id = if let Some(call_id) = msg.call_id() {
+            call_id.clone()
+        } else {
+            error!("on_register: no `Call-ID`");
+            self.send_res(&msg, 400).await;
+            return;
+        };
+        let cseq = if let Some(cseq) = Self::cseq(&msg) {
+            cseq
+        } else {
+            error!("on_register: no `CSeq`");
+            self.send_res(&msg, 400).await;
+            return;
+        };
+        let contacts = Self::contact_hdrs(&msg);
+        let bindings_before: Vec<Binding> = self
+            .system
+            .registrations
+            .lock()
+            .await
+            .bindings(&username)
+            .into_iter()
+            .cloned()
+            .collect();
+        let mut instance_ids = Vec::new();
+        let result = if Self::has_wildcard_contact(&msg) {
+            // 10.3 Processing REGISTER Requests (step 6): "*" is only allowed with `Expires: 0` and no other contacts
+            if msg.expires() != Some(0) || !contacts.is_empty() {
+                error!("on_register: invalid `Contact: *`");
+                self.send_res(&msg, 400).await;
+                return;
+            }
+            self.system
+                .registrations
+                .lock()
+                .await
+                .remove_all(&username, &call_id, cseq)
+        } else {
+            let flow = Flow::new(self.transport, self.address);
+            // RFC 3327 5.3 Procedures at the Registrar: the Path vector is stored with each binding
+            let path = Self::header_values(&msg, "path");
+            let mut contact_infos: Vec<ContactInfo> = Vec::new();
+            for h in contacts {
+                // 10.2.1.1 Setting the Expiration Interval of Contact Addresses: the "expires" parameter overrides `Expires`
+                let requested = h
+                    .parameters
+                    .get("expires")
+                    .and_then(|expires| expires.as_ref())
+                    .and_then(|expires| expires.parse().ok())
+                    .or_else(|| msg.expires());
+                match self.system.expires_policy.grant(requested) {
+                    Ok(expires) => {
+                        let contact_info =
+                            ContactInfo::new(h.uri.clone(), h.parameters.clone(), flow, expires)
+                                .path(path.clone());
+                        if let Some(instance_id) = contact_info.instance_id() {
+                            instance_ids.push(instance_id.to_string());
+                        }
+                        contact_infos.push(contact_info);
+                    }
+                    Err(min_expires) => {
+                        debug!("on_register: interval is too brief: {:?}", requested);
+                        self.prepare_and_send_res(&msg, 423, |generator| {
+                            generator.header(Header::MinExpires(min_expires))
+                        })
+                        .await;
+                        return;
+                    }
+                }
+            }
+            self.system
+                .registrations
+                .lock()
+                .await
+                .update(&username, &call_id, cseq, contact_infos)
+        };
+        if let Err(e) = result {
+            error!("on_register: {}", e);
+            self.send_res(&msg, 500).await;
+            return;
+        }
+        // RFC 5627 7.1.2.1 Processing a REGISTER Request: GRUUs are issued only if the UA supports them
+        let gruu = Self::supports(&msg, "gruu");
+        let mut registrations = self.system.registrations.lock().await;
+        if gruu {
+            for instance_id in instance_ids.iter() {
+                registrations.issue_temp_gruu(&username, instance_id);
+            }
+        }
+        // 10.3 Processing REGISTER Requests (step 8): the response contains all current bindings with the granted expiration intervals
+        let bindings = registrations.bindings(&username);
+        let contacts: Vec<Header> = bindings
+            .iter()
+            .map(|b| Header::Contact(self.binding_contact_hdr(&username, b, gruu)))
+            .collect();
+        let mut expiries: Vec<u32> = bindings.iter().map(|b| b.expires()).collect();
+        drop(registrations);
+        // Subscribers are told when the bindings expire unless they are refreshed
+        expiries.sort_unstable();
+        expiries.dedup();
+        for expires in expiries {
+            let timer = Timer::BindingExpiry {
+                aor: username.clone(),
+            };
+            self.set_timer(Duration::from_secs(u64::from(expires) + 1), timer)
+                .await;
+        }
+        // RFC 3327 5.3 Procedures at the Registrar: Path is returned only if the UA supports it
+        let path = if Self::supports(&msg, "path") {
+            Self::header_values(&msg, "path")
+        } else {
+            Vec::new()
+        };
+        self.prepare_and_send_res(&msg, 200, |generator| {
+            let generator = contacts
+                .into_iter()
+                .fold(generator, |generator, h| generator.header(h));
+            if path.is_empty() {
+                generator
+            } else {
+                generator.header(Header::Other("Path".to_string(), path.join(", ")))
+            }
+        })
+        .await;
+        self.notify_reg_subscribers(&username, &bindings_before)
+            .await;
+    }
+
+    /// Authenticates `msg` as per 22 Usage of HTTP Authentication.
+    /// The registrar (`proxy` is `false`) uses `Authorization` and 401, the proxy uses `Proxy-Authorization` and 407.
+    /// Returns `true` if users aren't authenticated, the client is a trusted peer or `msg` is authenticated as `username`.
+    /// Otherwise, responds to `msg` and returns `false`
+    async fn authenticate(&mut self, msg: &SipMessage, username: &str, proxy: bool) -> bool {
+        if self.trusted {
+            return true;
+        }
+        let system = self.system.clone();
+        let mut authenticator = if let Some(authenticator) = &system.authenticator {
+            authenticator.lock().await
+        } else {
+            return true;
+        };
+        let method = if let Some(method) = msg.method() {
+            method.to_string()
+        } else {
+            error!("authenticate: no method");
+            return false;
+        };
+        let (uri, body) = match msg {
+            SipMessage::Request { uri, body, .. } => (uri.to_string(), body.as_slice()),
+            SipMessage::Response { .. } => {
+                error!("authenticate: not request");
+                return false;
+            }
+        };
+        // Credentials for other realms (proxies further down the path) are ignored
+        let authorization = Self::authorizations(msg, proxy)
+            .into_iter()
+            .find(|authorization| authenticator.is_own(authorization));
+        let challenges = match authorization {
+            None => authenticator.challenge(false),
+            Some(authorization) => {
+                match authenticator.authenticate(&method, &uri, &authorization, body) {
+                    Ok(user) if user == username => {
+                        if let Some(ban_list) = &self.system.ban_list {
+                            ban_list
+                                .lock()
+                                .unwrap()
+                                .record_success(self.address.ip(), username);
+                        }
+                        return true;
+                    }
+                    Ok(user) => {
+                        drop(authenticator);
+                        error!(
+                            "authenticate: \"{}\" can't act on behalf of \"{}\"",
+                            user, username
+                        );
+                        self.record_auth_failure(username);
+                        self.send_res(msg, 403).await;
+                        return false;
+                    }
+                    Err(AuthError::StaleNonce) => authenticator.challenge(true),
+                    Err(e @ AuthError::UnknownNonce)
+                    | Err(e @ AuthError::WrongRealm)
+                    | Err(e @ AuthError::Replay) => {
+                        error!("authenticate: {}", e);
+                        authenticator.challenge(false)
+                    }
+                    Err(e) => {
+                        drop(authenticator);
+                        error!("authenticate: {}", e);
+                        if e == AuthError::WrongResponse || e == AuthError::UnknownUser {
+                            self.record_auth_failure(username);
+                        }
+                        let code = if e == AuthError::InvalidHeader || e == AuthError::WrongUri {
+                            400
+                        } else {
+                            403
+                        };
+                        self.send_res(msg, code).await;
+                        return false;
+                    }
+                }
+            }
+        };
+        drop(authenticator);
+        let (code, name) = if proxy {
+            (407, "Proxy-Authenticate")
+        } else {
+            (401, "WWW-Authenticate")
+        };
+        // One challenge per algorithm, the most preferred first
+        self.prepare_and_send_res(msg, code, |generator| {
+            challenges
+                .into_iter()
+                .fold(generator, |generator, challenge| {
+                    generator.header(Header::Other(name.to_string(), challenge))
+                })
+        })
+        .await;
+        false
+    }
+
+    /// Counts a failed attempt to authenticate as `username`, so that password guessing gets the client's IP banned
+    fn record_auth_failure(&self, username: &str) {
+        if let Some(ban_list) = &self.system.ban_list {
+            let ip = self.address.ip();
+            if ban_list.lock().unwrap().record_failure(ip, username) {
+                error!("record_auth_failure: banned {}", ip);
+            }
+        }
+    }
+
+    /// Authenticates a dialog-creating request as its `From` user, so that a caller can't pretend to be another user.
+    /// Returns `true` if the request may proceed. Otherwise, the request has already been responded
+    async fn authenticate_caller(&mut self, msg: &SipMessage) -> bool {
+        if !Self::needs_proxy_authentication(msg) {
+            return true;
+        }
+        if let Some(caller) = msg.from_header_username() {
+            self.authenticate(msg, &caller, true).await
+        } else {
+            error!("authenticate_caller: no `username` in `From`");
+            self.send_res(msg, 400).await;
+            false
+        }
+    }
+
+    /// Returns `true` if `msg` must be authenticated by the proxy: the requests that create dialogs.
+    /// 22.1 Framework: ACK and CANCEL can't be challenged
+    fn needs_proxy_authentication(msg: &SipMessage) -> bool {
+        Self::creates_dialog(msg)
+    }
+
+    /// Returns `true` if `msg` is a request that creates a dialog (or a subscription): INVITE, SUBSCRIBE or REFER outside of a dialog
+    fn creates_dialog(msg: &SipMessage) -> bool {
+        match msg.method() {
+            Some(Method::Invite) | Some(Method::Subscribe) | Some(Method::Refer) => {
+                msg.to_header_tag().is_none()
+            }
+            _ => false,
+        }
+    }
+
+    async fn route_request(&mut self, mut msg: SipMessage) {
+        let key = if let Some(branch) = msg.via_header_branch() {
+            branch.clone()
+        } else {
+            error!("route_request: no `branch` in `Via`");
+            self.send_res(&msg, 400).await;
+            return;
+        };
+        if self.system.forks.lock().await.contains(&key) {
+            match msg.method() {
+                // 16.7 Response Processing (step 6): the ACK for a non-2xx final response completes the server's own transaction
+                Some(Method::Ack) => {
+                    debug!("route_request: ACK for a forked INVITE");
+                    return;
+                }
+                // 17.2.1 INVITE Server Transaction: a retransmission gets a provisional response again instead of forking the request anew
+                Some(Method::Invite) => {
+                    debug!("route_request: INVITE retransmission");
+                    self.send_res(&msg, 100).await;
+                    return;
+                }
+                // 16.10 CANCEL Processing
+                Some(Method::Cancel) => {
+                    self.send_res(&msg, 200).await;
+                    self.system.forks.lock().await.stop(&key);
+                    self.cancel_branches(&key).await;
+                    return;
+                }
+                _ => {}
+            }
+        }
+        // 16.3 Request Validation (step 3): the request has run out of hops, probably in a loop
+        if Self::max_forwards(&msg) == Some(0) {
+            debug!("route_request: too many hops");
+            self.send_res(&msg, 483).await;
+            return;
+        }
+        if !self.authenticate_caller(&msg).await {
+            return;
+        }
+        // 16.2 Stateful Proxy: the caller stops retransmitting the INVITE before the targets respond
+        if msg.method() == Some(Method::Invite) {
+            self.send_res(&msg, 100).await;
+        }
+        self.stamp_via(&mut msg);
+        Self::decrement_max_forwards(&mut msg);
+        // 22.3 Proxy-to-User Authentication: the proxy consumes the credentials meant for its realm only
+        if let Some(authenticator) = &self.system.authenticator {
+            let authenticator = authenticator.lock().await;
+            msg.headers_mut().0.retain(|h| match h {
+                Header::ProxyAuthorization(authorization) => {
+                    !authenticator.is_own(&authorization.to_string())
+                }
+                Header::Other(name, value) if name.eq_ignore_ascii_case("proxy-authorization") => {
+                    !authenticator.is_own(value)
+                }
+                _ => true,
+            });
+        }
+        // 16.4 Route Information Preprocessing
+        let mut routes = self.preprocess_routes(&mut msg);
+        // 16.6 Request Forwarding (step 4): the server stays in the path of the dialog
+        if !self.back_to_back && Self::creates_dialog(&msg) {
+            let record_route = Route::new(
+                Uri::new(self.schema, self.domain.clone())
+                    .parameter(UriParam::Other("lr".to_string(), None)),
+            );
+            let index = msg
+                .headers()
+                .0
+                .iter()
+                .position(|h| match h {
+                    Header::Other(name, _) => name.eq_ignore_ascii_case("record-route"),
+                    _ => false,
+                })
+                .unwrap_or(0);
+            msg.headers_mut().0.insert(
+                index,
+                Header::Other("Record-Route".to_string(), record_route.to_string()),
+            );
+        }
+        // Targets are the Request-URIs with the flows they are sent over and the Route sets (Path vectors) preloaded to them.
+        // A target located with DNS has a flow per server, and the servers are tried one after another (failover)
+        let mut failover = false;
+        // The trunk the request goes out through if the callee is a number outside the server
+        let mut trunk_name = None;
+        let targets: Vec<(Uri, Flow, Vec<String>)> = if !routes.is_empty() {
+            // 16.6 Request Forwarding (steps 6 and 7): the request goes to the next hop of the Route set
+            let next_hop = routes[0].clone();
+            let flows = self.system.locator.locate(next_hop.uri()).await;
+            if flows.is_empty() {
+                error!("route_request: can't resolve `Route` {}", next_hop);
+                self.send_res(&msg, 502).await;
+                return;
+            }
+            let mut uri = if let SipMessage::Request { uri, .. } = &msg {
+                uri.clone()
+            } else {
+                return;
+            };
+            if !next_hop.is_loose() {
+                // The next hop is a strict router: it expects to find itself in the Request-URI
+                routes.remove(0);
+                routes.push(Route::new(uri));
+                uri = next_hop.uri().clone();
+            }
+            failover = true;
+            flows
+                .into_iter()
+                .map(|flow| (uri.clone(), flow, Vec::new()))
+                .collect()
+        } else if let Some(targets) = self.dispatch_target(&msg).await {
+            if targets.is_empty() {
+                return;
+            }
+            failover = true;
+            targets
+        } else if let Some(targets) = self.remote_target(&msg).await {
+            failover = true;
+            targets
+        } else if let Some(gruu_contact) = self.gruu_contact(&msg).await {
+            if let Some(target) = gruu_contact {
+                vec![target]
+            } else {
+                // RFC 5627 7.3.2 Request Targeting: the GRUU's binding doesn't exist
+                debug!("route_request: GRUU doesn't refer to any binding");
+                self.send_res(&msg, 480).await;
+                return;
+            }
+        } else if let Some(callee) = self.local_callee(&msg) {
+            let callee_contacts: Vec<(Uri, Flow, Vec<String>)> = self
+                .system
+                .registrations
+                .lock()
+                .await
+                .bindings(&callee)
+                .into_iter()
+                .map(|b| (b.uri().clone(), b.flow(), b.path().clone()))
+                .collect();
+            let system = self.system.clone();
+            let trunk = if Trunks::is_number(&callee) {
+                system.trunks.find(&callee)
+            } else {
+                None
+            };
+            if !callee_contacts.is_empty() {
+                debug!("route_request: callee \"{}\" is registered", callee);
+                callee_contacts
+            } else if let Some(trunk) = trunk {
+                debug!(
+                    "route_request: {} goes out through trunk {}",
+                    callee,
+                    trunk.name()
+                );
+                let uri = trunk.request_uri(&callee);
+                let flows = system.locator.locate(&uri).await;
+                if flows.is_empty() {
+                    error!("route_request: can't resolve trunk {}", trunk.name());
+                    self.send_res(&msg, 503).await;
+                    return;
+                }
+                Self::rewrite_from(&mut msg, trunk);
+                trunk_name = Some(trunk.name().clone());
+                failover = true;
+                flows
+                    .into_iter()
+                    .map(|flow| (uri.clone(), flow, Vec::new()))
+                    .collect()
+            } else {
+                debug!("route_request: callee \"{}\" isn't registered", callee);
+                self.send_res(&msg, 404).await;
+                return;
+            }
+        } else if let Some(targets) = self.foreign_target(&msg).await {
+            failover = true;
+            targets
+        } else {
+            return;
+        };
+        Self::set_routes(&mut msg, &routes);
+        // The server should have different dialogs with clients if the server operates in Back-to-Back User Agent mode
+        if self.back_to_back && !self.convert_request_dialog(&mut msg).await {
+            self.send_res(&msg, 400).await;
+            return;
+        }
+        self.track_trunk_call(&mut msg, trunk_name).await;
+        // Every device of the callee receives the request.
+        // The request is sent over the connection the device registered over, but it targets the contact URI.
+        // RFC 3327 5.4 Procedures at Intermediate Proxies: the Path vector is the preloaded Route set
+        let requests: Vec<(Flow, SipMessage)> = targets
+            .into_iter()
+            .map(|(target_uri, flow, path)| {
+                let mut msg = msg.clone();
+                if let SipMessage::Request { uri, .. } = &mut msg {
+                    *uri = target_uri;
+                }
+                for (i, route) in path.into_iter().enumerate() {
+                    msg.headers_mut()
+                        .0
+                        .insert(i, Header::Other("Route".to_string(), route));
+                }
+                (flow, msg)
+            })
+            .collect();
+        // 16.6 Request Forwarding: an INVITE rings all the targets at once (parallel forking)
+        // or one after another in the order of q-values (sequential forking)
+        let requests = if msg.method() == Some(Method::Invite) && msg.to_header_tag().is_none() {
+            let forking = if failover {
+                Forking::Failover
+            } else if self.system.sequential_forking {
+                Forking::Sequential
+            } else {
+                Forking::Parallel
+            };
+            let requests = self
+                .system
+                .forks
+                .lock()
+                .await
+                .start(key.clone(), requests, forking);
+            // 16.6 Request Forwarding (step 11): Timer C
+            if let Some(time_left) = self.system.forks.lock().await.time_left(&key) {
+                self.set_timer(time_left, Timer::Fork { key }).await;
+            }
+            requests
+        } else if failover {
+            // Other requests go to the most preferred server only
+            requests.into_iter().take(1).collect()
+        } else {
+            requests
+        };
+        // The server connects to the targets it doesn't have connections with
+        for (flow, msg) in requests {
+            self.event_handler
+                .handle(ClientEvent::Connect { flow, msg })
+                .await;
+        }
+    }
+
+    /// Returns the value of Max-Forwards or `None` if there is no such header
+    fn max_forwards(msg: &SipMessage) -> Option<u32> {
+        msg.headers().0.iter().find_map(|h| match h {
+            Header::MaxForwards(max_forwards) => Some(*max_forwards),
+            _ => None,
+        })
+    }
+
+    /// Decrements Max-Forwards of a request the server forwards or adds it with 70 as per 16.6 Request Forwarding (step 3)
+    fn decrement_max_forwards(msg: &mut SipMessage) {
+        for h in msg.headers_mut().0.iter_mut() {
+            if let Header::MaxForwards(max_forwards) = h {
+                *max_forwards = max_forwards.saturating_sub(1);
+                return;
+            }
+        }
+        msg.headers_mut().0.push(Header::MaxForwards(70));
+    }
+
+    /// Returns the user the request targets if the Request-URI is in one of the server's domains as per 16.5 Determining Request Targets.
+    /// In B2BUA mode a mid-dialog request targets the server itself, so the user of the other leg is taken from `To`
+    fn local_callee(&self, msg: &SipMessage) -> Option<String> {
+        if self.back_to_back && msg.to_header_tag().is_some() {
+            return msg.to_header_username();
+        }
+        let uri = if let SipMessage::Request { uri, .. } = msg {
+            uri
+        } else {
+            return None;
+        };
+        if self.is_local_domain(&uri.host) {
+            uri.auth.as_ref().map(|auth| auth.username.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the target of a request whose Request-URI isn't in the server's domains (or lacks a user) if the policy forwards such requests.
+    /// The target is located as per RFC 3263, so there is a flow per server of the domain.
+    /// Otherwise, responds to the request and returns `None`
+    async fn foreign_target(&mut self, msg: &SipMessage) -> Option<Vec<(Uri, Flow, Vec<String>)>> {
+        let uri = if let SipMessage::Request { uri, .. } = msg {
+            uri.clone()
+        } else {
+            return None;
+        };
+        if self.is_local_domain(&uri.host) {
+            debug!("route_request: no user in the Request-URI");
+            self.send_res(msg, 404).await;
+            return None;
+        }
+        match self.system.foreign_domain_policy {
+            ForeignDomainPolicy::Forward => {
+                // The outbound proxy routes the request by the Request-URI, so only the flows differ
+                let next_hop = self.system.outbound_proxy.as_ref().unwrap_or(&uri);
+                let flows = self.system.locator.locate(next_hop).await;
+                if flows.is_empty() {
+                    debug!("route_request: can't resolve {}", uri);
+                    self.send_res(msg, 404).await;
+                    return None;
+                }
+                debug!("route_request: forwarded to {:?}", flows);
+                Some(
+                    flows
+                        .into_iter()
+                        .map(|flow| (uri.clone(), flow, Vec::new()))
+                        .collect(),
+                )
+            }
+            ForeignDomainPolicy::Reject(code) => {
+                debug!("route_request: {} isn't the server's domain", uri);
+                self.send_res(msg, code).await;
+                None
+            }
+        }
+    }
+
+    /// Passes a REGISTER of a client on to a back-end (the one its registration's Call-ID is bound to) statelessly,
+    /// so that the back-end binds the client's own contact. The response comes back through the server's Via
+    async fn dispatch_register(&mut self, mut msg: SipMessage) {
+        let system = self.system.clone();
+        let dispatcher = if let Some(dispatcher) = &system.dispatcher {
+            dispatcher
+        } else {
+            return;
+        };
+        let key = if let Some(branch) = msg.via_header_branch() {
+            branch.clone()
+        } else {
+            error!("dispatch_register: no `branch` in `Via`");
+            self.send_res(&msg, 400).await;
+            return;
+        };
+        if Self::max_forwards(&msg) == Some(0) {
+            debug!("dispatch_register: too many hops");
+            self.send_res(&msg, 483).await;
+            return;
+        }
+        let call_id = if let Some(call_id) = msg.call_id() {
+            call_id.clone()
+        } else {
+            error!("dispatch_register: no `Call-ID`");
+            self.send_res(&msg, 400).await;
+            return;
+        };
+        let flow = dispatcher.lock().unwrap().dispatch(&call_id);
+        let flow = if let Some(flow) = flow {
+            flow
+        } else {
+            error!("dispatch_register: every back-end is down");
+            self.send_res(&msg, 503).await;
+            return;
+        };
+        self.stamp_via(&mut msg);
+        Self::decrement_max_forwards(&mut msg);
+        let via = self.via_hdr_with_branch(ViaBranchGenerator::derive(&key, self.address));
+        let path = Route::new(
+            Uri::new(self.schema, self.domain.clone())
+                .parameter(UriParam::Other("lr".to_string(), None)),
+        );
+        Dispatcher::forward_register(&mut msg, via, &path);
+        debug!("dispatch_register: dispatched to {}", flow.addr());
+        self.event_handler
+            .handle(ClientEvent::Connect { flow, msg })
+            .await;
+    }
+
+    /// Returns the targets of a request if the server is a dispatcher: a request from a client goes to a back-end
+    /// (the one its call is bound to), a request from a back-end goes to its Request-URI (the contact of a client registered through the server).
+    /// Returns `None` if the server isn't a dispatcher. If the request can't be dispatched, responds to it and returns no targets
+    async fn dispatch_target(&mut self, msg: &SipMessage) -> Option<Vec<(Uri, Flow, Vec<String>)>> {
+        let system = self.system.clone();
+        let dispatcher = system.dispatcher.as_ref()?;
+        let uri = if let SipMessage::Request { uri, .. } = msg {
+            uri.clone()
+        } else {
+            return None;
+        };
+        if self.is_backend() {
+            let flows = self.system.locator.locate(&uri).await;
+            if flows.is_empty() {
+                debug!("dispatch_target: can't resolve {}", uri);
+                self.send_res(msg, 404).await;
+            }
+            return Some(
+                flows
+                    .into_iter()
+                    .map(|flow| (uri.clone(), flow, Vec::new()))
+                    .collect(),
+            );
+        }
+        let call_id = if let Some(call_id) = msg.call_id() {
+            call_id.clone()
+        } else {
+            error!("dispatch_target: no `Call-ID`");
+            self.send_res(msg, 400).await;
+            return Some(Vec::new());
+        };
+        let flow = dispatcher.lock().unwrap().dispatch(&call_id);
+        let flow = if let Some(flow) = flow {
+            flow
+        } else {
+            error!("dispatch_target: every back-end is down");
+            self.send_res(msg, 503).await;
+            return Some(Vec::new());
+        };
+        debug!("dispatch_target: dispatched to {}", flow.addr());
+        Some(vec![(uri, flow, Vec::new())])
+    }
+
+    fn is_backend(&self) -> bool {
+        match &self.system.dispatcher {
+            Some(dispatcher) => dispatcher.lock().unwrap().is_backend(self.address),
+            None => false,
+        }
+    }
+
+    /// Probes the back-end with OPTIONS and schedules the next probe. A probe that hasn't been answered by the next one has timed out
+    async fn probe(&mut self) {
+        if self.probe_pending {
+            self.on_probe_result(false);
+        }
+        let ip = match self.address.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => return,
+        };
+        self.probe_cseq += 1;
+        let uri = Uri::new(UriSchema::Sip, Domain::Ipv4(ip, Some(self.address.port())));
+        let tag = self.system.dialog_gen.tag();
+        let from = NamedHeader::new(Uri::new(self.schema, self.domain.clone()))
+            .param("tag", Some(tag.as_str()));
+        match RequestGenerator::new()
+            .method(Method::Options)
+            .uri(uri.clone())
+            .header(Header::Via(self.via_hdr().await))
+            .header(Header::From(from))
+            .header(Header::To(NamedHeader::new(uri)))
+            .header(Header::MaxForwards(70))
+            .header(Header::CallId(self.probe_call_id.clone()))
+            .header(Header::CSeq(self.probe_cseq, Method::Options))
+            .header(Header::ContentLength(0))
+            .build()
+        {
+            Ok(request) => {
+                self.probe_pending = true;
+                self.event_handler.handle(ClientEvent::Send(request)).await;
+            }
+            Err(e) => error!("probe: failed to generate options: {}", e),
+        }
+        self.set_timer(self.system.probe_interval, Timer::Probe)
+            .await;
+    }
+
+    /// A probe fails if the back-end is unavailable (5xx). Any other response means that it's alive
+    fn on_probe_response(&mut self, msg: &SipMessage) {
+        if !self.probe_pending
+            || msg.call_id() != Some(&self.probe_call_id)
+            || Self::cseq(msg) != Some(self.probe_cseq)
+        {
+            return;
+        }
+        self.probe_pending = false;
+        let succeeded = !matches!(msg.status_code(), Some(code) if code >= 500);
+        self.on_probe_result(succeeded);
+    }
+
+    fn on_probe_result(&self, succeeded: bool) {
+        let dispatcher = if let Some(dispatcher) = &self.system.dispatcher {
+            dispatcher
+        } else {
+            return;
+        };
+        let mut dispatcher = dispatcher.lock().unwrap();
+        if succeeded {
+            if dispatcher.probe_succeeded(self.address) {
+                info!("back-end {} is up", self.address);
+            }
+        } else if dispatcher.probe_failed(self.address) {
+            warn!("back-end {} is down", self.address);
+        }
+    }
+
+    /// Returns `true` if `host` is the server's address (the default port is 5060) or one of the configured domains
+    fn is_local_domain(&self, host: &Domain) -> bool {
+        Route::is_local_host(host, &self.domain, &self.system.domains)
+    }
+
+    /// Processes the Route set of a received request as per 16.4 Route Information Preprocessing
+    /// and returns the Route set that is left
+    fn preprocess_routes(&self, msg: &mut SipMessage) -> Vec<Route> {
+        let routes: Vec<Route> = Self::header_values(msg, "route")
+            .into_iter()
+            .filter_map(|value| Route::parse(&value))
+            .collect();
+        match msg {
+            SipMessage::Request { uri, .. } => {
+                Route::preprocess(uri, routes, &self.domain, &self.system.domains)
+            }
+            _ => routes,
+        }
+    }
+
+    /// Replaces `Route` of `msg` with `routes`
+    fn set_routes(msg: &mut SipMessage, routes: &[Route]) {
+        msg.headers_mut().0.retain(|h| match h {
+            Header::Other(name, _) => !name.eq_ignore_ascii_case("route"),
+            _ => true,
+        });
+        for (i, route) in routes.iter().enumerate() {
+            msg.headers_mut()
+                .0
+                .insert(i, Header::Other("Route".to_string(), route.to_string()));
+        }
+    }
+
+    /// Returns the target of a mid-dialog request in proxy mode: the Request-URI is the remote target (the Contact of the other side).
+    /// The request goes over the connection the contact is registered over or to the servers located by the Request-URI
+    async fn remote_target(&self, msg: &SipMessage) -> Option<Vec<(Uri, Flow, Vec<String>)>> {
+        if self.back_to_back || msg.to_header_tag().is_none() {
+            return None;
+        }
+        let uri = if let SipMessage::Request { uri, .. } = msg {
+            uri
+        } else {
+            return None;
+        };
+        if let Some(username) = msg.to_header_username() {
+            let registrations = self.system.registrations.lock().await;
+            if let Some(binding) = registrations
+                .bindings(&username)
+                .into_iter()
+                .find(|b| b.uri() == uri)
+            {
+                return Some(vec![(uri.clone(), binding.flow(), Vec::new())]);
+            }
+        }
+        let flows = self.system.locator.locate(uri).await;
+        if flows.is_empty() {
+            return None;
+        }
+        Some(
+            flows
+                .into_iter()
+                .map(|flow| (uri.clone(), flow, Vec::new()))
+                .collect(),
+        )
+    }
+
+    async fn on_routed_request(&mut self, mut msg: SipMessage) {
+        let via_index = msg
+            .headers()
+            .0
+            .iter()
+            .position(|h| matches!(h, Header::Via(_)))
+            .unwrap_or(0);
+        // CANCEL and ACK the server generates for a branch and REGISTER the dispatcher passes on already have the server's Via
+        if let Some(Header::Via(via)) = msg.headers().0.get(via_index) {
+            if via.uri.host == self.domain {
+                self.event_handler.handle(ClientEvent::Send(msg)).await;
+                return;
+            }
+        }
+        // 16.6 Request Forwarding (step 8): the server pushes its Via, so that responses come back through it
+        let key = if let Some(branch) = msg.via_header_branch() {
+            branch.clone()
+        } else {
+            error!("on_routed_request: no `branch` in `Via`");
+            return;
+        };
+        let via_branch = ViaBranchGenerator::derive(&key, self.address);
+        // The B2BUA's Via takes the place of the Via headers of the other leg, the proxy's one is pushed on top of them
+        let via_index = if self.back_to_back {
+            self.system
+                .hidden_vias
+                .lock()
+                .await
+                .hide(&mut msg, &via_branch);
+            if let Some(hiding) = &self.system.topology_hiding {
+                hiding.hide(&mut msg, &self.domain);
+            }
+            0
+        } else {
+            via_index
+        };
+        msg.headers_mut().0.insert(
+            via_index,
+            Header::Via(self.via_hdr_with_branch(via_branch.clone())),
+        );
+        let mut relayed = true;
+        if self.back_to_back {
+            // TODO: This should be checked in route_request
+            if let Some(h) = msg.contact_header_mut() {
+                *h = self.contact_hdr();
+            } else {
+                // method() is checked to exist in on_message (on_routed_message is called only if some function called from on_message asked the msg to be routed)
+                if msg.method().unwrap() == Method::Invite {
+                    error!("on_routed_request: no `Contact` in invite");
+                    return;
+                }
+            }
+            relayed = self.rewrite_sdp(&mut msg).await;
+        }
+        // The request sent on the branch is kept, so that the branch can be CANCELled and ACKed
+        if msg.method() == Some(Method::Invite) {
+            let mut forks = self.system.forks.lock().await;
+            if forks.contains(&key) {
+                let flow = Flow::new(self.transport, self.address);
+                forks.add_branch(&key, via_branch.clone(), flow, msg.clone());
+                drop(forks);
+                // The branch stops ringing if it isn't answered in time
+                if let Some(ring_timeout) = self.system.ring_timeout {
+                    let timer = Timer::Ring {
+                        key,
+                        branch: via_branch,
+                    };
+                    self.set_timer(ring_timeout, timer).await;
+                }
+            }
+        }
+        // The call can't go on without media, so the server answers the request for the target (ACK can't be answered)
+        if !relayed && msg.method() != Some(Method::Ack) {
+            match self.create_response_generator(&msg, 500).build() {
+                Ok(res) => self.process_response(res, false).await,
+                Err(e) => error!("on_routed_request: failed to generate response: {}", e),
+            }
+            return;
+        }
+        self.event_handler.handle(ClientEvent::Send(msg)).await;
+    }
+
+    async fn on_routed_response(&mut self, mut msg: SipMessage) {
+        // Responses to REGISTER the dispatcher has passed on go back as they are
+        if self.back_to_back && msg.method() != Some(Method::Register) {
+            if self.convert_response_dialog(&mut msg).await {
+                if let Some(h) = msg.contact_header_mut() {
+                    *h = self.contact_hdr();
+                } else {
+                    // method() is checked to exist in on_message (on_routed_response is called only if some function called from on_message asked the msg to be routed)
+                    if msg.method().unwrap() == Method::Invite {
+                        if let Some(status_code) = msg.status_code() {
+                            if status_code >= 200 && status_code <= 299 {
+                                error!("on_routed_response: no `Contact` in invite 2xx response");
+                                return;
+                            }
+                        } else {
+                            error!("on_routed_response: no status code in response");
+                            return;
+                        }
+                    }
+                }
+            } else {
+                error!("on_routed_response: convert_response_dialog failed");
+                return;
+            }
+            if let Some(hiding) = &self.system.topology_hiding {
+                hiding.hide(&mut msg, &self.domain);
+            }
+            // The call can't go on without media: the caller gets 500 instead of a final response, and the callee's leg ends
+            // since its 2xx isn't acknowledged (13.3.1.4 The INVITE is Accepted). A provisional response is dropped
+            if !self.rewrite_sdp(&mut msg).await {
+                match &mut msg {
+                    SipMessage::Response { code, .. } if *code >= 200 => *code = 500,
+                    _ => return,
+                }
+                msg.headers_mut().0.retain(|h| !Self::is_content_type(h));
+                Self::set_body(&mut msg, Vec::new());
+            }
+        }
+        self.event_handler.handle(ClientEvent::Send(msg)).await;
+    }
+
+    /// Rewrites the SDP of a message crossing the legs of the B2BUA (the body or its part, as Content-Type says).
+    /// With topology hiding, the session's origin is the server. A body without SDP is left as it is.
+    /// Returns `false` if the media of the SDP can't be relayed
+    async fn rewrite_sdp(&mut self, msg: &mut SipMessage) -> bool {
+        let body = match &*msg {
+            SipMessage::Request { body, .. } | SipMessage::Response { body, .. } => body,
+        };
+        let range = match Self::content_type(msg)
+            .and_then(|content_type| SessionDescription::locate(&content_type, body))
+        {
+            Some(range) => range,
+            None => return true,
+        };
+        let mut sdp: SessionDescription = match std::str::from_utf8(&body[range.clone()])
+            .map_err(|e| e.to_string())
+            .and_then(|body| body.parse())
+        {
+            Ok(sdp) => sdp,
+            Err(e) => {
+                error!("rewrite_sdp: invalid SDP: {}", e);
+                return true;
+            }
+        };
+        if self.system.topology_hiding.is_some() {
+            sdp.origin.username = "-".to_string();
+            match &self.domain {
+                Domain::Ipv4(ip, _) => {
+                    sdp.origin.addr_type = "IP4".to_string();
+                    sdp.origin.address = ip.to_string();
+                }
+                Domain::Domain(name, _) => sdp.origin.address = name.clone(),
+            }
+        }
+        let system = self.system.clone();
+        if let (Some(relay), Some(call_id)) = (&system.media_relay, msg.call_id()) {
+            // The SDP comes from the leg other than the one the message goes to
+            let pair = system.dialogs.lock().await.pair(call_id);
+            if let Some((call, leg)) = pair {
+                if !Self::relay_media(relay, &mut sdp, call, 1 - leg).await {
+                    return false;
+                }
+            }
+        }
+        let mut body = body.clone();
+        body.splice(range, sdp.to_string().into_bytes());
+        Self::set_body(msg, body);
+        true
+    }
+
+    /// Returns the value of Content-Type
+    fn content_type(msg: &SipMessage) -> Option<String> {
+        let h = msg.headers().0.iter().find(|h| Self::is_content_type(h))?;
+        let h = h.to_string();
+        h.splitn(2, ':')
+            .nth(1)
+            .map(|value| value.trim().to_string())
+    }
+
+    /// Returns `true` if `h` is Content-Type (or its compact form `c`)
+    fn is_content_type(h: &Header) -> bool {
+        // A header is written as `Name: value`
+        let h = h.to_string();
+        let name = h.split(':').next().unwrap_or_default().trim();
+        name.eq_ignore_ascii_case("content-type") || name.eq_ignore_ascii_case("c")
+    }
+
+    /// Makes the endpoint of the other leg send the media of `sdp` to `relay` instead of the endpoint of `leg` the SDP comes from.
+    /// Returns `false` if ports can't be allocated for the streams. The relay releases the ones it has allocated for `sdp` then
+    async fn relay_media(
+        relay: &MediaRelay,
+        sdp: &mut SessionDescription,
+        call: u32,
+        leg: usize,
+    ) -> bool {
+        // A disabled stream has port 0
+        let endpoints: Vec<Option<SocketAddr>> = sdp
+            .media
+            .iter()
+            .map(|media| {
+                let ip = sdp
+                    .connection(media)
+                    .and_then(|c| c.address.split('/').next()?.parse::<IpAddr>().ok())?;
+                Some(SocketAddr::new(ip, media.port)).filter(|_| media.port != 0)
+            })
+            .collect();
+        let ports = match relay.update_all(call, leg, &endpoints).await {
+            Ok(ports) => ports,
+            Err(e) => {
+                error!(
+                    "relay_media: can't relay the streams of call {}: {}",
+                    call, e
+                );
+                return false;
+            }
+        };
+        let addr_type = match relay.ip() {
+            IpAddr::V4(_) => "IP4",
+            IpAddr::V6(_) => "IP6",
+        };
+        let relay_connection = Connection {
+            net_type: "IN".to_string(),
+            addr_type: addr_type.to_string(),
+            address: relay.ip().to_string(),
+        };
+        for (media, port) in sdp.media.iter_mut().zip(ports) {
+            if let Some(port) = port {
+                media.port = port;
+                if media.connection.is_some() {
+                    media.connection = Some(relay_connection.clone());
+                }
+                // RTCP goes to the port next to the relay's RTP port
+                media.attributes.retain(|a| a.name != "rtcp");
+            }
+        }
+        if sdp.connection.is_some() {
+            sdp.connection = Some(relay_connection);
+        }
+        true
+    }
+
+    /// Stops relaying the media of the call `msg` belongs to
+    async fn release_media(&self, msg: &SipMessage) {
+        if let (Some(relay), Some(call_id)) = (&self.system.media_relay, msg.call_id()) {
+            let pair = self.system.dialogs.lock().await.pair(call_id);
+            if let Some((call, _)) = pair {
+                relay.release(call).await;
+            }
+        }
+    }
+
+    /// Replaces the body of `msg` and recomputes `Content-Length`
+    fn set_body(msg: &mut SipMessage, new_body: Vec<u8>) {
+        let len = new_body.len() as u32;
+        match msg {
+            SipMessage::Request { body, .. } | SipMessage::Response { body, .. } => {
+                *body = new_body
+            }
+        }
+        let headers = &mut msg.headers_mut().0;
+        headers.retain(|h| !matches!(h, Header::ContentLength(_)));
+        headers.push(Header::ContentLength(len));
+    }
+
+    /// Routes a response as per 16.7 Response Processing: the server removes its Via
+    /// and the response goes to the address of the next Via
+    async fn route_response(&mut self, msg: SipMessage) {
+        self.process_response(msg, true).await;
+    }
+
+    /// Processes a response of one of the server's client transactions. `received` is `false` if the server acts as if it had received
+    /// the response (16.8 Processing Timer C), so it isn't acknowledged
+    async fn process_response(&mut self, mut msg: SipMessage, received: bool) {
+        let via_index = if let Some(via_index) = msg
+            .headers()
+            .0
+            .iter()
+            .position(|h| matches!(h, Header::Via(_)))
+        {
+            via_index
+        } else {
+            error!("route_response: no `Via`");
+            return;
+        };
+        // 18.1.2 Receiving Responses: a response whose top Via isn't the server's is discarded
+        match &msg.headers().0[via_index] {
+            Header::Via(via) if via.uri.host == self.domain => {}
+            _ => {
+                error!("route_response: top `Via` isn't the server's");
+                return;
+            }
+        }
+        let branch = if let Header::Via(via) = msg.headers_mut().0.remove(via_index) {
+            Self::via_branch(&via)
+        } else {
+            None
+        };
+        // The Via headers of the other leg of the B2BUA are restored
+        if let Some(branch) = branch.as_ref().filter(|_| self.back_to_back) {
+            if let Some(vias) = self.system.hidden_vias.lock().await.restore(branch) {
+                for (i, via) in vias.into_iter().enumerate() {
+                    msg.headers_mut().0.insert(via_index + i, via);
+                }
+            }
+        }
+        let (addr, key) = match msg.headers().0.iter().find_map(|h| match h {
+            Header::Via(via) => Some((Self::via_addr(via), Self::via_branch(via))),
+            _ => None,
+        }) {
+            Some((Some(addr), key)) => (addr, key),
+            Some((None, _)) => {
+                error!("route_response: no address in `Via`");
+                return;
+            }
+            None => {
+                // The response is for CANCEL or ACK the server generated
+                debug!("route_response: no `Via` after the server's");
+                return;
+            }
+        };
+        if let (Some(Method::Invite), Some(code), Some(branch), Some(key)) =
+            (msg.method(), msg.status_code(), &branch, &key)
+        {
+            if received
+                && (code == 401 || code == 407)
+                && self.answer_trunk_challenge(key, branch, code, &msg).await
+            {
+                return;
+            }
+        }
+        // 16.7 Response Processing (step 5): 100 (Trying) is hop-by-hop, the server has sent its own
+        if msg.status_code() == Some(100) {
+            return;
+        }
+        self.restore_trunk_cseq(&mut msg).await;
+        let msg = if let (Some(Method::Invite), Some(code), Some(branch), Some(key)) =
+            (msg.method(), msg.status_code(), branch, key)
+        {
+            match self
+                .on_fork_response(&key, &branch, code, &msg, received)
+                .await
+            {
+                ResponseAction::Forward => msg,
+                ResponseAction::Absorb => return,
+                ResponseAction::ForwardBest(best) => {
+                    self.forget_trunk_call(&best).await;
+                    self.release_media(&best).await;
+                    best
+                }
+                ResponseAction::TryNext(flow, msg) => {
+                    debug!("route_response: trying the next target {}", flow.addr());
+                    self.event_handler
+                        .handle(ClientEvent::Connect { flow, msg })
+                        .await;
+                    return;
+                }
+            }
+        } else {
+            if msg.method() == Some(Method::Bye)
+                && matches!(msg.status_code(), Some(code) if code >= 200)
+            {
+                self.forget_trunk_call(&msg).await;
+                self.release_media(&msg).await;
+                if let (Some(dispatcher), Some(call_id)) = (&self.system.dispatcher, msg.call_id())
+                {
+                    dispatcher.lock().unwrap().unbind(call_id);
+                }
+            }
+            msg
+        };
+        debug!("route_response: routed to {}", addr);
+        self.event_handler
+            .handle(ClientEvent::Route { addr, msg })
+            .await;
+    }
+
+    /// Handles a response to an INVITE received on `branch` of the fork `key` as per 16.7 Response Processing.
+    /// `received` is `false` if the response hasn't actually been received, so it isn't acknowledged
+    async fn on_fork_response(
+        &mut self,
+        key: &str,
+        branch: &str,
+        code: u32,
+        msg: &SipMessage,
+        received: bool,
+    ) -> ResponseAction {
+        let (action, ack) = {
+            let mut forks = self.system.forks.lock().await;
+            let action = forks.on_response(key, branch, code, msg.clone());
+            // 17.1.1.3 Construction of the ACK Request: the server's client transaction acknowledges a non-2xx final response itself
+            let ack = if received && code >= 300 {
+                forks
+                    .request(key, branch)
+                    .and_then(|req| Self::hop_request(req, Method::Ack, msg.headers().to()))
+            } else {
+                None
+            };
+            (action, ack)
+        };
+        if let Some(ack) = ack {
+            self.event_handler.handle(ClientEvent::Send(ack)).await;
+        }
+        // Step 10: once the request is answered or declined everywhere, the other branches stop ringing
+        if (200..300).contains(&code) || code >= 600 {
+            self.cancel_branches(key).await;
+        }
+        match action {
+            // Step 6: 503 (Service Unavailable) of a target isn't meant for the caller
+            ResponseAction::ForwardBest(mut best) => {
+                if let SipMessage::Response { code, .. } = &mut best {
+                    if *code == 503 {
+                        *code = 500;
+                    }
+                }
+                ResponseAction::ForwardBest(best)
+            }
+            action => action,
+        }
+    }
+
+    /// Gives up on `branch` of the fork `key` if it's still ringing: the branch is CANCELled and fails with 408 (Request Timeout) right away,
+    /// so the next target rings without waiting for the 487 (Request Terminated) of the CANCEL
+    async fn on_ring_timeout(&mut self, key: &str, branch: &str) {
+        let pending = self
+            .system
+            .forks
+            .lock()
+            .await
+            .pending_branch(key, branch)
+            .map(|(flow, req)| (flow, req.clone()));
+        if let Some(pending) = pending {
+            debug!("on_ring_timeout: {} isn't answered", branch);
+            self.time_out_branches(vec![pending]).await;
+        }
+    }
+
+    /// Gives up on the fork `key` as per 16.8 Processing Timer C unless a provisional response has restarted the timer:
+    /// the remaining targets aren't tried, the pending branches are CANCELled, and the server acts as if they had received 408 (Request Timeout),
+    /// so the caller gets the best final response and the fork completes
+    async fn on_fork_timeout(&mut self, key: String) {
+        let branches: Vec<(Flow, SipMessage)> = {
+            let mut forks = self.system.forks.lock().await;
+            match forks.time_left(&key) {
+                Some(time_left) if time_left > Duration::from_secs(0) => {
+                    drop(forks);
+                    self.set_timer(time_left, Timer::Fork { key }).await;
+                    return;
+                }
+                Some(_) => {}
+                None => return,
+            }
+            forks.stop(&key);
+            forks
+                .pending(&key)
+                .into_iter()
+                .map(|(flow, req)| (flow, req.clone()))
+                .collect()
+        };
+        debug!("on_fork_timeout: {} isn't answered", key);
+        self.time_out_branches(branches).await;
+    }
+
+    /// CANCELs the branches (the flows with the requests sent over them) and processes 408 (Request Timeout) for them
+    async fn time_out_branches(&mut self, branches: Vec<(Flow, SipMessage)>) {
+        for (flow, req) in branches {
+            if let Some(cancel) = Self::hop_request(&req, Method::Cancel, None) {
+                self.event_handler
+                    .handle(ClientEvent::Connect { flow, msg: cancel })
+                    .await;
+            }
+            match self.create_response_generator(&req, 408).build() {
+                Ok(res) => self.process_response(res, false).await,
+                Err(e) => error!("time_out_branches: failed to generate response: {}", e),
+            }
         }
     }
 
-    async fn on_req(&mut self, msg: SipMessage) {
-        self.send_res(&msg, 200).await;
+    /// Sends CANCEL on every branch of the fork `key` that hasn't received a final response as per 9.1 Client Behavior
+    async fn cancel_branches(&mut self, key: &str) {
+        let cancels: Vec<(Flow, SipMessage)> = self
+            .system
+            .forks
+            .lock()
+            .await
+            .pending(key)
+            .into_iter()
+            .filter_map(|(flow, req)| {
+                Self::hop_request(req, Method::Cancel, None).map(|cancel| (flow, cancel))
+            })
+            .collect();
+        for (flow, msg) in cancels {
+            self.event_handler
+                .handle(ClientEvent::Connect { flow, msg })
+                .await;
+        }
     }
 
-    async fn on_register(&mut self, msg: SipMessage) {
-        let username = if let Some(username) = msg.to_header_username() {
-            username
+    /// Rewrites the URI of `From` as `trunk` requires. The tag is kept, so the caller still recognizes the dialog
+    fn rewrite_from(msg: &mut SipMessage, trunk: &Trunk) {
+        for h in msg.headers_mut().0.iter_mut() {
+            if let Header::From(from) = h {
+                from.uri = trunk.from_uri(&from.uri);
+            }
+        }
+    }
+
+    /// Returns Call-ID and the From tag, which identify the caller's side of a call going out through a trunk
+    fn trunk_call_key(msg: &SipMessage) -> Option<(String, String)> {
+        Some((msg.call_id()?.clone(), msg.from_header_tag()?.clone()))
+    }
+
+    /// Starts tracking an INVITE going out through `trunk_name`.
+    /// Requests of a tracked call get CSeq numbers of the trunk leg
+    async fn track_trunk_call(&mut self, msg: &mut SipMessage, trunk_name: Option<String>) {
+        let call = if let Some(call) = Self::trunk_call_key(msg) {
+            call
         } else {
-            error!("on_register: no `username` in `To`");
-            self.send_res(&msg, 400).await;
             return;
         };
-        let expires = if let Some(expires) = msg.expires() {
-            expires
+        let mut trunk_calls = self.system.trunk_calls.lock().await;
+        if let Some(trunk) = trunk_name {
+            if msg.method() == Some(Method::Invite) && msg.to_header_tag().is_none() {
+                trunk_calls.insert(
+                    call.clone(),
+                    TrunkCall {
+                        trunk,
+                        cseqs: TrunkCSeqs::new(),
+                    },
+                );
+            }
+        }
+        if let (Some(trunk_call), Some(method), Some(cseq)) =
+            (trunk_calls.get_mut(&call), msg.method(), Self::cseq(msg))
+        {
+            Self::set_cseq(msg, trunk_call.cseqs.request(method, cseq));
+        }
+    }
+
+    /// Changes CSeq of a response to the caller of a call going out through a trunk back to what the caller sent
+    async fn restore_trunk_cseq(&mut self, msg: &mut SipMessage) {
+        let call = if let Some(call) = Self::trunk_call_key(msg) {
+            call
         } else {
-            error!("on_register: no `Expires`");
-            self.send_res(&msg, 400).await;
             return;
         };
-        self.prepare_and_send_res(&msg, 200, |generator| {
-            generator.header(Header::Expires(expires))
-        })
-        .await;
-        let mut reg = self.system.registrations.lock().await;
-        if expires > 0 {
-            reg.register_user(username.clone(), self.address);
-        } else {
-            reg.unregister_user(&username);
+        if let (Some(trunk_call), Some(method), Some(code), Some(cseq)) = (
+            self.system.trunk_calls.lock().await.get_mut(&call),
+            msg.method(),
+            msg.status_code(),
+            Self::cseq(msg),
+        ) {
+            Self::set_cseq(msg, trunk_call.cseqs.response(method, code, cseq));
         }
     }
 
-    async fn route_request(&mut self, mut msg: SipMessage) {
-        if msg.via_header_branch().is_none() {
-            error!("route_request: no `branch` in `Via`");
-            self.send_res(&msg, 400).await;
-            return;
+    async fn forget_trunk_call(&mut self, msg: &SipMessage) {
+        if let Some(call) = Self::trunk_call_key(msg) {
+            self.system.trunk_calls.lock().await.remove(&call);
+        }
+    }
+
+    fn set_cseq(msg: &mut SipMessage, number: u32) {
+        for h in msg.headers_mut().0.iter_mut() {
+            if let Header::CSeq(cseq, _) = h {
+                *cseq = number;
+            }
+        }
+    }
+
+    /// Sends the INVITE of `branch` again with credentials of the trunk that has challenged it with 401 or 407.
+    /// The new request gets the next CSeq number of the trunk leg.
+    /// Returns `false` if the challenge should be relayed to the caller instead (the call doesn't go through a trunk with credentials
+    /// or the credentials have already been rejected)
+    async fn answer_trunk_challenge(
+        &mut self,
+        key: &str,
+        branch: &str,
+        code: u32,
+        msg: &SipMessage,
+    ) -> bool {
+        let proxy = code == 407;
+        let call = if let Some(call) = Self::trunk_call_key(msg) {
+            call
+        } else {
+            return false;
         };
-        let callee_addr = if let Some(callee) = msg.to_header_username() {
-            let callee_addr = self.system.registrations.lock().await.user_addr(&callee);
-            if let Some(callee_addr) = callee_addr {
-                debug!("route_request: callee \"{}\" is registered", callee);
-                callee_addr
+        let system = self.system.clone();
+        let credentials = {
+            let trunk_calls = system.trunk_calls.lock().await;
+            let trunk_call = if let Some(trunk_call) = trunk_calls.get(&call) {
+                trunk_call
             } else {
-                debug!("route_request: callee \"{}\" isn't registered", callee);
-                self.send_res(&msg, 404).await;
-                return;
+                return false;
+            };
+            if let Some(credentials) = system
+                .trunks
+                .get(&trunk_call.trunk)
+                .and_then(Trunk::digest_credentials)
+            {
+                credentials
+            } else {
+                return false;
             }
+        };
+        let request = if let Some(request) = system.forks.lock().await.request(key, branch) {
+            request.clone()
         } else {
-            error!("route_request: no `username` in `To`");
-            self.send_res(&msg, 400).await;
-            return;
+            return false;
         };
-        // The server should have different dialogs with clients if the server operates in Back-to-Back User Agent mode
-        if !self.back_to_back || self.convert_request_dialog(&mut msg).await {
-            self.event_handler
-                .handle(ClientEvent::Route {
-                    addr: callee_addr,
-                    msg,
-                })
-                .await;
+        if !Self::authorizations(&request, proxy).is_empty() {
+            error!("answer_trunk_challenge: the trunk has rejected the credentials");
+            return false;
+        }
+        let (uri, body) = if let SipMessage::Request { uri, body, .. } = &request {
+            (uri.to_string(), body.clone())
         } else {
-            self.send_res(&msg, 400).await;
+            return false;
+        };
+        let authorization = Self::challenges(msg, proxy).iter().find_map(|challenge| {
+            credentials.authorization(challenge, &Method::Invite.to_string(), &uri, &body)
+        });
+        let authorization = if let Some(authorization) = authorization {
+            authorization
+        } else {
+            error!("answer_trunk_challenge: can't answer the challenge");
+            return false;
+        };
+        // 17.1.1.3 Construction of the ACK Request: the challenge is a non-2xx final response
+        if let Some(ack) = Self::hop_request(&request, Method::Ack, msg.headers().to()) {
+            self.event_handler.handle(ClientEvent::Send(ack)).await;
         }
-    }
-
-    async fn on_routed_request(&mut self, mut msg: SipMessage) {
-        // It's checked in route_request
-        let via_branch = msg.via_header_branch().unwrap().clone();
-        if let Some(h) = msg.via_header_mut() {
-            *h = self.via_hdr_with_branch(via_branch.clone());
+        let new_branch = ViaBranchGenerator::derive(branch, self.address);
+        let mut retry = request;
+        if let (Some(trunk_call), Some(cseq)) = (
+            system.trunk_calls.lock().await.get_mut(&call),
+            Self::cseq(&retry),
+        ) {
+            Self::set_cseq(&mut retry, trunk_call.cseqs.resend(cseq));
         }
-        if self.back_to_back {
-            // TODO: This should be checked in route_request
-            if let Some(h) = msg.contact_header_mut() {
-                *h = self.contact_hdr();
-            } else {
-                // method() is checked to exist in on_message (on_routed_message is called only if some function called from on_message asked the msg to be routed)
-                if msg.method().unwrap() == Method::Invite {
-                    error!("on_routed_request: no `Contact` in invite");
-                    return;
+        if let Some(h) = retry
+            .headers_mut()
+            .0
+            .iter_mut()
+            .find(|h| matches!(h, Header::Via(_)))
+        {
+            *h = Header::Via(self.via_hdr_with_branch(new_branch.clone()));
+        }
+        let name = if proxy {
+            "Proxy-Authorization"
+        } else {
+            "Authorization"
+        };
+        let index = retry
+            .headers()
+            .0
+            .iter()
+            .position(|h| matches!(h, Header::ContentLength(_)))
+            .unwrap_or_else(|| retry.headers().0.len());
+        retry
+            .headers_mut()
+            .0
+            .insert(index, Header::Other(name.to_string(), authorization));
+        system
+            .forks
+            .lock()
+            .await
+            .retry_branch(key, branch, new_branch.clone(), retry.clone());
+        for timer in self.timers.values_mut() {
+            if let Timer::Ring {
+                key: timer_key,
+                branch: timer_branch,
+            } = timer
+            {
+                if timer_key == key && timer_branch == branch {
+                    *timer_branch = new_branch.clone();
                 }
             }
         }
-        self.event_handler.handle(ClientEvent::Send(msg)).await;
+        debug!("answer_trunk_challenge: resent the INVITE with credentials");
+        self.event_handler.handle(ClientEvent::Send(retry)).await;
+        true
     }
 
-    async fn on_routed_response(&mut self, mut msg: SipMessage) {
-        if self.back_to_back {
-            if self.convert_response_dialog(&mut msg).await {
-                if let Some(h) = msg.contact_header_mut() {
-                    *h = self.contact_hdr();
-                } else {
-                    // method() is checked to exist in on_message (on_routed_response is called only if some function called from on_message asked the msg to be routed)
-                    if msg.method().unwrap() == Method::Invite {
-                        if let Some(status_code) = msg.status_code() {
-                            if status_code >= 200 && status_code <= 299 {
-                                error!("on_routed_response: no `Contact` in invite 2xx response");
-                                return;
-                            }
-                        } else {
-                            error!("on_routed_response: no status code in response");
-                            return;
-                        }
-                    }
+    /// Returns the values of `WWW-Authenticate` (or `Proxy-Authenticate` if `proxy` is `true`) of a response
+    fn challenges(msg: &SipMessage, proxy: bool) -> Vec<String> {
+        let name = if proxy {
+            "proxy-authenticate"
+        } else {
+            "www-authenticate"
+        };
+        msg.headers()
+            .0
+            .iter()
+            .filter_map(|h| match h {
+                Header::WwwAuthenticate(challenge) if !proxy => Some(challenge.to_string()),
+                Header::ProxyAuthenticate(challenge) if proxy => Some(challenge.to_string()),
+                Header::Other(h_name, value) if h_name.eq_ignore_ascii_case(name) => {
+                    Some(value.clone())
                 }
-            } else {
-                error!("on_routed_response: convert_response_dialog failed");
-                return;
-            }
-        }
-        self.event_handler.handle(ClientEvent::Send(msg)).await;
+                _ => None,
+            })
+            .collect()
     }
 
-    async fn route_response(&mut self, msg: SipMessage) {
-        let caller = if let Some(caller) = msg.from_header_username() {
-            caller
+    /// Builds CANCEL or ACK of the server's client transaction that sent `req`: the same Request-URI, Call-ID, From, CSeq number,
+    /// the server's Via only and the same Route set. `to` replaces `To` (an ACK has the tag of the response)
+    fn hop_request(req: &SipMessage, method: Method, to: Option<Header>) -> Option<SipMessage> {
+        let (uri, version, headers) = if let SipMessage::Request {
+            uri,
+            version,
+            headers,
+            ..
+        } = req
+        {
+            (uri, version, headers)
         } else {
-            error!("route_response: no `username` in `From`");
-            return;
+            return None;
         };
-        if let Some(caller_addr) = self.system.registrations.lock().await.user_addr(&caller) {
-            debug!("route_response: caller \"{}\" is registered", caller);
-            let event = ClientEvent::Route {
-                addr: caller_addr,
-                msg,
-            };
-            self.event_handler.handle(event).await;
-        } else {
-            debug!("route_response: caller \"{}\" isn't registered", caller);
+        let mut has_via = false;
+        let mut headers: Vec<Header> = headers
+            .0
+            .iter()
+            .filter_map(|h| match h {
+                Header::Via(_) if !has_via => {
+                    has_via = true;
+                    Some(h.clone())
+                }
+                Header::To(_) => Some(to.clone().unwrap_or_else(|| h.clone())),
+                Header::CSeq(cseq, _) => Some(Header::CSeq(*cseq, method)),
+                Header::From(_) | Header::CallId(_) | Header::MaxForwards(_) => Some(h.clone()),
+                Header::Other(name, _) if name.eq_ignore_ascii_case("route") => Some(h.clone()),
+                _ => None,
+            })
+            .collect();
+        headers.push(Header::ContentLength(0));
+        Some(SipMessage::Request {
+            method,
+            uri: uri.clone(),
+            version: version.clone(),
+            headers: Headers(headers),
+            body: Vec::new(),
+        })
+    }
+
+    fn via_branch(via: &ViaHeader) -> Option<String> {
+        via.uri.parameters.iter().find_map(|p| match p {
+            UriParam::Branch(branch) => Some(branch.clone()),
+            _ => None,
+        })
+    }
+
+    /// 18.2.1 Receiving Requests: records the address the request came from in its top Via.
+    /// `rport` (RFC 3581) is always filled, since the client worker a response is routed to is the one of the address the request came from
+    fn stamp_via(&self, msg: &mut SipMessage) {
+        let received = match self.address.ip() {
+            IpAddr::V4(ip) => Domain::Ipv4(ip, None),
+            IpAddr::V6(ip) => Domain::Domain(ip.to_string(), None),
+        };
+        if let Some(via) = msg.via_header_mut() {
+            via.uri.parameters.retain(|p| match p {
+                UriParam::Received(_) => false,
+                UriParam::Other(name, _) => !name.eq_ignore_ascii_case("rport"),
+                _ => true,
+            });
+            via.uri.parameters.push(UriParam::Received(received));
+            via.uri.parameters.push(UriParam::Other(
+                "rport".to_string(),
+                Some(self.address.port().to_string()),
+            ));
+        }
+    }
+
+    /// Returns the address a response is sent to as per 18.2.2 Sending Responses and RFC 3581 (`received` and `rport` take precedence over `sent-by`)
+    fn via_addr(via: &ViaHeader) -> Option<SocketAddr> {
+        let mut host = via.uri.host.clone();
+        let mut rport = None;
+        for param in &via.uri.parameters {
+            match param {
+                UriParam::Received(received) => host = received.clone(),
+                UriParam::Other(name, Some(port)) if name.eq_ignore_ascii_case("rport") => {
+                    rport = port.parse::<u16>().ok()
+                }
+                _ => {}
+            }
         }
+        let (ip, port) = match host {
+            Domain::Ipv4(ip, port) => (IpAddr::V4(ip), port),
+            Domain::Domain(host, port) => (host.parse().ok()?, port),
+        };
+        Some(SocketAddr::new(ip, rport.or(port).unwrap_or(5060)))
     }
 
     async fn on_subscribe(&mut self, msg: SipMessage) {
+        if !self.authenticate_caller(&msg).await {
+            return;
+        }
+        if Self::event_package(&msg).as_deref() == Some("reg") {
+            self.on_reg_subscribe(msg).await;
+            return;
+        }
         self.send_res(&msg, 200).await;
         let (uri, headers) = if let SipMessage::Request { uri, headers, .. } = msg {
             (uri, headers)
@@ -291,6 +1923,257 @@ impl<'a> MyClient<'a> {
         }
     }
 
+    /// Handles a subscription to the registration state of a user as per https://tools.ietf.org/html/rfc3680
+    async fn on_reg_subscribe(&mut self, msg: SipMessage) {
+        let aor = if let SipMessage::Request {
+            uri: Uri {
+                auth: Some(auth), ..
+            },
+            ..
+        } = &msg
+        {
+            auth.username.clone()
+        } else {
+            error!("on_reg_subscribe: no `username` in uri");
+            self.send_res(&msg, 400).await;
+            return;
+        };
+        // RFC 3680 6.6 Security Considerations: only the user itself learns where it's registered
+        if !self.trusted && msg.from_header_username().as_ref() != Some(&aor) {
+            debug!("on_reg_subscribe: subscription to another user \"{}\"", aor);
+            self.send_res(&msg, 403).await;
+            return;
+        }
+        // A refresh comes within the subscription's dialog, so it has the tag the server has chosen
+        let tag = msg
+            .to_header_tag()
+            .cloned()
+            .unwrap_or_else(|| self.system.dialog_gen.tag());
+        let (from_hdr, to_hdr, call_id) =
+            match (msg.headers().from(), msg.headers().to(), msg.call_id()) {
+                (Some(Header::From(from_hdr)), Some(Header::To(to_hdr)), Some(call_id)) => (
+                    from_hdr,
+                    to_hdr.param("tag", Some(tag.as_str())),
+                    call_id.clone(),
+                ),
+                _ => {
+                    error!("on_reg_subscribe: no `From`, `To` or `Call-ID`");
+                    self.send_res(&msg, 400).await;
+                    return;
+                }
+            };
+        // NOTIFY requests are sent to the subscriber's contact
+        let uri = Self::contact_hdrs(&msg)
+            .first()
+            .map(|h| h.uri.clone())
+            .unwrap_or_else(|| from_hdr.uri.clone());
+        // 6.2 Subscription Duration: the default is 3761 seconds
+        let expires = msg.expires().unwrap_or(3761);
+        match self
+            .create_response_generator(&msg, 200)
+            .header(Header::Expires(expires))
+            .build()
+        {
+            Ok(mut res) => {
+                // The subscription's dialog is identified by the tag NOTIFY requests are sent with
+                for h in res.headers_mut().0.iter_mut() {
+                    if let Header::To(h) = h {
+                        *h = to_hdr.clone();
+                    }
+                }
+                self.send_to_client(res).await;
+            }
+            Err(e) => error!("on_reg_subscribe: failed to generate response: {}", e),
+        }
+        let subscription = RegSubscription::new(
+            aor.clone(),
+            Flow::new(self.transport, self.address),
+            uri,
+            call_id.clone(),
+            to_hdr,
+            from_hdr,
+            expires,
+        );
+        let system = self.system.clone();
+        let bindings: Vec<Binding> = system
+            .registrations
+            .lock()
+            .await
+            .bindings(&aor)
+            .into_iter()
+            .cloned()
+            .collect();
+        let bindings: Vec<&Binding> = bindings.iter().collect();
+        let aor_uri = self.aor_uri(&aor);
+        let mut reg_subscriptions = system.reg_subscriptions.lock().await;
+        // 6.1 NOTIFY sent in response to SUBSCRIBE contains full state
+        let notify = if expires > 0 {
+            let subscription = reg_subscriptions.subscribe(subscription);
+            let (cseq, version) = subscription.next_notify();
+            let reg_info = RegInfo::full(&aor_uri, version, &bindings);
+            self.reg_notify(subscription, cseq, &reg_info, false).await
+        } else {
+            // Unsubscribing: the final NOTIFY terminates the subscription
+            let mut subscription = reg_subscriptions
+                .unsubscribe(&call_id)
+                .unwrap_or(subscription);
+            let (cseq, version) = subscription.next_notify();
+            let reg_info = RegInfo::full(&aor_uri, version, &bindings);
+            self.reg_notify(&subscription, cseq, &reg_info, true).await
+        };
+        drop(reg_subscriptions);
+        if let Some(notify) = notify {
+            self.send_to_client(notify).await;
+        }
+        // The subscription is terminated with the final NOTIFY unless it's refreshed
+        if expires > 0 {
+            let timer = Timer::SubscriptionExpiry { call_id };
+            self.set_timer(Duration::from_secs(u64::from(expires) + 1), timer)
+                .await;
+        }
+    }
+
+    /// Removes the expired bindings of `aor` and tells its subscribers about them
+    async fn expire_bindings(&mut self, aor: &str) {
+        let mut registrations = self.system.registrations.lock().await;
+        let expired = registrations.remove_expired_bindings(aor);
+        if expired.is_empty() {
+            return;
+        }
+        // The expired bindings have no time left, so the subscribers get the "expired" event for them
+        let mut bindings_before: Vec<Binding> =
+            registrations.bindings(aor).into_iter().cloned().collect();
+        drop(registrations);
+        bindings_before.extend(expired);
+        self.notify_reg_subscribers(aor, &bindings_before).await;
+    }
+
+    /// Terminates the subscription to registrations with `call_id` with the final NOTIFY unless it has been refreshed
+    async fn on_reg_subscription_expiry(&mut self, call_id: &str) {
+        let system = self.system.clone();
+        let mut subscription = if let Some(subscription) = system
+            .reg_subscriptions
+            .lock()
+            .await
+            .remove_expired(call_id)
+        {
+            subscription
+        } else {
+            return;
+        };
+        let bindings: Vec<Binding> = system
+            .registrations
+            .lock()
+            .await
+            .bindings(subscription.aor())
+            .into_iter()
+            .cloned()
+            .collect();
+        let bindings: Vec<&Binding> = bindings.iter().collect();
+        let (cseq, version) = subscription.next_notify();
+        let reg_info = RegInfo::full(&self.aor_uri(subscription.aor()), version, &bindings);
+        if let Some(notify) = self.reg_notify(&subscription, cseq, &reg_info, true).await {
+            self.send_to_client(notify).await;
+        }
+    }
+
+    /// Sends NOTIFY with partial state to every subscriber of `aor` if its bindings differ from `bindings_before`
+    async fn notify_reg_subscribers(&mut self, aor: &str, bindings_before: &[Binding]) {
+        let system = self.system.clone();
+        let bindings: Vec<Binding> = system
+            .registrations
+            .lock()
+            .await
+            .bindings(aor)
+            .into_iter()
+            .cloned()
+            .collect();
+        let bindings: Vec<&Binding> = bindings.iter().collect();
+        let aor_uri = self.aor_uri(aor);
+        if RegInfo::partial(&aor_uri, 0, bindings_before, &bindings).is_none() {
+            return;
+        }
+        let mut notifies = Vec::new();
+        for subscription in system.reg_subscriptions.lock().await.subscriptions_mut(aor) {
+            let (cseq, version) = subscription.next_notify();
+            if let Some(reg_info) = RegInfo::partial(&aor_uri, version, bindings_before, &bindings)
+            {
+                if let Some(notify) = self.reg_notify(subscription, cseq, &reg_info, false).await {
+                    notifies.push((subscription.flow(), notify));
+                }
+            }
+        }
+        for (flow, notify) in notifies {
+            if flow.addr() == self.address {
+                self.send_to_client(notify).await;
+            } else {
+                self.event_handler
+                    .handle(ClientEvent::Route {
+                        addr: flow.addr(),
+                        msg: notify,
+                    })
+                    .await;
+            }
+        }
+    }
+
+    async fn reg_notify(
+        &self,
+        subscription: &RegSubscription,
+        cseq: u32,
+        reg_info: &RegInfo,
+        terminated: bool,
+    ) -> Option<SipMessage> {
+        let body = reg_info.to_xml().into_bytes();
+        let subscription_state = if terminated {
+            Header::Other(
+                "Subscription-State".to_string(),
+                "terminated;reason=timeout".to_string(),
+            )
+        } else {
+            Header::SubscriptionState(SubscriptionState::Active {
+                expires: Some(subscription.expires()),
+                parameters: HashMap::new(),
+            })
+        };
+        match RequestGenerator::new()
+            .method(Method::Notify)
+            .uri(subscription.uri().clone())
+            .header(Header::Via(self.via_hdr().await))
+            .header(Header::From(subscription.from().clone()))
+            .header(Header::To(subscription.to().clone()))
+            .header(Header::Other("Event".to_string(), "reg".to_string()))
+            .header(Header::MaxForwards(70))
+            .header(Header::CallId(subscription.call_id().clone()))
+            .header(Header::CSeq(cseq, Method::Notify))
+            .header(Header::Contact(self.contact_hdr()))
+            .header(subscription_state)
+            .header(Header::Other(
+                "Content-Type".to_string(),
+                "application/reginfo+xml".to_string(),
+            ))
+            .build()
+        {
+            Ok(mut request) => {
+                Self::set_body(&mut request, body);
+                Some(request)
+            }
+            Err(e) => {
+                error!("reg_notify: failed to generate notify: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Returns `true` if `msg` is a response to NOTIFY of the "reg" event package. Such responses aren't routed
+    async fn is_reg_notify_response(&self, msg: &SipMessage) -> bool {
+        if let Some(call_id) = msg.call_id() {
+            self.system.reg_subscriptions.lock().await.contains(call_id)
+        } else {
+            false
+        }
+    }
+
     async fn prepare_and_send_res<F>(&mut self, req: &SipMessage, code: u32, f: F)
     where
         F: FnOnce(ResponseGenerator) -> ResponseGenerator,
@@ -315,6 +2198,7 @@ impl<'a> MyClient<'a> {
     }
 
     fn create_response_generator(&self, req: &SipMessage, code: u32) -> ResponseGenerator {
+        let hides_topology = self.back_to_back && self.system.topology_hiding.is_some();
         if let SipMessage::Request { headers, .. } = req {
             let headers = headers
                 .0
@@ -323,16 +2207,25 @@ impl<'a> MyClient<'a> {
                     Header::Contact(_) | Header::ContentLength(_) => None,
                     Header::To(h) => Some(Header::To(h.clone().param("tag", Some("123456")))),
                     // Via must be kept: 17.1.3 Matching Responses to Client Transactions
+                    Header::Via(_) | Header::From(_) | Header::CallId(_) | Header::CSeq(..) => {
+                        Some(h.clone())
+                    }
+                    // Topology hiding: only the headers of 8.2.6.2 Headers and Tags are copied
+                    _ if hides_topology => None,
                     _ => Some(h.clone()),
                 })
                 .collect();
-            ResponseGenerator::new()
-                .code(code)
-                .headers(headers)
+            let generator = ResponseGenerator::new().code(code).headers(headers);
+            // 10.3 Processing REGISTER Requests: Contact of a REGISTER response lists the bindings instead
+            let generator = if req.method() == Some(Method::Register) {
+                generator
+            } else {
                 // 8.1.1.8 Contact "URI at which the UA would like to receive requests"
-                .header(Header::Contact(self.contact_hdr()))
-                // 20.14 Content-Length "If no body is present in a msg, then the Content-Length header field value MUST be set to zero"
-                .header(Header::ContentLength(0))
+                generator.header(Header::Contact(self.contact_hdr()))
+            };
+            // 20.14 Content-Length "If no body is present in a msg, then the Content-Length header field value MUST be set to zero".
+            // set_body recomputes it for a response that gets a body
+            generator.header(Header::ContentLength(0))
         } else {
             panic!("not request");
         }
@@ -388,11 +2281,16 @@ impl<'a> MyClient<'a> {
             *msg.call_id_mut().unwrap() = new_call_id.clone();
             let incomplete_dialog = IncompleteDialogInfo::new(new_call_id, next_dialog_server_tag);
             let dialog = DialogInfo::new(call_id.clone(), server_tag, client_tag.clone());
-            self.system
-                .dialogs
-                .lock()
-                .await
-                .add(dialog, incomplete_dialog);
+            let mut dialogs = self.system.dialogs.lock().await;
+            dialogs.add(dialog, incomplete_dialog);
+            let users = [msg.from_header_username(), msg.to_header_username()];
+            if users
+                .iter()
+                .flatten()
+                .any(|user| self.system.record_users.contains(user))
+            {
+                dialogs.set_recording(&call_id, true).await;
+            }
         }
         true
     }
@@ -441,6 +2339,166 @@ impl<'a> MyClient<'a> {
         true
     }
 
+    fn cseq(msg: &SipMessage) -> Option<u32> {
+        msg.headers().0.iter().find_map(|h| match h {
+            Header::CSeq(cseq, _) => Some(*cseq),
+            _ => None,
+        })
+    }
+
+    /// Returns the values of `Authorization` or `Proxy-Authorization` (if `proxy` is `true`)
+    fn authorizations(msg: &SipMessage, proxy: bool) -> Vec<String> {
+        let name = if proxy {
+            "proxy-authorization"
+        } else {
+            "authorization"
+        };
+        msg.headers()
+            .0
+            .iter()
+            .filter_map(|h| match h {
+                Header::Authorization(authorization) if !proxy => Some(authorization.to_string()),
+                Header::ProxyAuthorization(authorization) if proxy => {
+                    Some(authorization.to_string())
+                }
+                Header::Other(h_name, value) if h_name.eq_ignore_ascii_case(name) => {
+                    Some(value.clone())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the event package name of `Event` without parameters
+    fn event_package(msg: &SipMessage) -> Option<String> {
+        msg.headers().0.iter().find_map(|h| match h {
+            Header::Event(event) => event
+                .to_string()
+                .split(';')
+                .next()
+                .map(|package| package.trim().to_string()),
+            Header::Other(name, value) if name.eq_ignore_ascii_case("event") => value
+                .split(';')
+                .next()
+                .map(|package| package.trim().to_string()),
+            _ => None,
+        })
+    }
+
+    fn contact_hdrs(msg: &SipMessage) -> Vec<&NamedHeader> {
+        msg.headers()
+            .0
+            .iter()
+            .filter_map(|h| match h {
+                Header::Contact(h) => Some(h),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the values of all headers named `name` that libsip doesn't parse (`Path`, `Route`, etc.).
+    /// Comma-separated values are split
+    fn header_values(msg: &SipMessage, name: &str) -> Vec<String> {
+        let mut values = Vec::new();
+        for h in msg.headers().0.iter() {
+            if let Header::Other(h_name, value) = h {
+                if h_name.eq_ignore_ascii_case(name) {
+                    let mut in_brackets = false;
+                    let mut in_quotes = false;
+                    let mut start = 0;
+                    for (i, c) in value.char_indices() {
+                        match c {
+                            '<' if !in_quotes => in_brackets = true,
+                            '>' if !in_quotes => in_brackets = false,
+                            '"' => in_quotes = !in_quotes,
+                            ',' if !in_brackets && !in_quotes => {
+                                values.push(value[start..i].trim().to_string());
+                                start = i + 1;
+                            }
+                            _ => {}
+                        }
+                    }
+                    values.push(value[start..].trim().to_string());
+                }
+            }
+        }
+        values.retain(|v| !v.is_empty());
+        values
+    }
+
+    /// Returns `true` if `Supported` of `msg` contains `option`
+    fn supports(msg: &SipMessage, option: &str) -> bool {
+        msg.headers().0.iter().any(|h| match h {
+            Header::Supported(options) => options.iter().any(|o| o == option),
+            Header::Other(name, value) if name.eq_ignore_ascii_case("supported") => {
+                value.split(',').any(|o| o.trim() == option)
+            }
+            _ => false,
+        })
+    }
+
+    /// libsip doesn't parse `Contact: *` into `Header::Contact`
+    fn has_wildcard_contact(msg: &SipMessage) -> bool {
+        msg.headers().0.iter().any(|h| match h {
+            Header::Other(name, value) => {
+                name.eq_ignore_ascii_case("contact") && value.trim() == "*"
+            }
+            _ => false,
+        })
+    }
+
+    /// Returns the contact as it was registered, but with "expires" set to the time left.
+    /// If `gruu` is `true`, "pub-gruu" and "temp-gruu" are added for a binding that has an instance ID as per RFC 5627 7.1.2.1
+    fn binding_contact_hdr(&self, username: &str, binding: &Binding, gruu: bool) -> NamedHeader {
+        let mut h = NamedHeader::new(binding.uri().clone());
+        h.parameters = binding.params().clone();
+        h.parameters
+            .insert("expires".to_string(), Some(binding.expires().to_string()));
+        if let (true, Some(instance_id)) = (gruu, binding.instance_id()) {
+            let pub_gruu = format!("\"{};gr={}\"", self.aor_uri(username), instance_id);
+            h.parameters.insert("pub-gruu".to_string(), Some(pub_gruu));
+            if let Some(temp_gruu) = binding.temp_gruu() {
+                let temp_gruu = format!("\"{};gr\"", self.aor_uri(temp_gruu));
+                h.parameters
+                    .insert("temp-gruu".to_string(), Some(temp_gruu));
+            }
+        }
+        h
+    }
+
+    /// Returns `None` if the Request-URI isn't a GRUU of the server's domains.
+    /// Otherwise, returns the contact of the binding the GRUU refers to (if any) as per RFC 5627 7.3.2
+    async fn gruu_contact(&self, msg: &SipMessage) -> Option<Option<(Uri, Flow, Vec<String>)>> {
+        let uri = if let SipMessage::Request { uri, .. } = msg {
+            uri
+        } else {
+            return None;
+        };
+        // A GRUU of another domain is routed like any other URI of that domain
+        if !self.is_local_domain(&uri.host) {
+            return None;
+        }
+        let gr = uri.parameters.iter().find_map(|p| match p {
+            UriParam::Other(name, value) if name.eq_ignore_ascii_case("gr") => Some(value.clone()),
+            _ => None,
+        })?;
+        let username = &uri.auth.as_ref()?.username;
+        let registrations = self.system.registrations.lock().await;
+        let binding = if let Some(instance_id) = gr {
+            registrations.binding_by_instance_id(username, &instance_id)
+        } else {
+            registrations.binding_by_temp_gruu(username)
+        };
+        Some(binding.map(|b| (b.uri().clone(), b.flow(), b.path().clone())))
+    }
+
+    /// Returns the address-of-record URI of a given user
+    fn aor_uri(&self, username: &str) -> String {
+        Uri::new(self.schema, self.domain.clone())
+            .auth(UriAuth::new(username.to_string()))
+            .to_string()
+    }
+
     fn contact_hdr(&self) -> NamedHeader {
         NamedHeader::new(Uri::new(self.schema, self.domain.clone()))
     }
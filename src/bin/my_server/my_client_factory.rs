@@ -1,11 +1,11 @@
 use crate::{my_client::MyClient, my_system::MySystem};
 use async_std::net::SocketAddr;
 use libsip::{Domain, Transport, UriSchema};
-use sip_server::{Client, ClientEventHandler, ClientFactory, Utils};
-use std::sync::Arc;
+use log::warn;
+use sip_server::{Access, Client, ClientEventHandler, ClientFactory, Flow, Utils};
+use std::{sync::Arc, time::Duration};
 
 pub struct MyClientFactory {
-    transport: Transport,
     schema: UriSchema,
     domain: Domain,
     utils: Arc<Utils>,
@@ -14,14 +14,55 @@ pub struct MyClientFactory {
 }
 
 impl ClientFactory for MyClientFactory {
+    fn access(&self, addr: SocketAddr) -> Access {
+        if self.system.access_list.is_allowed(addr.ip()) {
+            Access::Allow
+        } else if self.system.reject_denied {
+            Access::Reject
+        } else {
+            Access::Drop
+        }
+    }
+
+    fn is_blocked(&self, addr: SocketAddr) -> bool {
+        match &self.system.ban_list {
+            Some(ban_list) => ban_list.lock().unwrap().is_banned(addr.ip()),
+            None => false,
+        }
+    }
+
+    fn initial_flows(&self) -> Vec<Flow> {
+        match &self.system.dispatcher {
+            Some(dispatcher) => dispatcher.lock().unwrap().flows(),
+            None => Vec::new(),
+        }
+    }
+
+    fn reconnect_interval(&self) -> Duration {
+        self.system.probe_interval
+    }
+
+    fn on_connect_failed(&self, flow: Flow) {
+        self.on_backend_lost(flow);
+    }
+
+    fn on_disconnected(&self, flow: Flow) {
+        self.on_backend_lost(flow);
+    }
+
     fn create_client(
         &self,
         address: SocketAddr,
+        transport: Transport,
         event_handler: Box<dyn ClientEventHandler>,
     ) -> Box<dyn Client> {
+        // Back-ends of the dispatcher are trusted peers
+        let trusted = self.system.access_list.is_trusted(address.ip())
+            || self.initial_flows().iter().any(|f| f.addr() == address);
         Box::new(MyClient::new(
             address,
-            self.transport,
+            transport,
+            trusted,
             self.schema,
             self.domain.clone(),
             self.utils.clone(),
@@ -33,19 +74,22 @@ impl ClientFactory for MyClientFactory {
 }
 
 impl MyClientFactory {
-    pub fn new(
-        transport: Transport,
-        schema: UriSchema,
-        domain: Domain,
-        back_to_back: bool,
-    ) -> Self {
+    pub fn new(schema: UriSchema, domain: Domain, back_to_back: bool, system: MySystem) -> Self {
         Self {
-            transport,
             schema,
             domain,
-            system: Arc::new(MySystem::new()),
+            system: Arc::new(system),
             utils: Arc::new(Utils::new()),
             back_to_back,
         }
     }
+
+    /// A back-end of the dispatcher the server can't reach is down until a probe over a new connection succeeds
+    fn on_backend_lost(&self, flow: Flow) {
+        if let Some(dispatcher) = &self.system.dispatcher {
+            if dispatcher.lock().unwrap().set_down(flow.addr()) {
+                warn!("back-end {} is down", flow.addr());
+            }
+        }
+    }
 }
@@ -0,0 +1,370 @@
+use libsip::{Domain, Transport, Uri};
+use sip_server::{
+    AccessList, DigestCredentials, DispatchAlgorithm, ExpiresPolicy, Flow, IpNet, NumberPattern,
+    Route, Trunk, Trunks,
+};
+use std::{collections::HashMap, fs, net::SocketAddr, path::PathBuf, time::Duration};
+
+/// What the proxy does with requests whose Request-URI isn't in the server's domains
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ForeignDomainPolicy {
+    /// The request is forwarded to the domain
+    Forward,
+    /// The request is rejected with the status code (403 or 404)
+    Reject(u32),
+}
+
+/// Server configuration. It's read from a file where each line is `key = value`.
+/// Empty lines and lines starting with `#` are skipped
+#[derive(Debug)]
+pub struct MyConfig {
+    /// `mode`: `b2bua` (the default) or `proxy`
+    pub back_to_back: bool,
+    /// The file registrations are kept in. Registrations are kept in memory only if it isn't set
+    pub registrations_file: Option<PathBuf>,
+    /// `expires_default`, `expires_min` and `expires_max`
+    pub expires_policy: ExpiresPolicy,
+    /// The realm of digest authentication. The server's domain is used if it isn't set
+    pub realm: Option<String>,
+    /// The file of `username:password` lines. Users aren't authenticated if it isn't set
+    pub credentials_file: Option<PathBuf>,
+    /// `nonce_lifetime` in seconds. The authenticator's default is used if it isn't set
+    pub nonce_lifetime: Option<Duration>,
+    /// `allow`, `deny` and `trusted_peers`: comma-separated networks in CIDR notation. The keys may be repeated
+    pub access_list: AccessList,
+    /// `deny_action`: `drop` (the default) ignores denied sources, `reject` answers them with 403 (Forbidden)
+    pub reject_denied: bool,
+    /// `ban_threshold`: how many failed authentication attempts (from a source or for a user) get the source banned. `0` disables bans
+    pub ban_threshold: u32,
+    /// `ban_period` in seconds
+    pub ban_period: Duration,
+    /// `forking`: `parallel` (the default) or `sequential`
+    pub sequential_forking: bool,
+    /// `ring_timeout` in seconds. Devices ring until the caller gives up or Timer C fires if it isn't set
+    pub ring_timeout: Option<Duration>,
+    /// `domains`: comma-separated domain names the server is responsible for besides its IP address
+    pub domains: Vec<String>,
+    /// `foreign_domains`: `forward`, `forbidden` (the default, 403) or `not_found` (404)
+    pub foreign_domain_policy: ForeignDomainPolicy,
+    /// `outbound_proxy`: the URI of the proxy requests for other domains are forwarded through (for example, `sip:proxy.example.com;lr`)
+    pub outbound_proxy: Option<Uri>,
+    /// `trunk.<name>.<field>`: trunks in the order their names first appear. Fields are `host` (`host[:port]`), `transport` (`udp` or `tcp`),
+    /// `prefix` or `pattern`, `strip`, `prepend`, `username` and `password`, `from_user`
+    pub trunks: Trunks,
+    /// `dispatcher`: `round_robin`, `weighted` or `call_id_hash`. If it's set, INVITE and REGISTER requests are spread over `backends`
+    /// instead of being handled by the server
+    pub dispatcher: Option<DispatchAlgorithm>,
+    /// `backends`: comma-separated `ipv4:port` of the back-end servers, each optionally followed by `;weight=N` (1 by default)
+    /// and `;transport=tcp`
+    pub backends: Vec<(Flow, u32)>,
+    /// `probe_interval` in seconds: how often back-ends are probed with OPTIONS
+    pub probe_interval: Duration,
+    /// `probe_failures`: how many failed probes in a row mark a back-end down
+    pub probe_failures: u32,
+    /// `topology_hiding`: `off` (the default) or `on`. If it's on, the B2BUA doesn't let the network of one leg show on the other one
+    pub topology_hiding: bool,
+    /// `topology_hiding_allow`: comma-separated headers passed from one leg to the other. It replaces the default allow-list
+    pub topology_hiding_allow: Option<Vec<String>>,
+    /// `media_relay`: `off` (the default) or `on`, and `rtp_ports`: the range of ports (`first-last`) the relay takes.
+    /// It's `None` if media isn't relayed
+    pub rtp_ports: Option<(u16, u16)>,
+    /// `media_timeout` in seconds: how long the relay relays a stream without packets from either leg
+    pub media_timeout: Duration,
+    /// `record_users`: comma-separated users whose calls are recorded (as the caller or the callee)
+    pub record_users: Vec<String>,
+    /// `recordings_dir`: the directory recordings are written to (the current one by default)
+    pub recordings_dir: PathBuf,
+}
+
+impl Default for MyConfig {
+    fn default() -> Self {
+        Self {
+            back_to_back: true,
+            registrations_file: None,
+            expires_policy: ExpiresPolicy::default(),
+            realm: None,
+            credentials_file: None,
+            nonce_lifetime: None,
+            access_list: AccessList::default(),
+            reject_denied: false,
+            ban_threshold: 10,
+            ban_period: Duration::from_secs(600),
+            sequential_forking: false,
+            ring_timeout: None,
+            domains: Vec::new(),
+            foreign_domain_policy: ForeignDomainPolicy::Reject(403),
+            outbound_proxy: None,
+            trunks: Trunks::new(),
+            dispatcher: None,
+            backends: Vec::new(),
+            probe_interval: Duration::from_secs(10),
+            probe_failures: 2,
+            topology_hiding: false,
+            topology_hiding_allow: None,
+            rtp_ports: None,
+            media_timeout: Duration::from_secs(60),
+            record_users: Vec::new(),
+            recordings_dir: PathBuf::from("."),
+        }
+    }
+}
+
+impl MyConfig {
+    pub fn load(path: &str) -> sip_server::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut config = Self::default();
+        let (mut expires_default, mut expires_min, mut expires_max) = (3600, 60, 7200);
+        let mut trunks: Vec<(String, HashMap<String, String>)> = Vec::new();
+        let (mut media_relay, mut rtp_ports) = (false, (10000, 20000));
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => return Err(format!("invalid line: {}", line).into()),
+            };
+            match key {
+                "mode" => {
+                    config.back_to_back = match value {
+                        "b2bua" => true,
+                        "proxy" => false,
+                        _ => return Err(format!("invalid mode: {}", value).into()),
+                    }
+                }
+                "registrations_file" => config.registrations_file = Some(value.into()),
+                "expires_default" => expires_default = value.parse()?,
+                "expires_min" => expires_min = value.parse()?,
+                "expires_max" => expires_max = value.parse()?,
+                "realm" => config.realm = Some(value.to_string()),
+                "credentials_file" => config.credentials_file = Some(value.into()),
+                "nonce_lifetime" => {
+                    config.nonce_lifetime = Some(Duration::from_secs(value.parse()?))
+                }
+                "allow" => {
+                    for net in Self::parse_nets(value)? {
+                        config.access_list.allow(net);
+                    }
+                }
+                "deny" => {
+                    for net in Self::parse_nets(value)? {
+                        config.access_list.deny(net);
+                    }
+                }
+                "trusted_peers" => {
+                    for net in Self::parse_nets(value)? {
+                        config.access_list.trust(net);
+                    }
+                }
+                "ban_threshold" => config.ban_threshold = value.parse()?,
+                "ban_period" => config.ban_period = Duration::from_secs(value.parse()?),
+                "forking" => {
+                    config.sequential_forking = match value {
+                        "parallel" => false,
+                        "sequential" => true,
+                        _ => return Err(format!("invalid forking: {}", value).into()),
+                    }
+                }
+                "ring_timeout" => config.ring_timeout = Some(Duration::from_secs(value.parse()?)),
+                "domains" => config.domains.extend(
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|domain| !domain.is_empty())
+                        .map(str::to_string),
+                ),
+                "foreign_domains" => {
+                    config.foreign_domain_policy = match value {
+                        "forward" => ForeignDomainPolicy::Forward,
+                        "forbidden" => ForeignDomainPolicy::Reject(403),
+                        "not_found" => ForeignDomainPolicy::Reject(404),
+                        _ => return Err(format!("invalid foreign_domains: {}", value).into()),
+                    }
+                }
+                "deny_action" => {
+                    config.reject_denied = match value {
+                        "drop" => false,
+                        "reject" => true,
+                        _ => return Err(format!("invalid deny_action: {}", value).into()),
+                    }
+                }
+                "outbound_proxy" => {
+                    let route = Route::parse(value)
+                        .ok_or_else(|| format!("invalid outbound_proxy: {}", value))?;
+                    config.outbound_proxy = Some(route.uri().clone());
+                }
+                "dispatcher" => {
+                    config.dispatcher = Some(match value {
+                        "round_robin" => DispatchAlgorithm::RoundRobin,
+                        "weighted" => DispatchAlgorithm::Weighted,
+                        "call_id_hash" => DispatchAlgorithm::CallIdHash,
+                        _ => return Err(format!("invalid dispatcher: {}", value).into()),
+                    })
+                }
+                "backends" => {
+                    for backend in value.split(',').filter(|b| !b.trim().is_empty()) {
+                        let backend = Self::parse_backend(backend)
+                            .ok_or_else(|| format!("invalid backend: {}", backend))?;
+                        config.backends.push(backend);
+                    }
+                }
+                "probe_interval" => config.probe_interval = Duration::from_secs(value.parse()?),
+                "probe_failures" => config.probe_failures = value.parse()?,
+                "topology_hiding" => {
+                    config.topology_hiding = match value {
+                        "off" => false,
+                        "on" => true,
+                        _ => return Err(format!("invalid topology_hiding: {}", value).into()),
+                    }
+                }
+                "media_relay" => {
+                    media_relay = match value {
+                        "off" => false,
+                        "on" => true,
+                        _ => return Err(format!("invalid media_relay: {}", value).into()),
+                    }
+                }
+                "rtp_ports" => {
+                    let mut ports = value.splitn(2, '-').map(|p| p.trim().parse::<u16>());
+                    rtp_ports = match (ports.next(), ports.next()) {
+                        (Some(Ok(first)), Some(Ok(last))) if first < last => (first, last),
+                        _ => return Err(format!("invalid rtp_ports: {}", value).into()),
+                    }
+                }
+                "media_timeout" => config.media_timeout = Duration::from_secs(value.parse()?),
+                "record_users" => {
+                    config.record_users = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|user| !user.is_empty())
+                        .map(str::to_string)
+                        .collect();
+                }
+                "recordings_dir" => config.recordings_dir = PathBuf::from(value),
+                "topology_hiding_allow" => {
+                    config.topology_hiding_allow = Some(
+                        value
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|name| !name.is_empty())
+                            .map(str::to_string)
+                            .collect(),
+                    )
+                }
+                _ if key.starts_with("trunk.") => {
+                    let mut parts = key["trunk.".len()..].splitn(2, '.');
+                    let (name, field) = match (parts.next(), parts.next()) {
+                        (Some(name), Some(field)) if !name.is_empty() => (name, field),
+                        _ => return Err(format!("invalid key: {}", key).into()),
+                    };
+                    let index = match trunks.iter().position(|(n, _)| n == name) {
+                        Some(index) => index,
+                        None => {
+                            trunks.push((name.to_string(), HashMap::new()));
+                            trunks.len() - 1
+                        }
+                    };
+                    trunks[index].1.insert(field.to_string(), value.to_string());
+                }
+                _ => return Err(format!("unknown key: {}", key).into()),
+            }
+        }
+        config.expires_policy = ExpiresPolicy::new(expires_default, expires_min, expires_max);
+        for (name, fields) in trunks {
+            config.trunks.add(Self::parse_trunk(name, fields)?);
+        }
+        if media_relay {
+            config.rtp_ports = Some(rtp_ports);
+        }
+        if !config.record_users.is_empty() && config.rtp_ports.is_none() {
+            return Err("record_users needs media_relay".into());
+        }
+        if !config.back_to_back && (config.topology_hiding || config.rtp_ports.is_some()) {
+            return Err("topology_hiding and media_relay need mode = b2bua".into());
+        }
+        if config.dispatcher.is_some() && config.backends.is_empty() {
+            return Err("dispatcher needs backends".into());
+        }
+        Ok(config)
+    }
+
+    fn parse_trunk(name: String, mut fields: HashMap<String, String>) -> sip_server::Result<Trunk> {
+        let mut field = |field: &str| fields.remove(field);
+        let host = field("host").ok_or_else(|| format!("trunk {} has no host", name))?;
+        let host = Self::parse_host(&host).ok_or_else(|| format!("invalid host: {}", host))?;
+        let transport = match field("transport").as_deref() {
+            None | Some("udp") => Transport::Udp,
+            Some("tcp") => Transport::Tcp,
+            Some(transport) => return Err(format!("invalid transport: {}", transport).into()),
+        };
+        let numbers = match (field("prefix"), field("pattern")) {
+            (Some(prefix), None) => NumberPattern::Prefix(prefix),
+            (None, Some(pattern)) => NumberPattern::Pattern(pattern),
+            _ => return Err(format!("trunk {} needs either prefix or pattern", name).into()),
+        };
+        let mut trunk = Trunk::new(name.clone(), host, transport, numbers);
+        if let Some(strip) = field("strip") {
+            trunk = trunk.strip(strip.parse()?);
+        }
+        if let Some(prepend) = field("prepend") {
+            trunk = trunk.prepend(prepend);
+        }
+        match (field("username"), field("password")) {
+            (Some(username), Some(password)) => {
+                trunk = trunk.credentials(DigestCredentials::new(username, password))
+            }
+            (None, None) => {}
+            _ => return Err(format!("trunk {} needs both username and password", name).into()),
+        }
+        if let Some(from_user) = field("from_user") {
+            trunk = trunk.from_user(from_user);
+        }
+        if let Some(unknown) = fields.keys().next() {
+            return Err(format!("unknown trunk field: {}", unknown).into());
+        }
+        Ok(trunk)
+    }
+
+    /// Parses `ip:port[;weight=N][;transport=udp|tcp]` where `ip` is an IPv4 address
+    fn parse_backend(value: &str) -> Option<(Flow, u32)> {
+        let mut parts = value.trim().split(';');
+        let addr: SocketAddr = parts.next()?.trim().parse().ok()?;
+        if !addr.is_ipv4() {
+            return None;
+        }
+        let (mut transport, mut weight) = (Transport::Udp, 1);
+        for param in parts {
+            let mut param = param.trim().splitn(2, '=');
+            match (param.next()?, param.next()?) {
+                ("weight", value) => weight = value.parse().ok()?,
+                ("transport", "udp") => transport = Transport::Udp,
+                ("transport", "tcp") => transport = Transport::Tcp,
+                _ => return None,
+            }
+        }
+        Some((Flow::new(transport, addr), weight))
+    }
+
+    /// Parses `host[:port]` where `host` is an IPv4 address or a domain name
+    fn parse_host(value: &str) -> Option<Domain> {
+        let mut parts = value.trim().splitn(2, ':');
+        let host = parts.next().filter(|host| !host.is_empty())?;
+        let port = match parts.next() {
+            Some(port) => Some(port.parse().ok()?),
+            None => None,
+        };
+        Some(match host.parse() {
+            Ok(ip) => Domain::Ipv4(ip, port),
+            Err(_) => Domain::Domain(host.to_string(), port),
+        })
+    }
+
+    fn parse_nets(value: &str) -> Result<Vec<IpNet>, String> {
+        value
+            .split(',')
+            .filter(|net| !net.trim().is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
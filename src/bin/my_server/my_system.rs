@@ -1,19 +1,159 @@
-use async_std::sync::Mutex;
-use sip_server::{DialogGen, Dialogs, Registrations};
+use crate::my_config::{ForeignDomainPolicy, MyConfig};
+use async_std::{sync::Mutex, task};
+use libsip::Uri;
+use log::warn;
+use sip_server::{
+    AccessList, BanList, Credentials, DialogGen, Dialogs, DigestAuthenticator, Dispatcher,
+    DnsResolver, ExpiresPolicy, FileRegistrationStore, Forks, HiddenVias, MediaRelay,
+    RegSubscriptions, RegistrationStore, Registrations, ServerLocator, TopologyHiding, TrunkCSeqs,
+    Trunks,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+/// A call that goes out through a trunk
+pub struct TrunkCall {
+    pub trunk: String,
+    /// CSeq numbers of the caller's requests on the trunk leg
+    pub cseqs: TrunkCSeqs,
+}
 
-#[derive(Debug)]
 pub struct MySystem {
     pub dialogs: Mutex<Dialogs>,
-    pub registrations: Mutex<Registrations>,
+    pub registrations: Mutex<Box<dyn RegistrationStore>>,
+    pub reg_subscriptions: Mutex<RegSubscriptions>,
+    /// INVITE requests forked to several devices
+    pub forks: Mutex<Forks>,
+    /// Whether the devices of a user are tried one after another instead of all at once
+    pub sequential_forking: bool,
+    /// How long a device rings before the proxy CANCELs it. `None` if it rings until the caller gives up
+    pub ring_timeout: Option<Duration>,
+    /// Domain names the server is responsible for besides its IP address
+    pub domains: Vec<String>,
+    pub foreign_domain_policy: ForeignDomainPolicy,
+    /// Finds the servers of URIs the server doesn't know flows to (foreign domains, Route entries)
+    pub locator: ServerLocator,
+    /// The proxy requests for other domains are forwarded through. They go to the domains directly if it isn't set
+    pub outbound_proxy: Option<Uri>,
+    pub trunks: Trunks,
+    /// Calls going out through trunks by Call-ID and the caller's From tag
+    pub trunk_calls: Mutex<HashMap<(String, String), TrunkCall>>,
+    /// Spreads calls and registrations over back-end servers. `None` if the server handles them itself.
+    /// Back-ends are listed outside async code, so it's guarded by a blocking mutex
+    pub dispatcher: Option<std::sync::Mutex<Dispatcher>>,
+    pub probe_interval: Duration,
+    /// The Via headers the B2BUA has replaced with its own one in requests it has sent
+    pub hidden_vias: Mutex<HiddenVias>,
+    /// Hides each leg of the B2BUA from the other one. `None` if messages cross legs as they are
+    pub topology_hiding: Option<TopologyHiding>,
+    /// Relays the media of B2BUA calls. `None` if media goes between the endpoints directly
+    pub media_relay: Option<MediaRelay>,
+    /// Users whose calls are recorded
+    pub record_users: Vec<String>,
     pub dialog_gen: DialogGen,
+    pub expires_policy: ExpiresPolicy,
+    /// `None` if users aren't authenticated
+    pub authenticator: Option<Mutex<DigestAuthenticator>>,
+    pub access_list: AccessList,
+    /// Whether requests from denied sources are answered with 403 instead of being ignored
+    pub reject_denied: bool,
+    /// Sources banned for failing authentication. `None` if they aren't banned.
+    /// It's checked for every packet outside async code, so it's guarded by a blocking mutex
+    pub ban_list: Option<std::sync::Mutex<BanList>>,
 }
 
 impl MySystem {
-    pub fn new() -> Self {
-        Self {
-            dialogs: Mutex::new(Dialogs::new()),
-            registrations: Mutex::new(Registrations::new()),
-            dialog_gen: DialogGen::new(),
+    /// # Parameters
+    /// * `ip` - The server's address. It's the realm if `config` doesn't specify one, and media is relayed over it
+    pub fn new(config: &MyConfig, ip: Ipv4Addr) -> sip_server::Result<Self> {
+        let registrations: Box<dyn RegistrationStore> =
+            if let Some(path) = &config.registrations_file {
+                Box::new(FileRegistrationStore::open(path)?)
+            } else {
+                Box::new(Registrations::new())
+            };
+        let authenticator = if let Some(path) = &config.credentials_file {
+            let credentials = Credentials::load(path)?;
+            let realm = config.realm.clone().unwrap_or_else(|| ip.to_string());
+            let mut authenticator = DigestAuthenticator::new(realm, Box::new(credentials));
+            if let Some(nonce_lifetime) = config.nonce_lifetime {
+                authenticator = authenticator.nonce_lifetime(nonce_lifetime);
+            }
+            Some(Mutex::new(authenticator))
+        } else {
+            None
+        };
+        // DNS is needed only for other domains, so a host without a resolver configuration still runs
+        let resolver = match task::block_on(DnsResolver::from_system_conf()) {
+            Ok(resolver) => resolver,
+            Err(e) => {
+                warn!(
+                    "no system DNS configuration ({}), public DNS servers are used",
+                    e
+                );
+                task::block_on(DnsResolver::with_default_conf())?
+            }
+        };
+        let ban_list = if config.ban_threshold > 0 {
+            Some(std::sync::Mutex::new(BanList::new(
+                config.ban_threshold,
+                config.ban_period,
+            )))
+        } else {
+            None
+        };
+        let dispatcher = config.dispatcher.map(|algorithm| {
+            let mut dispatcher = Dispatcher::new(algorithm).max_failures(config.probe_failures);
+            for (flow, weight) in config.backends.iter() {
+                dispatcher.add(*flow, *weight);
+            }
+            std::sync::Mutex::new(dispatcher)
+        });
+        let topology_hiding = if config.topology_hiding {
+            let mut hiding = TopologyHiding::new();
+            if let Some(headers) = &config.topology_hiding_allow {
+                hiding = hiding.allowed_headers(headers.clone());
+            }
+            Some(hiding)
+        } else {
+            None
+        };
+        let media_relay = config.rtp_ports.map(|(first_port, last_port)| {
+            MediaRelay::new(IpAddr::V4(ip), first_port, last_port)
+                .inactivity_timeout(config.media_timeout)
+        });
+        let mut dialogs = Dialogs::new();
+        if let Some(relay) = &media_relay {
+            dialogs = dialogs.media_relay(relay.clone(), config.recordings_dir.clone());
         }
+        Ok(Self {
+            dialogs: Mutex::new(dialogs),
+            registrations: Mutex::new(registrations),
+            reg_subscriptions: Mutex::new(RegSubscriptions::new()),
+            forks: Mutex::new(Forks::new()),
+            sequential_forking: config.sequential_forking,
+            ring_timeout: config.ring_timeout,
+            domains: config.domains.clone(),
+            foreign_domain_policy: config.foreign_domain_policy,
+            locator: ServerLocator::new(Box::new(resolver)),
+            outbound_proxy: config.outbound_proxy.clone(),
+            trunks: config.trunks.clone(),
+            trunk_calls: Mutex::new(HashMap::new()),
+            dispatcher,
+            probe_interval: config.probe_interval,
+            hidden_vias: Mutex::new(HiddenVias::new()),
+            topology_hiding,
+            media_relay,
+            record_users: config.record_users.clone(),
+            dialog_gen: DialogGen::new(),
+            expires_policy: config.expires_policy,
+            authenticator,
+            access_list: config.access_list.clone(),
+            reject_denied: config.reject_denied,
+            ban_list,
+        })
     }
 }
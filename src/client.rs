@@ -1,24 +1,78 @@
+use crate::Flow;
 use async_std::net::SocketAddr;
 use async_trait::async_trait;
-use libsip::SipMessage;
+use libsip::{SipMessage, Transport};
+use std::time::Duration;
 
 #[async_trait]
 pub trait Client: Send + Sync {
+    /// Called once when the client is created, before it handles any message
+    async fn on_start(&mut self) {}
+
     async fn on_msg(&mut self, msg: SipMessage);
 
     async fn on_routed_msg(&mut self, msg: SipMessage);
+
+    /// Called when a timer set with [`ClientEvent::SetTimer`](enum.ClientEvent.html#variant.SetTimer) expires
+    async fn on_timer(&mut self, _id: u64) {}
+
+    /// Called when `msg` the client has routed with [`ClientEvent::Connect`](enum.ClientEvent.html#variant.Connect) can't be delivered,
+    /// since connecting to `flow` has failed
+    async fn on_route_failed(&mut self, _flow: Flow, _msg: SipMessage) {}
+}
+
+/// What the server does with messages coming from a new connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    /// A client is created to handle the messages
+    Allow,
+    /// The messages are ignored
+    Drop,
+    /// Requests are answered with 403 (Forbidden), other messages are ignored
+    Reject,
 }
 
 /// The server will ask the factory to create a client when a new connection established.
 /// The created client will handle message coming from the connection
 pub trait ClientFactory: Send + Sync {
+    /// Decides whether messages from `addr` are accepted. The server calls it before [`create_client`](#tymethod.create_client),
+    /// and no client is created for `addr` unless it's allowed
+    fn access(&self, _addr: SocketAddr) -> Access {
+        Access::Allow
+    }
+
+    /// Returns `true` if messages from `addr` should be dropped before they are parsed, even if a client exists for `addr`
+    /// (for example, because of a ban). The server calls it for every UDP datagram and TCP read
+    fn is_blocked(&self, _addr: SocketAddr) -> bool {
+        false
+    }
+
+    /// Returns the flows the server connects to at startup, creating clients for them before any message is exchanged.
+    /// The server connects again every [`reconnect_interval`](#method.reconnect_interval) to those it has no connections with
+    fn initial_flows(&self) -> Vec<Flow> {
+        Vec::new()
+    }
+
+    /// Returns how often the server tries to connect to the initial flows it has no connections with
+    fn reconnect_interval(&self) -> Duration {
+        Duration::from_secs(30)
+    }
+
+    /// Called when connecting to `flow` fails
+    fn on_connect_failed(&self, _flow: Flow) {}
+
+    /// Called when the connection of `flow` is closed. Its client is dropped, so messages routed to `flow` later open a new connection
+    fn on_disconnected(&self, _flow: Flow) {}
+
     /// Creates a new client. The server ensures that this function won't be called if some client exists for `address`
     /// # Parameters
     /// * `addr` - The address of the connection that the created client will receive messages from
+    /// * `transport` - The transport protocol of the connection
     /// * `event_handler` - The event handler that provides the only mechanism for the created client to communicate with the server
     fn create_client(
         &self,
         addr: SocketAddr,
+        transport: Transport,
         event_handler: Box<dyn ClientEventHandler>,
     ) -> Box<dyn Client>;
 }
@@ -29,6 +83,11 @@ pub enum ClientEvent {
     Send(SipMessage),
     /// Routes the message to be handled by the client whose connection's address matches
     Route { addr: SocketAddr, msg: SipMessage },
+    /// Routes the message like [`Route`](#variant.Route) does. If no client's connection matches, the server opens a connection to `flow`
+    /// and creates a client for it first. If connecting fails, the message is given back to [`Client::on_route_failed`](trait.Client.html#method.on_route_failed)
+    Connect { flow: Flow, msg: SipMessage },
+    /// Makes the server call [`Client::on_timer`](trait.Client.html#method.on_timer) of the client with `id` after `after`
+    SetTimer { after: Duration, id: u64 },
 }
 
 #[async_trait]
@@ -1,10 +1,16 @@
-use crate::{Client, Receiver};
+use crate::{Client, Flow, Receiver};
 use futures::StreamExt;
 use libsip::SipMessage;
 
 pub(crate) enum ClientWorkerMessage {
     Received(SipMessage),
     Routed(SipMessage),
+    Timer(u64),
+    /// The message the client has routed can't be delivered to `flow`
+    RouteFailed {
+        flow: Flow,
+        msg: SipMessage,
+    },
 }
 
 pub(crate) struct ClientWorker {
@@ -18,10 +24,15 @@ impl ClientWorker {
     }
 
     pub async fn run<'a>(mut self) {
+        self.client.on_start().await;
         while let Some(msg) = self.receiver.next().await {
             match msg {
                 ClientWorkerMessage::Received(msg) => self.client.on_msg(msg).await,
                 ClientWorkerMessage::Routed(msg) => self.client.on_routed_msg(msg).await,
+                ClientWorkerMessage::Timer(id) => self.client.on_timer(id).await,
+                ClientWorkerMessage::RouteFailed { flow, msg } => {
+                    self.client.on_route_failed(flow, msg).await
+                }
             }
         }
     }
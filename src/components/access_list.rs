@@ -0,0 +1,130 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// A network in CIDR notation (for example, `192.168.0.0/16`). A single address is a network of its own
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Returns `None` if `prefix_len` is longer than `addr`
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        if prefix_len <= Self::max_prefix_len(addr) {
+            Some(Self { addr, prefix_len })
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn max_prefix_len(addr: IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid network: {}", s);
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .and_then(|addr| addr.parse().ok())
+            .ok_or_else(invalid)?;
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None => Self::max_prefix_len(addr),
+        };
+        Self::new(addr, prefix_len).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Decides which source addresses SIP traffic is accepted from
+/// # Examples
+/// ```
+/// use sip_server::AccessList;
+///
+/// let mut access_list = AccessList::new();
+/// access_list.allow("10.0.0.0/8".parse().unwrap());
+/// access_list.deny("10.0.1.0/24".parse().unwrap());
+/// access_list.trust("203.0.113.5".parse().unwrap());
+///
+/// assert!(access_list.is_allowed("10.0.0.1".parse().unwrap()));
+/// assert!(!access_list.is_allowed("10.0.1.1".parse().unwrap()));
+/// assert!(!access_list.is_allowed("192.168.0.1".parse().unwrap()));
+///
+/// // Trusted peers are allowed even if they are outside the allowed networks
+/// assert!(access_list.is_allowed("203.0.113.5".parse().unwrap()));
+/// assert!(access_list.is_trusted("203.0.113.5".parse().unwrap()));
+/// assert!(!access_list.is_trusted("10.0.0.1".parse().unwrap()));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    /// Networks traffic is accepted from. Any network is allowed if it's empty
+    allow: Vec<IpNet>,
+    /// Networks traffic is never accepted from. It takes precedence over `allow` and `trusted`
+    deny: Vec<IpNet>,
+    /// Networks whose clients don't have to authenticate
+    trusted: Vec<IpNet>,
+}
+
+impl AccessList {
+    /// Creates a list that allows any address and trusts none
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(&mut self, net: IpNet) {
+        self.allow.push(net);
+    }
+
+    pub fn deny(&mut self, net: IpNet) {
+        self.deny.push(net);
+    }
+
+    pub fn trust(&mut self, net: IpNet) {
+        self.trusted.push(net);
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty()
+            || self.allow.iter().any(|net| net.contains(ip))
+            || self.trusted.iter().any(|net| net.contains(ip))
+    }
+
+    /// Returns `true` if `ip` is allowed and its clients are considered authenticated
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.is_allowed(ip) && self.trusted.iter().any(|net| net.contains(ip))
+    }
+}
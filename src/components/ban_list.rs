@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// How many sources and usernames failures are counted for at most, so that a flood of made-up usernames can't exhaust memory
+const MAX_TRACKED: usize = 10_000;
+
+/// Failed authentication attempts since `since`
+#[derive(Clone, Copy, Debug)]
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+/// Counts failed authentication attempts per source IP and per username and bans sources that fail too often
+/// # Examples
+/// ```
+/// use sip_server::BanList;
+/// use std::time::Duration;
+///
+/// let mut ban_list = BanList::new(3, Duration::from_secs(600));
+/// let attacker = "198.51.100.7".parse().unwrap();
+///
+/// assert!(!ban_list.record_failure(attacker, "alice"));
+/// assert!(!ban_list.record_failure(attacker, "bob"));
+/// // The third failure crosses the threshold
+/// assert!(ban_list.record_failure(attacker, "carol"));
+/// assert!(ban_list.is_banned(attacker));
+/// assert_eq!(ban_list.bans().len(), 1);
+///
+/// assert!(ban_list.lift(attacker));
+/// assert!(!ban_list.is_banned(attacker));
+/// ```
+#[derive(Debug)]
+pub struct BanList {
+    /// How many failures (from a source or for a user) lead to a ban
+    threshold: u32,
+    /// How long a ban lasts. Failures older than it are forgotten
+    ban_period: Duration,
+    ip_failures: HashMap<IpAddr, Failures>,
+    user_failures: HashMap<String, Failures>,
+    /// Banned sources with the time their bans end at
+    bans: HashMap<IpAddr, Instant>,
+}
+
+impl BanList {
+    pub fn new(threshold: u32, ban_period: Duration) -> Self {
+        Self {
+            threshold,
+            ban_period,
+            ip_failures: HashMap::new(),
+            user_failures: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    /// Counts a failed attempt of `ip` to authenticate as `username`.
+    /// Bans `ip` and returns `true` if the failures of either `ip` or `username` reach the threshold
+    pub fn record_failure(&mut self, ip: IpAddr, username: &str) -> bool {
+        let now = Instant::now();
+        self.remove_expired(now);
+        let new_failures = Failures {
+            count: 0,
+            since: now,
+        };
+        let ip_count = Self::count(&mut self.ip_failures, ip, new_failures);
+        let user_count = Self::count(&mut self.user_failures, username.to_string(), new_failures);
+        if ip_count < self.threshold && user_count < self.threshold {
+            return false;
+        }
+        // Both start over, so that the next failure for the user doesn't ban another source right away
+        self.ip_failures.remove(&ip);
+        self.user_failures.remove(username);
+        self.bans.insert(ip, now + self.ban_period);
+        true
+    }
+
+    /// Counts a failure of `key` and returns its failures. The oldest entry is dropped if there are too many
+    fn count<K: Clone + Eq + Hash>(
+        failures: &mut HashMap<K, Failures>,
+        key: K,
+        new_failures: Failures,
+    ) -> u32 {
+        if !failures.contains_key(&key) && failures.len() >= MAX_TRACKED {
+            let oldest = failures
+                .iter()
+                .min_by_key(|(_, failures)| failures.since)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                failures.remove(&oldest);
+            }
+        }
+        let failures = failures.entry(key).or_insert(new_failures);
+        failures.count += 1;
+        failures.count
+    }
+
+    /// Forgets failures counted for longer than the ban period and bans that have ended
+    fn remove_expired(&mut self, now: Instant) {
+        let ban_period = self.ban_period;
+        let is_current = |failures: &Failures| now.duration_since(failures.since) < ban_period;
+        self.ip_failures.retain(|_, failures| is_current(failures));
+        self.user_failures
+            .retain(|_, failures| is_current(failures));
+        self.bans.retain(|_, until| now < *until);
+    }
+
+    /// Forgets the failures of `ip` and `username` after `ip` has authenticated as `username`
+    pub fn record_success(&mut self, ip: IpAddr, username: &str) {
+        self.ip_failures.remove(&ip);
+        self.user_failures.remove(username);
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        match self.bans.get(&ip) {
+            Some(until) => Instant::now() < *until,
+            None => false,
+        }
+    }
+
+    /// Returns banned sources with how long their bans last
+    pub fn bans(&mut self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        self.remove_expired(now);
+        self.bans
+            .iter()
+            .map(|(ip, until)| (*ip, until.duration_since(now)))
+            .collect()
+    }
+
+    /// Lifts the ban of `ip`. Returns `false` if `ip` isn't banned
+    pub fn lift(&mut self, ip: IpAddr) -> bool {
+        self.ip_failures.remove(&ip);
+        match self.bans.remove(&ip) {
+            Some(until) => Instant::now() < until,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_resets_user_failures() {
+        let mut ban_list = BanList::new(2, Duration::from_secs(600));
+        let attacker = "198.51.100.7".parse().unwrap();
+        let other = "198.51.100.8".parse().unwrap();
+        assert!(!ban_list.record_failure(attacker, "alice"));
+        assert!(ban_list.record_failure(attacker, "alice"));
+        // A typo of another source isn't enough for a ban
+        assert!(!ban_list.record_failure(other, "alice"));
+        assert!(!ban_list.is_banned(other));
+    }
+
+    #[test]
+    fn failures_expire() {
+        let mut ban_list = BanList::new(2, Duration::from_millis(10));
+        let ip = "198.51.100.7".parse().unwrap();
+        assert!(!ban_list.record_failure(ip, "alice"));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!ban_list.record_failure(ip, "bob"));
+        assert_eq!(ban_list.ip_failures.len(), 1);
+        assert_eq!(ban_list.user_failures.len(), 1);
+    }
+
+    #[test]
+    fn tracked_usernames_are_limited() {
+        let mut ban_list = BanList::new(u32::MAX, Duration::from_secs(600));
+        let ip = "198.51.100.7".parse().unwrap();
+        for i in 0..MAX_TRACKED + 10 {
+            ban_list.record_failure(ip, &format!("user{}", i));
+        }
+        assert_eq!(ban_list.user_failures.len(), MAX_TRACKED);
+    }
+}
@@ -0,0 +1,121 @@
+use super::DigestAlgorithm;
+use std::{collections::HashMap, fs, path::Path};
+
+/// Provides passwords of users for digest authentication
+pub trait CredentialStore: Send + Sync {
+    /// Returns `username`'s password or `None` if the user is unknown
+    fn password(&self, username: &str) -> Option<String>;
+
+    /// Returns the algorithms `username` may authenticate with or `None` if any offered algorithm is allowed
+    fn algorithms(&self, _username: &str) -> Option<Vec<DigestAlgorithm>> {
+        None
+    }
+}
+
+#[derive(Clone, Debug)]
+struct UserCredentials {
+    password: String,
+    algorithms: Option<Vec<DigestAlgorithm>>,
+}
+
+/// [`CredentialStore`](trait.CredentialStore.html) that keeps passwords in memory
+#[derive(Clone, Debug, Default)]
+pub struct Credentials(HashMap<String, UserCredentials>);
+
+impl Credentials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads credentials from a file where each line is `username:password` or `username:password:algorithms`,
+    /// where `algorithms` is names of the algorithms the user may authenticate with separated by `,` (for example, `SHA-256,SHA-512-256`).
+    /// The username ends at the first `:`, so a password may contain `:`. A password that ends with `:` and algorithm names
+    /// has to be followed by the algorithms. Empty lines and lines starting with `#` are skipped
+    pub fn load<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Self::parse(&content)
+    }
+
+    fn parse(content: &str) -> crate::Result<Self> {
+        let mut credentials = Self::new();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            let (username, rest) = match (parts.next(), parts.next()) {
+                (Some(username), Some(rest)) if !username.is_empty() => (username, rest),
+                _ => return Err(format!("invalid credentials: {}", line).into()),
+            };
+            // The last field is the algorithms only if all of it is algorithm names
+            let algorithms = rest.rfind(':').and_then(|colon| {
+                let algorithms = rest[colon + 1..]
+                    .split(',')
+                    .map(|a| DigestAlgorithm::from_name(a.trim()))
+                    .collect::<Option<Vec<_>>>()?;
+                Some((colon, algorithms))
+            });
+            match algorithms {
+                Some((colon, algorithms)) => {
+                    credentials.insert(username.to_string(), rest[..colon].to_string());
+                    credentials.restrict_algorithms(username, algorithms);
+                }
+                None => credentials.insert(username.to_string(), rest.to_string()),
+            }
+        }
+        Ok(credentials)
+    }
+
+    /// Adds a user or changes an existing user's password
+    pub fn insert(&mut self, username: String, password: String) {
+        self.0.insert(
+            username,
+            UserCredentials {
+                password,
+                algorithms: None,
+            },
+        );
+    }
+
+    /// Allows `username` to authenticate only with `algorithms`
+    pub fn restrict_algorithms(&mut self, username: &str, algorithms: Vec<DigestAlgorithm>) {
+        if let Some(user) = self.0.get_mut(username) {
+            user.algorithms = Some(algorithms);
+        }
+    }
+}
+
+impl CredentialStore for Credentials {
+    fn password(&self, username: &str) -> Option<String> {
+        self.0.get(username).map(|user| user.password.clone())
+    }
+
+    fn algorithms(&self, username: &str) -> Option<Vec<DigestAlgorithm>> {
+        self.0
+            .get(username)
+            .and_then(|user| user.algorithms.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_may_contain_colons() {
+        let credentials = Credentials::parse("joe:pass:word\nann:a:b:SHA-256,MD5\n").unwrap();
+        assert_eq!(credentials.password("joe"), Some("pass:word".to_string()));
+        assert_eq!(credentials.algorithms("joe"), None);
+        assert_eq!(credentials.password("ann"), Some("a:b".to_string()));
+        assert_eq!(
+            credentials.algorithms("ann"),
+            Some(vec![DigestAlgorithm::Sha256, DigestAlgorithm::Md5])
+        );
+    }
+
+    #[test]
+    fn line_without_password_is_invalid() {
+        assert!(Credentials::parse("joe\n").is_err());
+        assert!(Credentials::parse(":secret\n").is_err());
+    }
+}
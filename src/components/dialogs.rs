@@ -1,3 +1,6 @@
+use super::MediaRelay;
+use std::{collections::HashSet, path::PathBuf};
+
 #[derive(Debug)]
 pub struct DialogInfo {
     call_id: String,
@@ -66,6 +69,10 @@ pub struct Dialogs {
     dialogs: Vec<Dialog>,
     incomplete_dialogs: Vec<IncompleteDialog>,
     next_dialog_id: u32,
+    /// Pairs of linked dialogs whose media is recorded
+    recorded: HashSet<u32>,
+    /// The relay that records media and the directory recordings are written to
+    media_relay: Option<(MediaRelay, PathBuf)>,
 }
 
 impl Dialogs {
@@ -73,6 +80,13 @@ impl Dialogs {
         Dialogs::default()
     }
 
+    /// Makes `relay` record calls as soon as recording is turned on, to files in `recordings_dir`.
+    /// The relay knows a call by the ID of its pair of dialogs
+    pub fn media_relay(mut self, relay: MediaRelay, recordings_dir: PathBuf) -> Self {
+        self.media_relay = Some((relay, recordings_dir));
+        self
+    }
+
     pub fn add(&mut self, dialog_info: DialogInfo, incomplete_dialog_info: IncompleteDialogInfo) {
         let dialog_id = self.take_dialog_id();
         let incomplete_dialog_id = self.take_dialog_id();
@@ -132,6 +146,73 @@ impl Dialogs {
         self.dialogs.push(dialog);
     }
 
+    /// Returns the pair of linked dialogs `call_id` belongs to with the leg of the dialog:
+    /// `0` is the dialog with the caller, `1` is the dialog with the callee
+    pub fn pair(&self, call_id: &str) -> Option<(u32, usize)> {
+        let (id, linked_dialog) = self
+            .dialogs
+            .iter()
+            .map(|d| (&d.call_id, d.id, d.linked_dialog))
+            .chain(
+                self.incomplete_dialogs
+                    .iter()
+                    .map(|d| (&d.call_id, d.id, d.linked_dialog)),
+            )
+            .find(|(id, _, _)| *id == call_id)
+            .map(|(_, id, linked_dialog)| (id, linked_dialog))?;
+        // The dialog with the caller is added first, so it has the lower ID
+        if id < linked_dialog {
+            Some((id, 0))
+        } else {
+            Some((linked_dialog, 1))
+        }
+    }
+
+    /// Turns recording of the call `call_id` belongs to (either leg) on or off, and so does the media relay right away.
+    /// Returns `false` if there's no such call
+    pub async fn set_recording(&mut self, call_id: &str, recording: bool) -> bool {
+        let pair = match self.pair(call_id) {
+            Some((pair, _)) => pair,
+            None => return false,
+        };
+        if recording {
+            self.recorded.insert(pair);
+        } else {
+            self.recorded.remove(&pair);
+        }
+        if let Some((relay, recordings_dir)) = &self.media_relay {
+            match self.recording(call_id) {
+                Some(name) => {
+                    let path = recordings_dir.join(Self::file_name(name));
+                    relay.record(pair, path).await;
+                }
+                None => relay.stop_recording(pair).await,
+            }
+        }
+        true
+    }
+
+    /// Returns the Call-ID of the caller's dialog if the call `call_id` belongs to is recorded.
+    /// Recordings are named after it
+    pub fn recording(&self, call_id: &str) -> Option<&String> {
+        let (pair, _) = self.pair(call_id)?;
+        if !self.recorded.contains(&pair) {
+            return None;
+        }
+        self.dialog_by_id(pair).map(|d| &d.call_id)
+    }
+
+    /// Returns `call_id` with characters that aren't allowed in file names replaced
+    fn file_name(call_id: &str) -> String {
+        call_id
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' | '@' => c,
+                _ => '_',
+            })
+            .collect()
+    }
+
     fn dialog(&self, call_id: &str, server_tag: &str, client_tag: &str) -> Option<&Dialog> {
         self.dialogs.iter().find(|d| {
             d.call_id == call_id && d.server_tag == server_tag && d.client_tag == client_tag
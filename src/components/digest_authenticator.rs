@@ -0,0 +1,727 @@
+use super::CredentialStore;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::{Digest, Sha256, Sha512Trunc256};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How long an issued nonce may be used for by default
+const NONCE_LIFETIME: Duration = Duration::from_secs(300);
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    /// `Authorization` can't be parsed or lacks required parameters (including "qop", since there is no replay protection without it)
+    InvalidHeader,
+    /// `Authorization` is for another realm
+    WrongRealm,
+    /// The nonce wasn't issued by the authenticator. The request should be challenged again
+    UnknownNonce,
+    /// The nonce was issued by the authenticator, but it has expired. The response is right though.
+    /// The request should be challenged again with "stale=true", so that the user isn't asked for a password
+    StaleNonce,
+    /// The nonce count has already been used with the nonce. The request should be challenged again
+    Replay,
+    /// The "uri" parameter isn't the Request-URI, so the credentials were computed for another request
+    WrongUri,
+    /// The user doesn't exist
+    UnknownUser,
+    /// The algorithm isn't offered or isn't allowed for the user
+    UnsupportedAlgorithm,
+    /// The response doesn't match the password
+    WrongResponse,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::InvalidHeader => write!(f, "invalid authorization header"),
+            AuthError::WrongRealm => write!(f, "wrong realm"),
+            AuthError::UnknownNonce => write!(f, "unknown nonce"),
+            AuthError::StaleNonce => write!(f, "stale nonce"),
+            AuthError::Replay => write!(f, "replayed nonce count"),
+            AuthError::WrongUri => write!(f, "uri doesn't match request-uri"),
+            AuthError::UnknownUser => write!(f, "unknown user"),
+            AuthError::UnsupportedAlgorithm => write!(f, "unsupported algorithm"),
+            AuthError::WrongResponse => write!(f, "wrong response"),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+/// Digest algorithms as per https://tools.ietf.org/html/rfc8760#section-2
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DigestAlgorithm {
+    Md5,
+    Sha256,
+    Sha512_256,
+}
+
+impl DigestAlgorithm {
+    /// Parses the value of the "algorithm" parameter
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("MD5") {
+            Some(DigestAlgorithm::Md5)
+        } else if name.eq_ignore_ascii_case("SHA-256") {
+            Some(DigestAlgorithm::Sha256)
+        } else if name.eq_ignore_ascii_case("SHA-512-256") {
+            Some(DigestAlgorithm::Sha512_256)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Md5 => "MD5",
+            DigestAlgorithm::Sha256 => "SHA-256",
+            DigestAlgorithm::Sha512_256 => "SHA-512-256",
+        }
+    }
+
+    /// Returns the hash of `data` in lowercase hex
+    fn hash(self, data: &[u8]) -> String {
+        match self {
+            DigestAlgorithm::Md5 => format!("{:x}", md5::compute(data)),
+            DigestAlgorithm::Sha256 => format!("{:x}", Sha256::digest(data)),
+            DigestAlgorithm::Sha512_256 => format!("{:x}", Sha512Trunc256::digest(data)),
+        }
+    }
+}
+
+/// HTTP Digest authentication as per https://tools.ietf.org/html/rfc3261#section-22, https://tools.ietf.org/html/rfc2617
+/// and https://tools.ietf.org/html/rfc8760
+/// # Examples
+/// ```
+/// use sip_server::{AuthError, Credentials, DigestAuthenticator};
+///
+/// let mut credentials = Credentials::new();
+/// credentials.insert("joe".to_string(), "secret".to_string());
+/// let mut authenticator = DigestAuthenticator::new("example.com".to_string(), Box::new(credentials));
+///
+/// // The most preferred algorithm goes first
+/// let challenges = authenticator.challenge(false);
+/// assert!(challenges[0].contains("algorithm=SHA-512-256"));
+/// let nonce = challenges[0].split("nonce=\"").nth(1).unwrap().split('"').next().unwrap();
+///
+/// let ha1 = format!("{:x}", md5::compute("joe:example.com:secret"));
+/// let ha2 = format!("{:x}", md5::compute("REGISTER:sip:example.com"));
+/// let response = format!("{:x}", md5::compute(format!("{}:{}:00000001:abc:auth:{}", ha1, nonce, ha2)));
+/// let authorization = format!(
+///     r#"Digest username="joe", realm="example.com", nonce="{}", uri="sip:example.com", response="{}", algorithm=MD5, qop=auth, nc=00000001, cnonce="abc""#,
+///     nonce, response
+/// );
+///
+/// assert_eq!(authenticator.authenticate("REGISTER", "sip:example.com", &authorization, b""), Ok("joe".to_string()));
+/// // The same nonce count can't be used twice
+/// assert_eq!(authenticator.authenticate("REGISTER", "sip:example.com", &authorization, b""), Err(AuthError::Replay));
+///
+/// let authorization = authorization.replace(&response, "0123456789abcdef0123456789abcdef");
+/// assert_eq!(authenticator.authenticate("REGISTER", "sip:example.com", &authorization, b""), Err(AuthError::WrongResponse));
+/// ```
+pub struct DigestAuthenticator {
+    realm: String,
+    credentials: Box<dyn CredentialStore>,
+    /// Offered algorithms, the most preferred first
+    algorithms: Vec<DigestAlgorithm>,
+    /// The key nonces are signed with, so that they can be verified without storing them
+    key: [u8; 32],
+    nonce_lifetime: Duration,
+    /// The last nonce count of each used nonce
+    nonce_counts: HashMap<String, u32>,
+}
+
+impl DigestAuthenticator {
+    /// Creates an authenticator that offers SHA-512-256, SHA-256 and MD5 (in this order)
+    pub fn new(realm: String, credentials: Box<dyn CredentialStore>) -> Self {
+        let mut key = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut key);
+        Self {
+            realm,
+            credentials,
+            algorithms: vec![
+                DigestAlgorithm::Sha512_256,
+                DigestAlgorithm::Sha256,
+                DigestAlgorithm::Md5,
+            ],
+            key,
+            nonce_lifetime: NONCE_LIFETIME,
+            nonce_counts: HashMap::new(),
+        }
+    }
+
+    /// Sets how long an issued nonce may be used for (300 seconds by default)
+    pub fn nonce_lifetime(mut self, nonce_lifetime: Duration) -> Self {
+        self.nonce_lifetime = nonce_lifetime;
+        self
+    }
+
+    /// Sets the offered algorithms, the most preferred first
+    pub fn algorithms(mut self, algorithms: Vec<DigestAlgorithm>) -> Self {
+        self.algorithms = algorithms;
+        self
+    }
+
+    pub fn realm(&self) -> &String {
+        &self.realm
+    }
+
+    /// Returns `true` if the value of `Authorization` (or `Proxy-Authorization`) is meant for this authenticator's realm.
+    /// As per 22.3 Proxy-to-User Authentication a proxy consumes only such credentials and forwards others
+    pub fn is_own(&self, authorization: &str) -> bool {
+        parse_params(authorization)
+            .and_then(|params| params.get("realm").map(|realm| realm == &self.realm))
+            .unwrap_or(false)
+    }
+
+    /// Issues a new nonce and returns the values of `WWW-Authenticate` (or `Proxy-Authenticate`): one per offered algorithm.
+    /// As per https://tools.ietf.org/html/rfc8760#section-2.4 they go in the order of preference.
+    /// `stale` should be `true` if the request was rejected because of [`AuthError::StaleNonce`](enum.AuthError.html)
+    pub fn challenge(&self, stale: bool) -> Vec<String> {
+        let nonce = self.issue_nonce();
+        let stale = if stale { ", stale=true" } else { "" };
+        self.algorithms
+            .iter()
+            .map(|algorithm| {
+                format!(
+                    r#"Digest realm="{}", nonce="{}", algorithm={}, qop="auth,auth-int"{}"#,
+                    self.realm,
+                    nonce,
+                    algorithm.name(),
+                    stale
+                )
+            })
+            .collect()
+    }
+
+    /// Verifies the value of `Authorization` (or `Proxy-Authorization`) of a request with a given method, Request-URI and body.
+    /// The body matters only for "qop=auth-int". Credentials without "qop" (RFC 2069) are rejected, since they have no nonce count.
+    /// Returns the authenticated username
+    pub fn authenticate(
+        &mut self,
+        method: &str,
+        request_uri: &str,
+        authorization: &str,
+        body: &[u8],
+    ) -> Result<String, AuthError> {
+        let params = parse_params(authorization).ok_or(AuthError::InvalidHeader)?;
+        let param = |name: &str| params.get(name).ok_or(AuthError::InvalidHeader);
+        let username = param("username")?;
+        let realm = param("realm")?;
+        let nonce = param("nonce")?;
+        let uri = param("uri")?;
+        let response = param("response")?;
+        if realm != &self.realm {
+            return Err(AuthError::WrongRealm);
+        }
+        // RFC 2617 3.2.2.5: credentials of another request can't be reused for this one
+        if !uri_matches(uri, request_uri) {
+            return Err(AuthError::WrongUri);
+        }
+        let issued_at = self.verify_nonce(nonce)?;
+        // "algorithm" defaults to MD5
+        let algorithm = match params.get("algorithm") {
+            Some(algorithm) => {
+                DigestAlgorithm::from_name(algorithm).ok_or(AuthError::UnsupportedAlgorithm)?
+            }
+            None => DigestAlgorithm::Md5,
+        };
+        if !self.algorithms.contains(&algorithm) {
+            return Err(AuthError::UnsupportedAlgorithm);
+        }
+        let password = self
+            .credentials
+            .password(username)
+            .ok_or(AuthError::UnknownUser)?;
+        if let Some(algorithms) = self.credentials.algorithms(username) {
+            if !algorithms.contains(&algorithm) {
+                return Err(AuthError::UnsupportedAlgorithm);
+            }
+        }
+        let hash = |value: String| algorithm.hash(value.as_bytes());
+        let ha1 = hash(format!("{}:{}:{}", username, realm, password));
+        let qop = param("qop")?;
+        // RFC 2617 3.2.2.3 A2: "auth-int" protects the body too
+        let ha2 = match qop.as_str() {
+            "auth" => hash(format!("{}:{}", method, uri)),
+            "auth-int" => hash(format!("{}:{}:{}", method, uri, algorithm.hash(body))),
+            _ => return Err(AuthError::InvalidHeader),
+        };
+        let nc = param("nc")?;
+        let expected = hash(format!(
+            "{}:{}:{}:{}:{}:{}",
+            ha1,
+            nonce,
+            nc,
+            param("cnonce")?,
+            qop,
+            ha2
+        ));
+        let nc = u32::from_str_radix(nc, 16).map_err(|_| AuthError::InvalidHeader)?;
+        if !constant_time_eq(&expected, response) {
+            return Err(AuthError::WrongResponse);
+        }
+        // Only after the response is checked, so that guessing passwords against a stale nonce counts as failures
+        if unix_time().saturating_sub(issued_at) >= self.nonce_lifetime.as_secs() {
+            return Err(AuthError::StaleNonce);
+        }
+        self.check_nonce_count(nonce, nc)?;
+        Ok(username.clone())
+    }
+
+    /// Returns `<issued at>.<random>.<HMAC of the former>` where `<issued at>` is the Unix time in hex
+    fn issue_nonce(&self) -> String {
+        let value = format!("{:x}.{:016x}", unix_time(), rand::rngs::OsRng.next_u64());
+        let signature = hex(&self.mac(&value).finalize().into_bytes());
+        format!("{}.{}", value, signature)
+    }
+
+    /// Checks that `nonce` was issued by [`issue_nonce`](#method.issue_nonce). Returns when it was issued at
+    fn verify_nonce(&self, nonce: &str) -> Result<u64, AuthError> {
+        let dot = nonce.rfind('.').ok_or(AuthError::UnknownNonce)?;
+        let (value, signature) = (&nonce[..dot], &nonce[dot + 1..]);
+        let signature = unhex(signature).ok_or(AuthError::UnknownNonce)?;
+        self.mac(value)
+            .verify(&signature)
+            .map_err(|_| AuthError::UnknownNonce)?;
+        let issued_at = value.split('.').next().ok_or(AuthError::UnknownNonce)?;
+        u64::from_str_radix(issued_at, 16).map_err(|_| AuthError::UnknownNonce)
+    }
+
+    fn mac(&self, value: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC accepts any key");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    /// Accepts `nc` only if it's greater than the last nonce count used with `nonce`, so that a captured request can't be replayed
+    fn check_nonce_count(&mut self, nonce: &str, nc: u32) -> Result<(), AuthError> {
+        let now = unix_time();
+        let lifetime = self.nonce_lifetime.as_secs();
+        // Expired nonces are rejected anyway, so their counts aren't needed
+        self.nonce_counts.retain(|nonce, _| {
+            let issued_at = nonce
+                .split('.')
+                .next()
+                .and_then(|issued_at| u64::from_str_radix(issued_at, 16).ok());
+            matches!(issued_at, Some(issued_at) if now.saturating_sub(issued_at) < lifetime)
+        });
+        match self.nonce_counts.get(nonce) {
+            Some(last) if *last >= nc => Err(AuthError::Replay),
+            _ => {
+                self.nonce_counts.insert(nonce.to_string(), nc);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The client side of digest authentication: answers challenges of a server the proxy sends requests to (for example, a trunk)
+/// # Examples
+/// ```
+/// use sip_server::{Credentials, DigestAuthenticator, DigestCredentials};
+///
+/// let mut credentials = Credentials::new();
+/// credentials.insert("trunk".to_string(), "secret".to_string());
+/// let mut authenticator = DigestAuthenticator::new("carrier.example".to_string(), Box::new(credentials));
+///
+/// let client = DigestCredentials::new("trunk".to_string(), "secret".to_string());
+/// let challenge = &authenticator.challenge(false)[0];
+/// let authorization = client.authorization(challenge, "INVITE", "sip:123@carrier.example", b"").unwrap();
+///
+/// assert_eq!(authenticator.authenticate("INVITE", "sip:123@carrier.example", &authorization, b""), Ok("trunk".to_string()));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct DigestCredentials {
+    username: String,
+    password: String,
+}
+
+impl DigestCredentials {
+    pub fn new(username: String, password: String) -> Self {
+        Self { username, password }
+    }
+
+    pub fn username(&self) -> &String {
+        &self.username
+    }
+
+    /// Returns the value of `Authorization` (or `Proxy-Authorization`) answering `challenge`, the value of `WWW-Authenticate`
+    /// (or `Proxy-Authenticate`), for a request with a given method, Request-URI and body.
+    /// Returns `None` if the challenge can't be parsed or its algorithm isn't supported
+    pub fn authorization(
+        &self,
+        challenge: &str,
+        method: &str,
+        uri: &str,
+        body: &[u8],
+    ) -> Option<String> {
+        let params = parse_params(challenge)?;
+        let realm = params.get("realm")?;
+        let nonce = params.get("nonce")?;
+        let algorithm = match params.get("algorithm") {
+            Some(algorithm) => DigestAlgorithm::from_name(algorithm)?,
+            None => DigestAlgorithm::Md5,
+        };
+        let hash = |value: String| algorithm.hash(value.as_bytes());
+        let ha1 = hash(format!("{}:{}:{}", self.username, realm, self.password));
+        // "auth" is preferred, since "auth-int" doesn't let a proxy change the body
+        let qop = params.get("qop").map(|qop| {
+            let offered: Vec<_> = qop.split(',').map(str::trim).collect();
+            if offered.contains(&"auth") || !offered.contains(&"auth-int") {
+                "auth"
+            } else {
+                "auth-int"
+            }
+        });
+        let ha2 = if qop == Some("auth-int") {
+            hash(format!("{}:{}:{}", method, uri, algorithm.hash(body)))
+        } else {
+            hash(format!("{}:{}", method, uri))
+        };
+        let mut authorization = format!(
+            r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm={}"#,
+            self.username,
+            realm,
+            nonce,
+            uri,
+            algorithm.name()
+        );
+        let response = match qop {
+            Some(qop) => {
+                let cnonce = format!("{:016x}", rand::rngs::OsRng.next_u64());
+                authorization += &format!(r#", qop={}, nc=00000001, cnonce="{}""#, qop, cnonce);
+                hash(format!(
+                    "{}:{}:00000001:{}:{}:{}",
+                    ha1, nonce, cnonce, qop, ha2
+                ))
+            }
+            None => hash(format!("{}:{}:{}", ha1, nonce, ha2)),
+        };
+        authorization += &format!(r#", response="{}""#, response);
+        if let Some(opaque) = params.get("opaque") {
+            authorization += &format!(r#", opaque="{}""#, opaque);
+        }
+        Some(authorization)
+    }
+}
+
+/// Compares hex digests without leaking through timing how many leading characters match
+fn constant_time_eq(expected: &str, response: &str) -> bool {
+    if expected.len() != response.len() {
+        return false;
+    }
+    expected
+        .bytes()
+        .zip(response.bytes())
+        .fold(0, |diff, (a, b)| {
+            diff | (a.to_ascii_lowercase() ^ b.to_ascii_lowercase())
+        })
+        == 0
+}
+
+/// Compares the "uri" parameter with the Request-URI. Their user, host (case-insensitively) and port (5060 by default) must match.
+/// URI parameters and headers are ignored, since proxies and UAs format them differently
+fn uri_matches(uri: &str, request_uri: &str) -> bool {
+    fn parts(uri: &str) -> Option<(String, String, String, u16)> {
+        let uri = uri.trim().trim_start_matches('<').trim_end_matches('>');
+        let colon = uri.find(':')?;
+        let (schema, rest) = (uri[..colon].to_ascii_lowercase(), &uri[colon + 1..]);
+        let rest = rest.split(&[';', '?'][..]).next()?;
+        let (user, host_port) = match rest.rfind('@') {
+            Some(at) => (&rest[..at], &rest[at + 1..]),
+            None => ("", rest),
+        };
+        // An IPv6 reference has colons inside brackets
+        let (host, port) = match host_port.rfind(':') {
+            Some(colon) if !host_port[colon..].contains(']') => {
+                (&host_port[..colon], host_port[colon + 1..].parse().ok()?)
+            }
+            _ => (host_port, 5060),
+        };
+        Some((schema, user.to_string(), host.to_ascii_lowercase(), port))
+    }
+    match (parts(uri), parts(request_uri)) {
+        (Some(uri), Some(request_uri)) => uri == request_uri,
+        _ => false,
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(value: &str) -> Option<Vec<u8>> {
+    value
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((hex_digit(*high)? << 4) | hex_digit(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Parses `Digest name=value, name="value", ...` into names (lowercase) and values (unquoted)
+fn parse_params(value: &str) -> Option<HashMap<String, String>> {
+    let value = value.trim();
+    // A byte index could fall inside a multi-byte character of a malformed header
+    let value = if matches!(value.get(..6), Some(scheme) if scheme.eq_ignore_ascii_case("digest")) {
+        &value[6..]
+    } else {
+        value
+    };
+    let mut params = HashMap::new();
+    let mut rest = value.trim_start();
+    while !rest.is_empty() {
+        let eq = rest.find('=')?;
+        let name = rest[..eq].trim().to_ascii_lowercase();
+        rest = rest[eq + 1..].trim_start();
+        let param_value = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"')?;
+            rest = &quoted[end + 1..];
+            quoted[..end].to_string()
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let param_value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+            param_value
+        };
+        params.insert(name, param_value);
+        rest = rest.trim_start().trim_start_matches(',').trim_start();
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Credentials;
+
+    fn authenticator() -> DigestAuthenticator {
+        let mut credentials = Credentials::new();
+        credentials.insert("joe".to_string(), "secret".to_string());
+        DigestAuthenticator::new("example.com".to_string(), Box::new(credentials))
+    }
+
+    fn authorization(authenticator: &DigestAuthenticator, uri: &str) -> String {
+        let challenge = &authenticator.challenge(false)[0];
+        DigestCredentials::new("joe".to_string(), "secret".to_string())
+            .authorization(challenge, "INVITE", uri, b"")
+            .unwrap()
+    }
+
+    /// Returns MD5 credentials of joe for `nonce` with a given nonce count
+    fn md5_authorization(nonce: &str, nc: u32) -> String {
+        let ha1 = format!("{:x}", md5::compute("joe:example.com:secret"));
+        let ha2 = format!("{:x}", md5::compute("INVITE:sip:bob@example.com"));
+        let response = md5::compute(format!("{}:{}:{:08x}:abc:auth:{}", ha1, nonce, nc, ha2));
+        format!(
+            r#"Digest username="joe", realm="example.com", nonce="{}", uri="sip:bob@example.com", response="{:x}", algorithm=MD5, qop=auth, nc={:08x}, cnonce="abc""#,
+            nonce, response, nc
+        )
+    }
+
+    fn issued_nonce(authenticator: &DigestAuthenticator) -> String {
+        let challenge = &authenticator.challenge(false)[0];
+        challenge
+            .split("nonce=\"")
+            .nth(1)
+            .unwrap()
+            .split('"')
+            .next()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn uri_must_match_request_uri() {
+        let mut authenticator = authenticator();
+        let authorization = authorization(&authenticator, "sip:bob@example.com");
+        assert_eq!(
+            authenticator.authenticate("INVITE", "sip:eve@example.com", &authorization, b""),
+            Err(AuthError::WrongUri)
+        );
+        assert_eq!(
+            authenticator.authenticate(
+                "INVITE",
+                "sip:bob@EXAMPLE.com:5060;transport=udp",
+                &authorization,
+                b""
+            ),
+            Ok("joe".to_string())
+        );
+    }
+
+    #[test]
+    fn uris_are_compared_by_user_host_and_port() {
+        assert!(uri_matches(
+            "sip:bob@example.com",
+            "sip:bob@example.com:5060"
+        ));
+        assert!(uri_matches("sip:[::1]:5070", "sip:[::1]:5070;lr"));
+        assert!(!uri_matches(
+            "sip:bob@example.com",
+            "sip:bob@example.com:5070"
+        ));
+        assert!(!uri_matches("sip:bob@example.com", "sip:Bob@example.com"));
+        assert!(!uri_matches("sip:bob@example.com", "sips:bob@example.com"));
+        assert!(!uri_matches("example.com", "sip:example.com"));
+    }
+
+    #[test]
+    fn credentials_of_other_realms_are_not_own() {
+        let authenticator = authenticator();
+        assert!(authenticator.is_own(&authorization(&authenticator, "sip:bob@example.com")));
+        assert!(!authenticator.is_own(
+            r#"Digest username="joe", realm="carrier.example", nonce="1", uri="sip:bob@example.com", response="0""#
+        ));
+        assert!(!authenticator.is_own("Basic am9lOnNlY3JldA=="));
+    }
+
+    #[test]
+    fn credentials_without_qop_are_rejected() {
+        let mut authenticator = authenticator();
+        let challenge = authenticator.challenge(false)[0].replace(r#", qop="auth,auth-int""#, "");
+        let authorization = DigestCredentials::new("joe".to_string(), "secret".to_string())
+            .authorization(&challenge, "INVITE", "sip:bob@example.com", b"")
+            .unwrap();
+        assert!(!authorization.contains("qop"));
+        assert_eq!(
+            authenticator.authenticate("INVITE", "sip:bob@example.com", &authorization, b""),
+            Err(AuthError::InvalidHeader)
+        );
+    }
+
+    #[test]
+    fn nonce_count_can_not_be_replayed() {
+        let mut authenticator = authenticator();
+        let authorization = authorization(&authenticator, "sip:bob@example.com");
+        assert_eq!(
+            authenticator.authenticate("INVITE", "sip:bob@example.com", &authorization, b""),
+            Ok("joe".to_string())
+        );
+        assert_eq!(
+            authenticator.authenticate("INVITE", "sip:bob@example.com", &authorization, b""),
+            Err(AuthError::Replay)
+        );
+        let next = authorization.replace("nc=00000001", "nc=00000002");
+        assert_eq!(
+            authenticator.authenticate("INVITE", "sip:bob@example.com", &next, b""),
+            Err(AuthError::WrongResponse)
+        );
+    }
+
+    #[test]
+    fn nonce_counts_must_grow() {
+        let mut authenticator = authenticator();
+        let nonce = issued_nonce(&authenticator);
+        let authenticate = |authenticator: &mut DigestAuthenticator, nc| {
+            authenticator.authenticate(
+                "INVITE",
+                "sip:bob@example.com",
+                &md5_authorization(&nonce, nc),
+                b"",
+            )
+        };
+        assert_eq!(authenticate(&mut authenticator, 3), Ok("joe".to_string()));
+        // A request captured before the last one can't be replayed
+        assert_eq!(authenticate(&mut authenticator, 2), Err(AuthError::Replay));
+        assert_eq!(authenticate(&mut authenticator, 3), Err(AuthError::Replay));
+        assert_eq!(authenticate(&mut authenticator, 5), Ok("joe".to_string()));
+        // Counts of another nonce are independent
+        let other = issued_nonce(&authenticator);
+        assert_eq!(
+            authenticator.authenticate(
+                "INVITE",
+                "sip:bob@example.com",
+                &md5_authorization(&other, 1),
+                b""
+            ),
+            Ok("joe".to_string())
+        );
+    }
+
+    #[test]
+    fn nonces_not_issued_by_authenticator_are_unknown() {
+        let other_server = authenticator();
+        let mut authenticator = authenticator();
+        let nonce = issued_nonce(&authenticator);
+        // A nonce with its time moved forward to outlive its lifetime
+        let (issued_at, rest) = nonce.split_at(nonce.find('.').unwrap());
+        let issued_at = u64::from_str_radix(issued_at, 16).unwrap();
+        let forged = format!("{:x}{}", issued_at + 3600, rest);
+        assert_eq!(
+            authenticator.authenticate(
+                "INVITE",
+                "sip:bob@example.com",
+                &md5_authorization(&forged, 1),
+                b""
+            ),
+            Err(AuthError::UnknownNonce)
+        );
+        // Nonces of another server (or of this one before a restart) are signed with another key
+        let other = issued_nonce(&other_server);
+        assert_eq!(
+            authenticator.authenticate(
+                "INVITE",
+                "sip:bob@example.com",
+                &md5_authorization(&other, 1),
+                b""
+            ),
+            Err(AuthError::UnknownNonce)
+        );
+    }
+
+    #[test]
+    fn stale_nonce_is_reported_only_for_right_response() {
+        let mut authenticator = authenticator().nonce_lifetime(Duration::from_secs(0));
+        let authorization = authorization(&authenticator, "sip:bob@example.com");
+        let wrong = DigestCredentials::new("joe".to_string(), "guess".to_string())
+            .authorization(
+                &authenticator.challenge(false)[0],
+                "INVITE",
+                "sip:bob@example.com",
+                b"",
+            )
+            .unwrap();
+        assert_eq!(
+            authenticator.authenticate("INVITE", "sip:bob@example.com", &wrong, b""),
+            Err(AuthError::WrongResponse)
+        );
+        assert_eq!(
+            authenticator.authenticate("INVITE", "sip:bob@example.com", &authorization, b""),
+            Err(AuthError::StaleNonce)
+        );
+    }
+
+    #[test]
+    fn non_ascii_credentials_are_not_own() {
+        let authenticator = authenticator();
+        assert!(!authenticator.is_own("Dige\u{e9}t realm=\"example.com\""));
+        assert!(!authenticator.is_own("\u{1f600}\u{1f600}"));
+        assert!(authenticator.is_own("Digest realm=\"example.com\", username=\"j\u{f6}e\""));
+    }
+
+    #[test]
+    fn responses_are_compared_case_insensitively() {
+        assert!(constant_time_eq("0a1b", "0A1B"));
+        assert!(!constant_time_eq("0a1b", "0a1c"));
+        assert!(!constant_time_eq("0a1b", "0a1"));
+    }
+}
@@ -0,0 +1,300 @@
+use super::{Flow, Route};
+use libsip::{Header, SipMessage, ViaHeader};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// How long a call stays bound to its back-end after its last request
+const STICKY_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How a back-end is selected for a new call
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DispatchAlgorithm {
+    /// Back-ends take turns
+    RoundRobin,
+    /// Back-ends take turns in proportion to their weights (smooth weighted round-robin)
+    Weighted,
+    /// The back-end is chosen by the hash of Call-ID
+    CallIdHash,
+}
+
+#[derive(Debug)]
+struct Backend {
+    flow: Flow,
+    weight: u32,
+    /// The running weight of the smooth weighted round-robin
+    current_weight: i64,
+    up: bool,
+    /// Failed probes in a row
+    failures: u32,
+}
+
+/// Spreads calls over a pool of back-end servers. Requests of a call go to the same back-end (by Call-ID),
+/// and new calls go only to back-ends that are up
+/// # Examples
+/// ```
+/// use libsip::Transport;
+/// use sip_server::{DispatchAlgorithm, Dispatcher, Flow};
+///
+/// let first = Flow::new(Transport::Udp, "10.0.0.1:5060".parse().unwrap());
+/// let second = Flow::new(Transport::Udp, "10.0.0.2:5060".parse().unwrap());
+/// let mut dispatcher = Dispatcher::new(DispatchAlgorithm::RoundRobin);
+/// dispatcher.add(first, 1);
+/// dispatcher.add(second, 1);
+///
+/// assert_eq!(dispatcher.dispatch("call-1"), Some(first));
+/// assert_eq!(dispatcher.dispatch("call-2"), Some(second));
+/// // Requests of a call stick to its back-end
+/// assert_eq!(dispatcher.dispatch("call-1"), Some(first));
+///
+/// assert!(dispatcher.probe_failed(second.addr()));
+/// assert_eq!(dispatcher.dispatch("call-3"), Some(first));
+/// // Even a back-end that is down keeps its calls
+/// assert_eq!(dispatcher.dispatch("call-2"), Some(second));
+///
+/// assert!(dispatcher.probe_succeeded(second.addr()));
+/// assert_eq!(dispatcher.dispatch("call-4"), Some(second));
+///
+/// // A back-end the server can't connect to is down until a probe succeeds
+/// assert!(dispatcher.set_down(second.addr()));
+/// assert_eq!(dispatcher.dispatch("call-5"), Some(first));
+/// ```
+#[derive(Debug)]
+pub struct Dispatcher {
+    algorithm: DispatchAlgorithm,
+    backends: Vec<Backend>,
+    /// The back-end the round-robin continues from
+    next: usize,
+    /// How many failed probes in a row mark a back-end down
+    max_failures: u32,
+    /// Back-ends calls are bound to by Call-ID with the time of the last request
+    sticky: HashMap<String, (Flow, Instant)>,
+}
+
+impl Dispatcher {
+    /// Creates a dispatcher that marks a back-end down after a single failed probe
+    pub fn new(algorithm: DispatchAlgorithm) -> Self {
+        Self {
+            algorithm,
+            backends: Vec::new(),
+            next: 0,
+            max_failures: 1,
+            sticky: HashMap::new(),
+        }
+    }
+
+    /// Sets how many failed probes in a row mark a back-end down
+    pub fn max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = max_failures.max(1);
+        self
+    }
+
+    /// Adds a back-end. It's considered up until a probe fails. `weight` matters only for [`DispatchAlgorithm::Weighted`](enum.DispatchAlgorithm.html)
+    pub fn add(&mut self, flow: Flow, weight: u32) {
+        self.backends.push(Backend {
+            flow,
+            weight,
+            current_weight: 0,
+            up: true,
+            failures: 0,
+        });
+    }
+
+    pub fn flows(&self) -> Vec<Flow> {
+        self.backends.iter().map(|b| b.flow).collect()
+    }
+
+    pub fn is_backend(&self, addr: SocketAddr) -> bool {
+        self.backends.iter().any(|b| b.flow.addr() == addr)
+    }
+
+    /// Returns the back-end the call `call_id` is bound to or, for a new call, selects a back-end that is up and binds the call to it.
+    /// Returns `None` if every back-end is down
+    pub fn dispatch(&mut self, call_id: &str) -> Option<Flow> {
+        let now = Instant::now();
+        self.sticky
+            .retain(|_, (_, used_at)| now.duration_since(*used_at) < STICKY_LIFETIME);
+        if let Some((flow, used_at)) = self.sticky.get_mut(call_id) {
+            *used_at = now;
+            return Some(*flow);
+        }
+        let flow = self.select(call_id)?;
+        self.sticky.insert(call_id.to_string(), (flow, now));
+        Some(flow)
+    }
+
+    /// Forgets the back-end of the call `call_id` (for example, the call has ended)
+    pub fn unbind(&mut self, call_id: &str) {
+        self.sticky.remove(call_id);
+    }
+
+    /// Records an answered probe of the back-end at `addr`. Returns `true` if the back-end was down
+    pub fn probe_succeeded(&mut self, addr: SocketAddr) -> bool {
+        match self.backends.iter_mut().find(|b| b.flow.addr() == addr) {
+            Some(backend) => {
+                backend.failures = 0;
+                let was_down = !backend.up;
+                backend.up = true;
+                was_down
+            }
+            None => false,
+        }
+    }
+
+    /// Records a failed probe (an error response or no response in time) of the back-end at `addr`.
+    /// Returns `true` if the back-end has just been marked down
+    pub fn probe_failed(&mut self, addr: SocketAddr) -> bool {
+        let max_failures = self.max_failures;
+        match self.backends.iter_mut().find(|b| b.flow.addr() == addr) {
+            Some(backend) => {
+                backend.failures += 1;
+                if backend.up && backend.failures >= max_failures {
+                    backend.up = false;
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false,
+        }
+    }
+
+    /// Marks the back-end at `addr` down at once, since the server can't connect to it or the connection has been closed.
+    /// Returns `true` if the back-end was up
+    pub fn set_down(&mut self, addr: SocketAddr) -> bool {
+        let max_failures = self.max_failures;
+        match self.backends.iter_mut().find(|b| b.flow.addr() == addr) {
+            Some(backend) => {
+                backend.failures = backend.failures.max(max_failures);
+                let was_up = backend.up;
+                backend.up = false;
+                was_up
+            }
+            None => false,
+        }
+    }
+
+    /// Prepares a REGISTER of a client for the back-end it's dispatched to. Registrations pass the dispatcher statelessly:
+    /// `via` is pushed, so that the response comes back through the dispatcher, and `path` is added as per RFC 3327 5.2,
+    /// so that requests to the registered contact do too. Call-ID, the tags and Contact are left for the back-end to bind the client
+    pub fn forward_register(msg: &mut SipMessage, via: ViaHeader, path: &Route) {
+        msg.headers_mut()
+            .0
+            .insert(0, Header::Other("Path".to_string(), path.to_string()));
+        let via_index = msg
+            .headers()
+            .0
+            .iter()
+            .position(|h| matches!(h, Header::Via(_)))
+            .unwrap_or(0);
+        msg.headers_mut().0.insert(via_index, Header::Via(via));
+    }
+
+    fn select(&mut self, call_id: &str) -> Option<Flow> {
+        let up: Vec<usize> = (0..self.backends.len())
+            .filter(|i| self.backends[*i].up)
+            .collect();
+        if up.is_empty() {
+            return None;
+        }
+        let index = match self.algorithm {
+            DispatchAlgorithm::RoundRobin => {
+                let index = up
+                    .iter()
+                    .copied()
+                    .find(|i| *i >= self.next)
+                    .unwrap_or(up[0]);
+                self.next = index + 1;
+                index
+            }
+            DispatchAlgorithm::Weighted => {
+                let total: i64 = up.iter().map(|i| self.backends[*i].weight as i64).sum();
+                for i in up.iter() {
+                    let backend = &mut self.backends[*i];
+                    backend.current_weight += backend.weight as i64;
+                }
+                let index = up
+                    .iter()
+                    .copied()
+                    .max_by_key(|i| (self.backends[*i].current_weight, -(*i as i64)))
+                    .unwrap();
+                self.backends[index].current_weight -= total;
+                index
+            }
+            DispatchAlgorithm::CallIdHash => {
+                let mut hasher = DefaultHasher::new();
+                call_id.hash(&mut hasher);
+                up[(hasher.finish() % up.len() as u64) as usize]
+            }
+        };
+        Some(self.backends[index].flow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsip::{
+        Domain, Method, NamedHeader, RequestGenerator, Transport, Uri, UriParam, UriSchema,
+    };
+
+    fn uri(host: &str) -> Uri {
+        Uri::new(UriSchema::Sip, Domain::Domain(host.to_string(), None))
+    }
+
+    #[test]
+    fn register_passes_statelessly() {
+        let contact = NamedHeader::new(uri("192.0.2.10:5062"));
+        let client_via = ViaHeader::new(
+            Uri::new_schemaless(Domain::Domain("192.0.2.10".to_string(), Some(5062)))
+                .parameter(UriParam::Branch("z9hG4bKclient".to_string())),
+            Transport::Udp,
+        );
+        let mut msg = RequestGenerator::new()
+            .method(Method::Register)
+            .uri(uri("example.com"))
+            .header(Header::Via(client_via.clone()))
+            .header(Header::From(NamedHeader::new(uri("example.com"))))
+            .header(Header::To(NamedHeader::new(uri("example.com"))))
+            .header(Header::CallId("reg-1".to_string()))
+            .header(Header::CSeq(1, Method::Register))
+            .header(Header::Contact(contact.clone()))
+            .build()
+            .unwrap();
+        let original = msg.clone();
+        let via = ViaHeader::new(
+            Uri::new_schemaless(Domain::Domain("dispatcher.example.com".to_string(), None))
+                .parameter(UriParam::Branch("z9hG4bKdispatcher".to_string())),
+            Transport::Udp,
+        );
+        let path = Route::new(
+            uri("dispatcher.example.com").parameter(UriParam::Other("lr".to_string(), None)),
+        );
+        Dispatcher::forward_register(&mut msg, via.clone(), &path);
+
+        assert_eq!(msg.contact_header(), Some(&contact));
+        assert_eq!(msg.call_id(), original.call_id());
+        let vias: Vec<&ViaHeader> = msg
+            .headers()
+            .0
+            .iter()
+            .filter_map(|h| match h {
+                Header::Via(via) => Some(via),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vias, vec![&via, &client_via]);
+        assert!(msg
+            .headers()
+            .0
+            .contains(&Header::Other("Path".to_string(), path.to_string())));
+        // Nothing but Via and Path is added
+        assert_eq!(msg.headers().0.len(), original.headers().0.len() + 2);
+        for h in original.headers().0.iter() {
+            assert!(msg.headers().0.contains(h));
+        }
+    }
+}
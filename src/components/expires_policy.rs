@@ -0,0 +1,61 @@
+/// Decides how long a registration is granted for as per https://tools.ietf.org/html/rfc3261#section-10.3 (step 7)
+/// # Examples
+/// ```
+/// use sip_server::ExpiresPolicy;
+///
+/// let policy = ExpiresPolicy::new(3600, 60, 7200);
+///
+/// // The default is used if neither the contact nor the request specifies expiration
+/// assert_eq!(policy.grant(None), Ok(3600));
+///
+/// assert_eq!(policy.grant(Some(0)), Ok(0));
+///
+/// assert_eq!(policy.grant(Some(600)), Ok(600));
+///
+/// assert_eq!(policy.grant(Some(86400)), Ok(7200));
+///
+/// // Too brief intervals are rejected with the minimum
+/// assert_eq!(policy.grant(Some(30)), Err(60));
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct ExpiresPolicy {
+    default: u32,
+    min: u32,
+    max: u32,
+}
+
+impl Default for ExpiresPolicy {
+    fn default() -> Self {
+        Self::new(3600, 60, 7200)
+    }
+}
+
+impl ExpiresPolicy {
+    /// # Parameters
+    /// * `default` - The interval granted if a REGISTER request doesn't specify it
+    /// * `min` - The shortest interval that may be requested
+    /// * `max` - The longest interval that is granted. Longer intervals are shortened to it
+    pub fn new(default: u32, min: u32, max: u32) -> Self {
+        let max = max.max(min);
+        Self {
+            default: default.max(min).min(max),
+            min,
+            max,
+        }
+    }
+
+    pub fn min(&self) -> u32 {
+        self.min
+    }
+
+    /// Returns the interval that is granted for `requested` (`0` means removal of the binding)
+    /// or `Err(min)` if `requested` is too brief and the request should be rejected with 423 (Interval Too Brief)
+    pub fn grant(&self, requested: Option<u32>) -> Result<u32, u32> {
+        match requested {
+            None => Ok(self.default),
+            Some(0) => Ok(0),
+            Some(requested) if requested < self.min => Err(self.min),
+            Some(requested) => Ok(requested.min(self.max)),
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use super::{
+    Binding, ContactInfo, ContactParams, Flow, RegistrationError, RegistrationStore, Registrations,
+};
+use crate::sip_parse;
+use libsip::Transport;
+use log::{error, info, warn};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// [`RegistrationStore`](trait.RegistrationStore.html) that keeps registrations in memory and
+/// rewrites a file every time they change. The file is read when the store is opened.
+///
+/// Each line of the file is a binding:
+/// `user<TAB>call_id<TAB>cseq<TAB>expires_at<TAB>transport<TAB>addr<TAB>uri<TAB>params<TAB>temp_gruus<TAB>path`,
+/// where `expires_at` is the number of seconds since the Unix epoch, `params` is `name=value` pairs separated by `;`,
+/// `temp_gruus` is user parts of temporary GRUUs separated by `,` and `path` is the Path vector separated by `,`.
+/// `%`, `TAB`, `CR`, `LF`, `;`, `,` and `=` inside the fields are written as `%XX`, so a quoted parameter value
+/// or a Path URI can't break the line up.
+///
+/// The file is written by a background thread, so that a slow disk doesn't hold up the callers
+#[derive(Debug)]
+pub struct FileRegistrationStore {
+    registrations: Registrations,
+    /// Sends the new content of the file to the writer thread
+    writer: Mutex<mpsc::Sender<String>>,
+}
+
+impl FileRegistrationStore {
+    /// Opens the store reloading bindings from `path`. Expired bindings are dropped.
+    /// The file is created on the first change if it doesn't exist
+    pub fn open<P: Into<PathBuf>>(path: P) -> crate::Result<Self> {
+        let path = path.into();
+        let mut registrations = Registrations::new();
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                let now = SystemTime::now();
+                for line in content.lines().filter(|l| !l.is_empty()) {
+                    match Self::parse_line(line, now) {
+                        Some(Some((user, binding))) => registrations.restore(user, binding),
+                        Some(None) => {}
+                        None => warn!("invalid registration is skipped: {}", line),
+                    }
+                }
+                info!("registrations are loaded from {}", path.display());
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        // Expired bindings are dropped from the file too
+        Self::write(&path, &Self::content(&registrations));
+        let (sender, receiver) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            while let Ok(mut content) = receiver.recv() {
+                // Only the latest content matters if changes came faster than the disk
+                while let Ok(newer) = receiver.try_recv() {
+                    content = newer;
+                }
+                Self::write(&path, &content);
+            }
+        });
+        Ok(Self {
+            registrations,
+            writer: Mutex::new(sender),
+        })
+    }
+
+    fn save(&self) {
+        let content = Self::content(&self.registrations);
+        if self.writer.lock().unwrap().send(content).is_err() {
+            error!("failed to save registrations: the writer has stopped");
+        }
+    }
+
+    fn content(registrations: &Registrations) -> String {
+        let now = SystemTime::now();
+        registrations
+            .all_bindings()
+            .map(|(user, binding)| Self::format_line(user, binding, now))
+            .collect()
+    }
+
+    fn write(path: &Path, content: &str) {
+        let tmp_path = path.with_extension("tmp");
+        let result = fs::write(&tmp_path, content).and_then(|_| fs::rename(&tmp_path, path));
+        if let Err(e) = result {
+            error!("failed to save registrations to {}: {}", path.display(), e);
+        }
+    }
+
+    fn format_line(user: &str, binding: &Binding, now: SystemTime) -> String {
+        let expires_at = (now + Duration::from_secs(binding.expires().into()))
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let transport = match binding.flow().transport() {
+            Transport::Tcp => "tcp",
+            _ => "udp",
+        };
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            escape(user),
+            escape(binding.call_id()),
+            binding.cseq(),
+            expires_at,
+            transport,
+            binding.flow().addr(),
+            escape(&binding.uri().to_string()),
+            format_params(binding.params()),
+            format_list(binding.temp_gruus()),
+            format_list(binding.path())
+        )
+    }
+
+    /// Returns `None` if `line` is invalid and `Some(None)` if the binding is expired
+    fn parse_line(line: &str, now: SystemTime) -> Option<Option<(String, Binding)>> {
+        let mut fields: Vec<&str> = line.split('\t').collect();
+        // Files written before GRUU and Path support don't have temporary GRUUs and Path
+        if fields.len() == 8 || fields.len() == 9 {
+            fields.resize(10, "");
+        }
+        if let [user, call_id, cseq, expires_at, transport, addr, uri, params, temp_gruus, path] =
+            fields[..]
+        {
+            let expires_at = UNIX_EPOCH + Duration::from_secs(expires_at.parse().ok()?);
+            let expires = match expires_at.duration_since(now) {
+                Ok(expires) if expires.as_secs() > 0 => expires.as_secs() as u32,
+                _ => return Some(None),
+            };
+            let transport = match transport {
+                "tcp" => Transport::Tcp,
+                "udp" => Transport::Udp,
+                _ => return None,
+            };
+            let flow = Flow::new(transport, addr.parse().ok()?);
+            let uri = sip_parse::parse_uri(&unescape(uri))?;
+            let contact =
+                ContactInfo::new(uri, parse_params(params), flow, expires).path(parse_list(path));
+            let binding = Binding::restore(
+                contact,
+                unescape(call_id),
+                cseq.parse().ok()?,
+                parse_list(temp_gruus),
+            );
+            Some(Some((unescape(user), binding)))
+        } else {
+            None
+        }
+    }
+}
+
+impl RegistrationStore for FileRegistrationStore {
+    fn update(
+        &mut self,
+        user: &str,
+        call_id: &str,
+        cseq: u32,
+        contacts: Vec<ContactInfo>,
+    ) -> Result<(), RegistrationError> {
+        self.registrations.update(user, call_id, cseq, contacts)?;
+        self.save();
+        Ok(())
+    }
+
+    fn remove_all(
+        &mut self,
+        user: &str,
+        call_id: &str,
+        cseq: u32,
+    ) -> Result<(), RegistrationError> {
+        self.registrations.remove_all(user, call_id, cseq)?;
+        self.save();
+        Ok(())
+    }
+
+    fn bindings(&self, user: &str) -> Vec<&Binding> {
+        self.registrations.bindings(user)
+    }
+
+    fn remove_expired(&mut self) -> bool {
+        let removed = self.registrations.remove_expired();
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    fn remove_expired_bindings(&mut self, user: &str) -> Vec<Binding> {
+        let expired = self.registrations.remove_expired_bindings(user);
+        if !expired.is_empty() {
+            self.save();
+        }
+        expired
+    }
+
+    fn issue_temp_gruu(&mut self, user: &str, instance_id: &str) -> Option<String> {
+        let temp_gruu = self.registrations.issue_temp_gruu(user, instance_id)?;
+        self.save();
+        Some(temp_gruu)
+    }
+
+    fn binding_by_instance_id(&self, user: &str, instance_id: &str) -> Option<&Binding> {
+        self.registrations.binding_by_instance_id(user, instance_id)
+    }
+
+    fn binding_by_temp_gruu(&self, temp_gruu: &str) -> Option<&Binding> {
+        self.registrations.binding_by_temp_gruu(temp_gruu)
+    }
+}
+
+/// Characters that separate the fields and the list items of a line, and `%` itself
+const ESCAPED: [(char, &str); 7] = [
+    ('%', "%25"),
+    ('\t', "%09"),
+    ('\n', "%0A"),
+    ('\r', "%0D"),
+    (';', "%3B"),
+    (',', "%2C"),
+    ('=', "%3D"),
+];
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match ESCAPED.iter().find(|(e, _)| *e == c) {
+            Some((_, code)) => escaped.push_str(code),
+            None => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Only the codes written by [`escape`] are decoded, so other `%` sequences of files
+/// written before escaping are read as is
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(pos) = rest.find('%') {
+        unescaped.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        match ESCAPED
+            .iter()
+            .find(|(_, code)| matches!(rest.get(..3), Some(r) if r.eq_ignore_ascii_case(code)))
+        {
+            Some((c, _)) => {
+                unescaped.push(*c);
+                rest = &rest[3..];
+            }
+            None => {
+                unescaped.push('%');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+fn format_params(params: &ContactParams) -> String {
+    let params: Vec<String> = params
+        .iter()
+        .map(|(name, value)| match value {
+            Some(value) => format!("{}={}", escape(name), escape(value)),
+            None => escape(name),
+        })
+        .collect();
+    params.join(";")
+}
+
+fn parse_params(params: &str) -> ContactParams {
+    params
+        .split(';')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let mut parts = p.splitn(2, '=');
+            let name = unescape(parts.next().unwrap_or_default());
+            (name, parts.next().map(unescape))
+        })
+        .collect()
+}
+
+fn format_list(items: &[String]) -> String {
+    let items: Vec<String> = items.iter().map(|i| escape(i)).collect();
+    items.join(",")
+}
+
+fn parse_list(items: &str) -> Vec<String> {
+    items
+        .split(',')
+        .filter(|i| !i.is_empty())
+        .map(unescape)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separators_inside_fields_survive_a_round_trip() {
+        let params: ContactParams = vec![
+            (
+                "+sip.instance".to_string(),
+                Some("\"<urn:uuid:1;a=b,c>\"".to_string()),
+            ),
+            ("reg-id".to_string(), Some("1".to_string())),
+            ("ob".to_string(), None),
+            ("x".to_string(), Some("50%\ttab".to_string())),
+        ]
+        .into_iter()
+        .collect();
+        let line = format_params(&params);
+        assert!(!line.contains('\t'));
+        assert_eq!(parse_params(&line), params);
+
+        let path = vec![
+            "<sip:proxy1.example.com;lr;x=a,b>".to_string(),
+            "<sip:proxy2.example.com;lr>".to_string(),
+        ];
+        assert_eq!(parse_list(&format_list(&path)), path);
+        assert!(parse_list("").is_empty());
+    }
+
+    #[test]
+    fn unknown_percent_sequences_are_kept() {
+        assert_eq!(unescape("sip:a%40b.com;lr"), "sip:a%40b.com;lr");
+        assert_eq!(unescape("100%"), "100%");
+        assert_eq!(unescape("a%3bb%2C"), "a;b,");
+    }
+}
@@ -0,0 +1,24 @@
+use libsip::Transport;
+use std::net::SocketAddr;
+
+/// The connection a message arrived on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Flow {
+    transport: Transport,
+    addr: SocketAddr,
+}
+
+impl Flow {
+    pub fn new(transport: Transport, addr: SocketAddr) -> Self {
+        Self { transport, addr }
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Returns the address of the connection's remote side
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
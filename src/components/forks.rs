@@ -0,0 +1,449 @@
+use super::Flow;
+use libsip::SipMessage;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How long a completed fork is kept, so that the ACK for its final response can be absorbed (64*T1 of https://tools.ietf.org/html/rfc3261#section-17.1.1.2)
+const COMPLETED_LIFETIME: Duration = Duration::from_secs(32);
+/// How long a fork waits for a final response by default (Timer C of https://tools.ietf.org/html/rfc3261#section-16.6 has to be greater than 3 minutes)
+const TIMER_C: Duration = Duration::from_secs(181);
+
+/// How a forked request is sent to its targets
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Forking {
+    /// To all the targets at once
+    Parallel,
+    /// To one target after another in the given order until one answers (for example, the devices of a user in the order of q-values)
+    Sequential,
+    /// To one server after another in the given order as per https://tools.ietf.org/html/rfc3263#section-4.3:
+    /// the next server is tried only if the current one is unreachable, doesn't respond in time (408) or is unavailable (503)
+    Failover,
+}
+
+/// What the proxy does with a response received on a branch of a forked request
+#[derive(Debug, PartialEq)]
+pub enum ResponseAction {
+    /// The response is relayed to the caller
+    Forward,
+    /// The response isn't relayed (yet)
+    Absorb,
+    /// The branch was the last one to complete. The response chosen among the branches' final responses is relayed instead
+    ForwardBest(SipMessage),
+    /// The branch failed, so the request is sent to the next target (sequential forking or failover). The response isn't relayed
+    TryNext(Flow, SipMessage),
+}
+
+/// A client transaction of a forked request
+#[derive(Debug)]
+struct Branch {
+    branch: String,
+    flow: Flow,
+    /// The request as it was sent on the branch
+    request: SipMessage,
+    final_code: Option<u32>,
+}
+
+/// A request forked to several targets
+#[derive(Debug)]
+struct Fork {
+    /// How many branches the request has been sent on
+    expected: usize,
+    branches: Vec<Branch>,
+    /// The targets that haven't been tried yet with the requests for them (sequential forking or failover)
+    queue: VecDeque<(Flow, SipMessage)>,
+    /// Whether only unreachable, timed out and unavailable targets make the next one be tried
+    failover: bool,
+    /// The best non-2xx final response received so far with its code
+    best: Option<(u32, SipMessage)>,
+    /// Whether a 2xx response has been received
+    answered: bool,
+    /// When a final response was relayed to the caller
+    completed_at: Option<Instant>,
+    /// When the fork started or last received a provisional response, which restarts Timer C
+    updated_at: Instant,
+}
+
+impl Fork {
+    fn is_completed(&self) -> bool {
+        self.completed_at.is_some()
+    }
+}
+
+/// Keeps the state of forked requests, so that the proxy relays the responses as per https://tools.ietf.org/html/rfc3261#section-16.7.
+/// Forks are identified by the branch of the server transaction (the caller's Via)
+#[derive(Debug)]
+pub struct Forks {
+    forks: HashMap<String, Fork>,
+    timer_c: Duration,
+}
+
+impl Default for Forks {
+    fn default() -> Self {
+        Self {
+            forks: HashMap::new(),
+            timer_c: TIMER_C,
+        }
+    }
+}
+
+impl Forks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how long a fork waits for a final response after it has started or received the last provisional response (181 seconds by default)
+    pub fn timer_c(mut self, timer_c: Duration) -> Self {
+        self.timer_c = timer_c;
+        self
+    }
+
+    /// Starts a fork of the request whose server transaction is `key` to `targets` (the flows with the requests sent over them).
+    /// Returns the targets the request must be sent to now
+    pub fn start(
+        &mut self,
+        key: String,
+        targets: Vec<(Flow, SipMessage)>,
+        forking: Forking,
+    ) -> Vec<(Flow, SipMessage)> {
+        let now = Instant::now();
+        // Timer C makes every fork complete eventually
+        self.forks.retain(|_, fork| match fork.completed_at {
+            Some(completed_at) => now.duration_since(completed_at) < COMPLETED_LIFETIME,
+            None => true,
+        });
+        let mut queue: VecDeque<_> = targets.into_iter().collect();
+        let targets: Vec<_> = match forking {
+            Forking::Parallel => queue.drain(..).collect(),
+            Forking::Sequential | Forking::Failover => queue.pop_front().into_iter().collect(),
+        };
+        self.forks.insert(
+            key,
+            Fork {
+                expected: targets.len(),
+                branches: Vec::new(),
+                queue,
+                failover: forking == Forking::Failover,
+                best: None,
+                answered: false,
+                completed_at: None,
+                updated_at: now,
+            },
+        );
+        targets
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.forks.contains_key(key)
+    }
+
+    /// Records the request sent on `branch` over `flow`
+    pub fn add_branch(&mut self, key: &str, branch: String, flow: Flow, request: SipMessage) {
+        if let Some(fork) = self.forks.get_mut(key) {
+            fork.branches.push(Branch {
+                branch,
+                flow,
+                request,
+                final_code: None,
+            });
+        }
+    }
+
+    /// Replaces `branch` with `new_branch` where `request` is sent again (for example, with credentials after a challenge).
+    /// The final response of `branch` isn't taken into account
+    pub fn retry_branch(
+        &mut self,
+        key: &str,
+        branch: &str,
+        new_branch: String,
+        request: SipMessage,
+    ) {
+        if let Some(b) = self
+            .forks
+            .get_mut(key)
+            .and_then(|fork| fork.branches.iter_mut().find(|b| b.branch == branch))
+        {
+            b.branch = new_branch;
+            b.request = request;
+            b.final_code = None;
+        }
+    }
+
+    /// Returns the request sent on `branch`
+    pub fn request(&self, key: &str, branch: &str) -> Option<&SipMessage> {
+        self.forks
+            .get(key)?
+            .branches
+            .iter()
+            .find(|b| b.branch == branch)
+            .map(|b| &b.request)
+    }
+
+    /// Decides what to do with `response` whose status code is `code` received on `branch`
+    pub fn on_response(
+        &mut self,
+        key: &str,
+        branch: &str,
+        code: u32,
+        response: SipMessage,
+    ) -> ResponseAction {
+        let fork = if let Some(fork) = self.forks.get_mut(key) {
+            fork
+        } else {
+            return ResponseAction::Forward;
+        };
+        // 100 (Trying) is hop-by-hop
+        if code < 200 {
+            if code == 100 || fork.is_completed() {
+                return ResponseAction::Absorb;
+            }
+            fork.updated_at = Instant::now();
+            return ResponseAction::Forward;
+        }
+        if let Some(b) = fork.branches.iter_mut().find(|b| b.branch == branch) {
+            // A retransmission or a response to a branch that has timed out has already been taken into account
+            if b.final_code.is_some() {
+                return ResponseAction::Absorb;
+            }
+            b.final_code = Some(code);
+        }
+        // Every 2xx is relayed, even a late one, since it establishes a dialog
+        if code < 300 {
+            fork.answered = true;
+            fork.completed_at.get_or_insert_with(Instant::now);
+            return ResponseAction::Forward;
+        }
+        let better = match &fork.best {
+            Some((best_code, _)) => Self::rank(code) < Self::rank(*best_code),
+            None => true,
+        };
+        if better {
+            fork.best = Some((code, response));
+        }
+        // 6xx means that the callee can't be reached anywhere, so the remaining targets aren't tried.
+        // Another server of a domain would give the same answer unless the server itself has failed
+        if code >= 600 || (fork.failover && code != 408 && code != 503) {
+            fork.queue.clear();
+        }
+        let all_completed = fork.branches.len() >= fork.expected
+            && fork.branches.iter().all(|b| b.final_code.is_some());
+        if fork.answered || fork.is_completed() || !all_completed {
+            return ResponseAction::Absorb;
+        }
+        if let Some((flow, request)) = fork.queue.pop_front() {
+            fork.expected += 1;
+            return ResponseAction::TryNext(flow, request);
+        }
+        fork.completed_at = Some(Instant::now());
+        match fork.best.take() {
+            Some((_, best)) => ResponseAction::ForwardBest(best),
+            None => ResponseAction::Absorb,
+        }
+    }
+
+    /// Returns how long is left until Timer C of the fork `key` fires (zero if it has fired already).
+    /// Returns `None` if the fork has completed
+    pub fn time_left(&self, key: &str) -> Option<Duration> {
+        let fork = self.forks.get(key).filter(|fork| !fork.is_completed())?;
+        Some((fork.updated_at + self.timer_c).saturating_duration_since(Instant::now()))
+    }
+
+    /// Stops trying the remaining targets (for example, the caller has CANCELled the request)
+    pub fn stop(&mut self, key: &str) {
+        if let Some(fork) = self.forks.get_mut(key) {
+            fork.queue.clear();
+        }
+    }
+
+    /// Returns the branches that haven't received a final response with the requests sent on them, so that they can be CANCELled
+    pub fn pending(&self, key: &str) -> Vec<(Flow, &SipMessage)> {
+        match self.forks.get(key) {
+            Some(fork) => fork
+                .branches
+                .iter()
+                .filter(|b| b.final_code.is_none())
+                .map(|b| (b.flow, &b.request))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the flow and the request sent on `branch` if the branch hasn't received a final response, so that it can be CANCELled
+    pub fn pending_branch(&self, key: &str, branch: &str) -> Option<(Flow, &SipMessage)> {
+        self.forks
+            .get(key)?
+            .branches
+            .iter()
+            .find(|b| b.branch == branch && b.final_code.is_none())
+            .map(|b| (b.flow, &b.request))
+    }
+
+    /// The lower, the better as per https://tools.ietf.org/html/rfc3261#section-16.7 (step 6):
+    /// 6xx responses are preferred, then the lowest response class
+    fn rank(code: u32) -> u32 {
+        if code >= 600 {
+            0
+        } else {
+            code / 100
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsip::{Domain, Method, RequestGenerator, ResponseGenerator, Transport, Uri, UriSchema};
+
+    fn flow(port: u16) -> Flow {
+        Flow::new(Transport::Udp, ([192, 0, 2, 1], port).into())
+    }
+
+    fn request() -> SipMessage {
+        RequestGenerator::new()
+            .method(Method::Invite)
+            .uri(Uri::new(
+                UriSchema::Sip,
+                Domain::Ipv4([192, 0, 2, 1].into(), None),
+            ))
+            .build()
+            .unwrap()
+    }
+
+    fn response(code: u32) -> SipMessage {
+        ResponseGenerator::new().code(code).build().unwrap()
+    }
+
+    /// Starts a fork to the targets on `ports` and sends the request on the branches named by the ports
+    fn start(forks: &mut Forks, ports: &[u16], forking: Forking) {
+        let targets = ports.iter().map(|port| (flow(*port), request())).collect();
+        for (flow, request) in forks.start("key".to_string(), targets, forking) {
+            forks.add_branch("key", flow.addr().port().to_string(), flow, request);
+        }
+    }
+
+    #[test]
+    fn timer_c_is_restarted_by_provisional_responses() {
+        let mut forks = Forks::new().timer_c(Duration::from_millis(50));
+        start(&mut forks, &[5060], Forking::Parallel);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(forks.time_left("key"), Some(Duration::from_secs(0)));
+        assert_eq!(
+            forks.on_response("key", "5060", 180, response(180)),
+            ResponseAction::Forward
+        );
+        assert!(forks.time_left("key").unwrap() > Duration::from_millis(10));
+        assert!(matches!(
+            forks.on_response("key", "5060", 408, response(408)),
+            ResponseAction::ForwardBest(_)
+        ));
+        assert_eq!(forks.time_left("key"), None);
+    }
+
+    #[test]
+    fn late_final_response_of_branch_is_absorbed() {
+        let mut forks = Forks::new();
+        start(&mut forks, &[5060, 5070], Forking::Sequential);
+        // The first target has timed out, so the second one rings
+        assert_eq!(
+            forks.on_response("key", "5060", 408, response(408)),
+            ResponseAction::TryNext(flow(5070), request())
+        );
+        forks.add_branch("key", "5070".to_string(), flow(5070), request());
+        // 487 (Request Terminated) for the CANCEL of the first target doesn't make another target ring
+        assert_eq!(
+            forks.on_response("key", "5060", 487, response(487)),
+            ResponseAction::Absorb
+        );
+        assert_eq!(forks.pending("key").len(), 1);
+    }
+
+    #[test]
+    fn failover_tries_next_server_only_if_server_fails() {
+        let mut forks = Forks::new();
+        start(&mut forks, &[5060, 5070, 5080], Forking::Failover);
+        assert_eq!(
+            forks.on_response("key", "5060", 503, response(503)),
+            ResponseAction::TryNext(flow(5070), request())
+        );
+        forks.add_branch("key", "5070".to_string(), flow(5070), request());
+        // The callee is busy, which the third server would say too
+        assert_eq!(
+            forks.on_response("key", "5070", 486, response(486)),
+            ResponseAction::ForwardBest(response(486))
+        );
+    }
+
+    #[test]
+    fn sequential_forking_tries_next_target_after_any_failure() {
+        let mut forks = Forks::new();
+        start(&mut forks, &[5060, 5070], Forking::Sequential);
+        assert_eq!(
+            forks.on_response("key", "5060", 486, response(486)),
+            ResponseAction::TryNext(flow(5070), request())
+        );
+    }
+
+    #[test]
+    fn ring_timeout_makes_next_target_ring() {
+        let mut forks = Forks::new();
+        start(&mut forks, &[5060, 5070], Forking::Sequential);
+        assert_eq!(
+            forks.on_response("key", "5060", 180, response(180)),
+            ResponseAction::Forward
+        );
+        // The ringing branch is CANCELled and fails as if it had timed out
+        assert!(forks.pending_branch("key", "5060").is_some());
+        assert_eq!(
+            forks.on_response("key", "5060", 408, response(408)),
+            ResponseAction::TryNext(flow(5070), request())
+        );
+        assert!(forks.pending_branch("key", "5060").is_none());
+        forks.add_branch("key", "5070".to_string(), flow(5070), request());
+        assert_eq!(
+            forks.on_response("key", "5070", 200, response(200)),
+            ResponseAction::Forward
+        );
+        // A branch that has been answered isn't CANCELled when its ring timeout fires
+        assert!(forks.pending_branch("key", "5070").is_none());
+        assert_eq!(forks.time_left("key"), None);
+    }
+
+    #[test]
+    fn timer_c_completes_fork_with_best_response() {
+        let mut forks = Forks::new().timer_c(Duration::from_millis(50));
+        start(&mut forks, &[5060, 5070, 5080], Forking::Parallel);
+        assert_eq!(
+            forks.on_response("key", "5060", 486, response(486)),
+            ResponseAction::Absorb
+        );
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(forks.time_left("key"), Some(Duration::from_secs(0)));
+        let pending: Vec<_> = forks.pending("key").iter().map(|(flow, _)| *flow).collect();
+        assert_eq!(pending, vec![flow(5070), flow(5080)]);
+        // The pending branches are CANCELled and get 408
+        assert_eq!(
+            forks.on_response("key", "5070", 408, response(408)),
+            ResponseAction::Absorb
+        );
+        assert_eq!(
+            forks.on_response("key", "5080", 408, response(408)),
+            ResponseAction::ForwardBest(response(486))
+        );
+        // 487 (Request Terminated) for the CANCEL comes after that
+        assert_eq!(
+            forks.on_response("key", "5080", 487, response(487)),
+            ResponseAction::Absorb
+        );
+    }
+
+    #[test]
+    fn six_hundred_stops_trying_targets() {
+        let mut forks = Forks::new();
+        start(&mut forks, &[5060, 5070], Forking::Sequential);
+        assert_eq!(
+            forks.on_response("key", "5060", 603, response(603)),
+            ResponseAction::ForwardBest(response(603))
+        );
+    }
+}
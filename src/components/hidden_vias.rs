@@ -0,0 +1,44 @@
+use libsip::{Header, SipMessage};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// How long the Via headers removed from a request are kept after the last response to it
+const HIDDEN_VIAS_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// Keeps the Via headers the B2BUA removes from a request before sending it to the other leg with its own Via,
+/// so that responses find their way back
+#[derive(Debug, Default)]
+pub struct HiddenVias(HashMap<String, (Vec<Header>, Instant)>);
+
+impl HiddenVias {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes the Via headers of `msg` and keeps them for the responses to the request the server sends with its Via of `branch`.
+    /// The server adds its Via afterwards
+    pub fn hide(&mut self, msg: &mut SipMessage, branch: &str) {
+        let now = Instant::now();
+        self.0
+            .retain(|_, (_, used_at)| now.duration_since(*used_at) < HIDDEN_VIAS_LIFETIME);
+        let mut vias = Vec::new();
+        msg.headers_mut().0.retain(|h| match h {
+            Header::Via(_) => {
+                vias.push(h.clone());
+                false
+            }
+            _ => true,
+        });
+        self.0.insert(branch.to_string(), (vias, now));
+    }
+
+    /// Returns the Via headers removed from the request the server has sent with `branch`.
+    /// They take the place of the server's Via in responses
+    pub fn restore(&mut self, branch: &str) -> Option<Vec<Header>> {
+        let (vias, used_at) = self.0.get_mut(branch)?;
+        *used_at = Instant::now();
+        Some(vias.clone())
+    }
+}
@@ -0,0 +1,563 @@
+use super::Recorder;
+use async_std::{
+    net::UdpSocket,
+    sync::{Arc, Mutex},
+    task,
+};
+use futures::future::{self, AbortHandle};
+use log::{debug, error, info};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+/// Big enough for any RTP or RTCP packet sent over Ethernet
+const MAX_PACKET_SIZE: usize = 2048;
+/// How long a stream is relayed without a packet from either leg by default
+const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An RTP packet on its way to the recorder: the leg, the source, the destination and the packet
+type RtpPacket = (usize, SocketAddr, SocketAddr, Vec<u8>);
+
+/// Where the media of a leg comes from
+#[derive(Debug, Default)]
+struct Peer {
+    addr: Option<SocketAddr>,
+    /// Whether `addr` has been observed rather than taken from SDP
+    latched: bool,
+}
+
+impl Peer {
+    /// Sets the address SDP announces. It holds until the first packet arrives
+    fn announce(&mut self, addr: SocketAddr) {
+        self.addr = Some(addr);
+        self.latched = false;
+    }
+
+    /// Latches onto the source of the first packet (comedia), since SDP of an endpoint behind NAT has its private address.
+    /// Returns `false` if the packet comes from elsewhere after that
+    fn accept(&mut self, src: SocketAddr) -> bool {
+        if !self.latched {
+            self.addr = Some(src);
+            self.latched = true;
+        }
+        self.addr == Some(src)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Peers {
+    rtp: [Peer; 2],
+    rtcp: [Peer; 2],
+}
+
+impl Peers {
+    fn get_mut(&mut self, rtcp: bool) -> &mut [Peer; 2] {
+        if rtcp {
+            &mut self.rtcp
+        } else {
+            &mut self.rtp
+        }
+    }
+}
+
+/// A media stream relayed between the legs of a call
+#[derive(Debug)]
+struct Stream {
+    /// The RTP ports of the legs. RTCP goes over the next ports
+    ports: [u16; 2],
+    peers: Arc<std::sync::Mutex<Peers>>,
+    /// Takes RTP of both legs to the recorder if the stream is recorded
+    recorder: Arc<std::sync::Mutex<Option<mpsc::Sender<RtpPacket>>>>,
+    tasks: Vec<AbortHandle>,
+}
+
+impl Stream {
+    /// Starts recording to `path` unless the stream is being recorded.
+    /// The recorder writes on a blocking task, so relaying doesn't wait for the disk
+    fn record(&self, path: PathBuf) {
+        let mut recorder = self.recorder.lock().unwrap();
+        if recorder.is_none() {
+            let (sender, receiver) = mpsc::channel();
+            task::spawn_blocking(move || {
+                MediaRelay::write_recording(Recorder::new(path), receiver)
+            });
+            *recorder = Some(sender);
+        }
+    }
+
+    /// The recorder writes the rest of the recording once it has taken the packets sent to it
+    fn stop_recording(&self) {
+        self.recorder.lock().unwrap().take();
+    }
+
+    /// Announces that the stream comes from `endpoint` on `leg` and returns the port the endpoint of the other leg sends it to
+    fn announce(&self, leg: usize, endpoint: SocketAddr) -> u16 {
+        let mut peers = self.peers.lock().unwrap();
+        peers.rtp[leg].announce(endpoint);
+        peers.rtcp[leg].announce(SocketAddr::new(
+            endpoint.ip(),
+            endpoint.port().saturating_add(1),
+        ));
+        self.ports[1 - leg]
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    next_port: u16,
+    used_ports: HashSet<u16>,
+    /// Streams by calls and the indexes of their media descriptions
+    streams: HashMap<(u32, usize), Stream>,
+    /// Paths of the recordings of recorded calls
+    recorded: HashMap<u32, PathBuf>,
+}
+
+/// Relays RTP and RTCP between the two legs of calls, so that endpoints behind different NATs hear each other.
+/// Each leg of a stream gets a port pair (RTP on an even port, RTCP on the next one) from the range,
+/// and the endpoint of a leg is the source of the first packet received from it, whatever its SDP says.
+/// A stream that hasn't relayed a packet for the inactivity timeout is released, so a call that ends without BYE doesn't keep its ports
+/// # Examples
+/// ```
+/// use async_std::{net::UdpSocket, task};
+/// use sip_server::MediaRelay;
+///
+/// task::block_on(async {
+///     let relay = MediaRelay::new("127.0.0.1".parse().unwrap(), 41000, 41999);
+///     let alice = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+///     let bob = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+///     // Alice (the caller's leg) offers her address, and Bob is told to send to the relay instead
+///     let bob_sends_to = relay.update(1, 0, 0, alice.local_addr().unwrap()).await.unwrap();
+///     let alice_sends_to = relay.update(1, 0, 1, bob.local_addr().unwrap()).await.unwrap();
+///
+///     bob.send_to(b"rtp", ("127.0.0.1", bob_sends_to)).await.unwrap();
+///     let mut buf = [0; 16];
+///     let (len, src) = alice.recv_from(&mut buf).await.unwrap();
+///     assert_eq!(&buf[..len], b"rtp");
+///     assert_eq!(src.port(), alice_sends_to);
+///
+///     relay.release(1).await;
+/// });
+/// ```
+#[derive(Clone, Debug)]
+pub struct MediaRelay {
+    ip: IpAddr,
+    first_port: u16,
+    last_port: u16,
+    inactivity_timeout: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+impl MediaRelay {
+    /// Creates a relay listening on `ip` that takes ports from `first_port` to `last_port`
+    pub fn new(ip: IpAddr, first_port: u16, last_port: u16) -> Self {
+        // RTP goes over even ports, and RTCP needs the next one
+        let first_port = first_port.saturating_add(first_port % 2);
+        let last_port = last_port.min(u16::MAX - 1);
+        Self {
+            ip,
+            first_port,
+            last_port,
+            inactivity_timeout: INACTIVITY_TIMEOUT,
+            state: Arc::new(Mutex::new(State {
+                next_port: first_port,
+                used_ports: HashSet::new(),
+                streams: HashMap::new(),
+                recorded: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Sets how long a stream is relayed without a packet (RTP or RTCP) from either leg
+    pub fn inactivity_timeout(mut self, timeout: Duration) -> Self {
+        self.inactivity_timeout = timeout;
+        self
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Records that the stream `index` (the media description's index) of `call` comes from `endpoint` on `leg` (`0` or `1`) as SDP says,
+    /// and returns the port the endpoint of the other leg sends the stream to. Ports are allocated for the stream when it's first seen
+    pub async fn update(
+        &self,
+        call: u32,
+        index: usize,
+        leg: usize,
+        endpoint: SocketAddr,
+    ) -> io::Result<u16> {
+        let mut state = self.state.lock().await;
+        let key = (call, index);
+        if !state.streams.contains_key(&key) {
+            self.start_stream(&mut state, key).await?;
+        }
+        Ok(state.streams[&key].announce(leg, endpoint))
+    }
+
+    /// Updates every stream of `call` like [`update`](#method.update) does with the endpoints of `leg` an SDP announces
+    /// (one per media description, `None` for a disabled stream) and returns the ports for the endpoint of the other leg.
+    /// If ports can't be allocated for a stream, the streams allocated by this update are released, so the call keeps only the streams it had
+    pub async fn update_all(
+        &self,
+        call: u32,
+        leg: usize,
+        endpoints: &[Option<SocketAddr>],
+    ) -> io::Result<Vec<Option<u16>>> {
+        let mut state = self.state.lock().await;
+        let mut started = Vec::new();
+        let mut ports = Vec::with_capacity(endpoints.len());
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            let endpoint = if let Some(endpoint) = endpoint {
+                *endpoint
+            } else {
+                ports.push(None);
+                continue;
+            };
+            let key = (call, index);
+            if !state.streams.contains_key(&key) {
+                if let Err(e) = self.start_stream(&mut state, key).await {
+                    for key in started {
+                        Self::remove_stream(&mut state, key);
+                    }
+                    return Err(e);
+                }
+                started.push(key);
+            }
+            ports.push(Some(state.streams[&key].announce(leg, endpoint)));
+        }
+        Ok(ports)
+    }
+
+    /// Records the streams of `call`, the relayed ones and the ones added later, unless they're being recorded.
+    /// The first stream is recorded to `path` (see [`Recorder`](struct.Recorder.html)), and further ones get `-<index>` appended
+    pub async fn record(&self, call: u32, path: PathBuf) {
+        let mut state = self.state.lock().await;
+        for ((c, index), stream) in state.streams.iter() {
+            if *c == call {
+                stream.record(Self::recording_path(&path, *index));
+            }
+        }
+        debug!("recording call {} to {:?}", call, path);
+        state.recorded.insert(call, path);
+    }
+
+    /// Stops recording the streams of `call`. The recordings are written to the end
+    pub async fn stop_recording(&self, call: u32) {
+        let mut state = self.state.lock().await;
+        state.recorded.remove(&call);
+        for ((c, _), stream) in state.streams.iter() {
+            if *c == call {
+                stream.stop_recording();
+            }
+        }
+    }
+
+    /// Stops relaying (and recording) the streams of `call` and frees their ports
+    pub async fn release(&self, call: u32) {
+        let mut state = self.state.lock().await;
+        let keys: Vec<_> = state
+            .streams
+            .keys()
+            .filter(|(c, _)| *c == call)
+            .copied()
+            .collect();
+        for key in keys {
+            Self::remove_stream(&mut state, key);
+        }
+        state.recorded.remove(&call);
+    }
+
+    fn remove_stream(state: &mut State, key: (u32, usize)) {
+        if let Some(stream) = state.streams.remove(&key) {
+            for task in stream.tasks.iter() {
+                task.abort();
+            }
+            stream.stop_recording();
+            for port in stream.ports.iter() {
+                state.used_ports.remove(port);
+            }
+            debug!(
+                "released ports {:?} of stream {} of call {}",
+                stream.ports, key.1, key.0
+            );
+        }
+    }
+
+    /// Allocates ports for the stream `key` (the call and the index of the media description) and starts relaying it
+    async fn start_stream(&self, state: &mut State, key: (u32, usize)) -> io::Result<()> {
+        let (port0, rtp0, rtcp0) = self.allocate(state).await?;
+        let (port1, rtp1, rtcp1) = match self.allocate(state).await {
+            Ok(allocated) => allocated,
+            Err(e) => {
+                state.used_ports.remove(&port0);
+                return Err(e);
+            }
+        };
+        let peers = Arc::new(std::sync::Mutex::new(Peers::default()));
+        let recorder = Arc::new(std::sync::Mutex::new(None));
+        let last_packet = Arc::new(std::sync::Mutex::new(Instant::now()));
+        let (rtp0, rtcp0, rtp1, rtcp1) = (
+            Arc::new(rtp0),
+            Arc::new(rtcp0),
+            Arc::new(rtp1),
+            Arc::new(rtcp1),
+        );
+        let mut tasks = Vec::new();
+        for (from, to, leg, rtcp) in [
+            (rtp0.clone(), rtp1.clone(), 0, false),
+            (rtp1, rtp0, 1, false),
+            (rtcp0.clone(), rtcp1.clone(), 0, true),
+            (rtcp1, rtcp0, 1, true),
+        ] {
+            let forward = Self::forward(
+                from,
+                to,
+                peers.clone(),
+                recorder.clone(),
+                last_packet.clone(),
+                leg,
+                rtcp,
+            );
+            let (forward, handle) = future::abortable(forward);
+            task::spawn(forward);
+            tasks.push(handle);
+        }
+        let watch = Self::watch(
+            self.state.clone(),
+            key,
+            last_packet,
+            self.inactivity_timeout,
+        );
+        let (watch, handle) = future::abortable(watch);
+        task::spawn(watch);
+        tasks.push(handle);
+        debug!(
+            "relaying stream {} of call {} over ports {:?}",
+            key.1,
+            key.0,
+            [port0, port1]
+        );
+        let stream = Stream {
+            ports: [port0, port1],
+            peers,
+            recorder,
+            tasks,
+        };
+        if let Some(path) = state.recorded.get(&key.0) {
+            stream.record(Self::recording_path(path, key.1));
+        }
+        state.streams.insert(key, stream);
+        Ok(())
+    }
+
+    /// Releases the stream `key` once it hasn't relayed a packet for `timeout`
+    async fn watch(
+        state: Arc<Mutex<State>>,
+        key: (u32, usize),
+        last_packet: Arc<std::sync::Mutex<Instant>>,
+        timeout: Duration,
+    ) {
+        loop {
+            let idle = last_packet.lock().unwrap().elapsed();
+            if idle >= timeout {
+                info!(
+                    "stream {} of call {} is inactive for {:?}",
+                    key.1, key.0, idle
+                );
+                let mut state = state.lock().await;
+                Self::remove_stream(&mut state, key);
+                // A call that has lost all its streams isn't recorded further
+                if !state.streams.keys().any(|(call, _)| *call == key.0) {
+                    state.recorded.remove(&key.0);
+                }
+                return;
+            }
+            task::sleep(timeout - idle).await;
+        }
+    }
+
+    /// Binds a free port pair
+    async fn allocate(&self, state: &mut State) -> io::Result<(u16, UdpSocket, UdpSocket)> {
+        let pairs = self.last_port.saturating_sub(self.first_port) / 2 + 1;
+        for _ in 0..pairs {
+            let port = state.next_port;
+            state.next_port = if port as u32 + 3 > self.last_port as u32 {
+                self.first_port
+            } else {
+                port + 2
+            };
+            if state.used_ports.contains(&port) {
+                continue;
+            }
+            let rtp = UdpSocket::bind(SocketAddr::new(self.ip, port)).await;
+            let rtcp = UdpSocket::bind(SocketAddr::new(self.ip, port + 1)).await;
+            if let (Ok(rtp), Ok(rtcp)) = (rtp, rtcp) {
+                state.used_ports.insert(port);
+                return Ok((port, rtp, rtcp));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "no free relay ports",
+        ))
+    }
+
+    /// Sends packets received from the endpoint of `leg` to the endpoint of the other leg. RTP is recorded on the way
+    async fn forward(
+        from: Arc<UdpSocket>,
+        to: Arc<UdpSocket>,
+        peers: Arc<std::sync::Mutex<Peers>>,
+        recorder: Arc<std::sync::Mutex<Option<mpsc::Sender<RtpPacket>>>>,
+        last_packet: Arc<std::sync::Mutex<Instant>>,
+        leg: usize,
+        rtcp: bool,
+    ) {
+        let local_addr = from.local_addr();
+        let mut buf = [0; MAX_PACKET_SIZE];
+        loop {
+            let (len, src) = match from.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("relay: recv failed: {}", e);
+                    continue;
+                }
+            };
+            let dest = {
+                let mut peers = peers.lock().unwrap();
+                let peers = peers.get_mut(rtcp);
+                if !peers[leg].accept(src) {
+                    continue;
+                }
+                peers[1 - leg].addr
+            };
+            *last_packet.lock().unwrap() = Instant::now();
+            if let (false, Ok(local_addr)) = (rtcp, &local_addr) {
+                let mut recorder = recorder.lock().unwrap();
+                if let Some(sender) = recorder.as_ref() {
+                    // The recorder has stopped on an error
+                    if sender
+                        .send((leg, src, *local_addr, buf[..len].to_vec()))
+                        .is_err()
+                    {
+                        *recorder = None;
+                    }
+                }
+            }
+            if let Some(dest) = dest {
+                if let Err(e) = to.send_to(&buf[..len], dest).await {
+                    error!("relay: send to {} failed: {}", dest, e);
+                }
+            }
+        }
+    }
+
+    /// Records the packets sent to `packets` until the stream stops being recorded
+    fn write_recording(mut recorder: Recorder, packets: mpsc::Receiver<RtpPacket>) {
+        for (leg, src, dst, packet) in packets {
+            if let Err(e) = recorder.packet(leg, src, dst, &packet) {
+                error!("relay: recording failed: {}", e);
+                return;
+            }
+        }
+        if let Err(e) = recorder.finish() {
+            error!("relay: can't write recording: {}", e);
+        }
+    }
+
+    /// Returns the path of the recording of the stream `index` of a call recorded to `path`
+    fn recording_path(path: &Path, index: usize) -> PathBuf {
+        match index {
+            0 => path.to_path_buf(),
+            index => {
+                // The index goes after the whole name, dots included
+                let mut path = OsString::from(path.as_os_str());
+                path.push(format!("-{}", index));
+                PathBuf::from(path)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_update_releases_streams_it_has_allocated() {
+        task::block_on(async {
+            // The range has ports for a single stream
+            let relay = MediaRelay::new("127.0.0.1".parse().unwrap(), 42000, 42003);
+            let endpoint = Some("127.0.0.1:40000".parse().unwrap());
+            assert!(relay.update_all(1, 0, &[endpoint, endpoint]).await.is_err());
+            assert!(relay.state.lock().await.used_ports.is_empty());
+            // The sockets are closed once the aborted tasks are dropped
+            task::sleep(Duration::from_millis(50)).await;
+
+            let ports = relay.update_all(1, 0, &[endpoint, None]).await.unwrap();
+            assert!(ports[0].is_some());
+            assert_eq!(ports[1], None);
+            // A new stream can't be added, but the call keeps the one it has
+            assert!(relay.update_all(1, 1, &[endpoint, endpoint]).await.is_err());
+            assert!(relay.state.lock().await.streams.contains_key(&(1, 0)));
+            relay.release(1).await;
+        });
+    }
+
+    #[test]
+    fn inactive_stream_is_released() {
+        task::block_on(async {
+            let relay = MediaRelay::new("127.0.0.1".parse().unwrap(), 42004, 42007)
+                .inactivity_timeout(Duration::from_millis(50));
+            let endpoint = "127.0.0.1:40000".parse().unwrap();
+            relay.update(1, 0, 0, endpoint).await.unwrap();
+            task::sleep(Duration::from_millis(200)).await;
+            let state = relay.state.lock().await;
+            assert!(state.streams.is_empty());
+            assert!(state.used_ports.is_empty());
+        });
+    }
+
+    #[test]
+    fn streams_of_recorded_call_are_recorded() {
+        task::block_on(async {
+            let path = std::env::temp_dir().join("relay-test");
+            let wav_path = path.with_extension("wav");
+            let relay = MediaRelay::new("127.0.0.1".parse().unwrap(), 42008, 42011);
+            // The call is recorded before its SDP comes
+            relay.record(1, path).await;
+            let alice = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let bob = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let bob_sends_to = relay
+                .update(1, 0, 0, alice.local_addr().unwrap())
+                .await
+                .unwrap();
+            relay
+                .update(1, 0, 1, bob.local_addr().unwrap())
+                .await
+                .unwrap();
+
+            // RTP version 2, payload type 0 (PCMU), 20 ms of audio
+            let mut packet = vec![0x80, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+            packet.extend_from_slice(&[0x7f; 160]);
+            bob.send_to(&packet, ("127.0.0.1", bob_sends_to))
+                .await
+                .unwrap();
+            let mut buf = [0; 256];
+            alice.recv_from(&mut buf).await.unwrap();
+            relay.release(1).await;
+            // The recorder finishes on its own task
+            task::sleep(Duration::from_millis(200)).await;
+
+            let wav = std::fs::read(&wav_path).unwrap();
+            std::fs::remove_file(&wav_path).unwrap();
+            assert_eq!(&wav[..4], b"RIFF");
+            assert!(wav[58..].chunks(2).any(|frame| frame == [0xff, 0x7f]));
+        });
+    }
+}
@@ -1,7 +1,53 @@
+mod access_list;
+mod ban_list;
+mod credential_store;
 mod dialog_gen;
 mod dialogs;
+mod digest_authenticator;
+mod dispatcher;
+mod expires_policy;
+mod file_registration_store;
+mod flow;
+mod forks;
+mod hidden_vias;
+mod media_relay;
+mod recorder;
+mod reg_info;
+mod reg_subscriptions;
+mod registration_store;
 mod registrations;
+mod resolver;
+mod route;
+mod sdp;
+mod server_locator;
+mod topology_hiding;
+mod trunk_cseqs;
+mod trunks;
 
+pub use access_list::{AccessList, IpNet};
+pub use ban_list::BanList;
+pub use credential_store::{CredentialStore, Credentials};
 pub use dialog_gen::DialogGen;
 pub use dialogs::{Dialog, DialogInfo, Dialogs, IncompleteDialog, IncompleteDialogInfo};
-pub use registrations::Registrations;
+pub use digest_authenticator::{
+    AuthError, DigestAlgorithm, DigestAuthenticator, DigestCredentials,
+};
+pub use dispatcher::{DispatchAlgorithm, Dispatcher};
+pub use expires_policy::ExpiresPolicy;
+pub use file_registration_store::FileRegistrationStore;
+pub use flow::Flow;
+pub use forks::{Forking, Forks, ResponseAction};
+pub use hidden_vias::HiddenVias;
+pub use media_relay::MediaRelay;
+pub use recorder::Recorder;
+pub use reg_info::RegInfo;
+pub use reg_subscriptions::{RegSubscription, RegSubscriptions};
+pub use registration_store::RegistrationStore;
+pub use registrations::{Binding, ContactInfo, ContactParams, RegistrationError, Registrations};
+pub use resolver::{DnsResolver, NaptrRecord, Resolver, SrvRecord, StaticResolver};
+pub use route::Route;
+pub use sdp::{Attribute, Connection, Direction, Media, Origin, SessionDescription};
+pub use server_locator::ServerLocator;
+pub use topology_hiding::TopologyHiding;
+pub use trunk_cseqs::TrunkCSeqs;
+pub use trunks::{NumberPattern, Trunk, Trunks};
@@ -0,0 +1,397 @@
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// G.711 is sampled at 8 kHz with a byte per sample
+const SAMPLES_PER_MS: usize = 8;
+/// Packets further ahead of the recorded audio than that are considered broken
+const MAX_GAP: usize = 10 * 1000 * SAMPLES_PER_MS;
+/// WAV audio is written once it's that far behind the clock, so late packets of either leg still get into place
+const JITTER: usize = 1000 * SAMPLES_PER_MS;
+/// `LINKTYPE_RAW`: packets start with an IPv4 or IPv6 header
+const PCAP_LINKTYPE_RAW: u32 = 101;
+
+/// A G.711 variant that is written to WAV as is
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum G711 {
+    Pcmu,
+    Pcma,
+}
+
+impl G711 {
+    fn from_payload_type(payload_type: u8) -> Option<Self> {
+        match payload_type {
+            0 => Some(G711::Pcmu),
+            8 => Some(G711::Pcma),
+            _ => None,
+        }
+    }
+
+    fn payload_type(self) -> u8 {
+        match self {
+            G711::Pcmu => 0,
+            G711::Pcma => 8,
+        }
+    }
+
+    fn wav_format(self) -> u16 {
+        match self {
+            G711::Pcmu => 7,
+            G711::Pcma => 6,
+        }
+    }
+
+    /// The encoded zero
+    fn silence(self) -> u8 {
+        match self {
+            G711::Pcmu => 0xff,
+            G711::Pcma => 0xd5,
+        }
+    }
+}
+
+/// The audio of a leg
+#[derive(Debug, Default)]
+struct Channel {
+    /// The RTP timestamp of the first packet and the position of its first sample
+    start: Option<(u32, usize)>,
+    /// Samples that haven't been written yet, from the position the file ends at
+    samples: VecDeque<u8>,
+}
+
+#[derive(Debug)]
+enum Sink {
+    /// The format isn't known until the first packet
+    None,
+    /// Audio is written as it falls `JITTER` behind, since packets of the legs come out of order.
+    /// The sizes in the header are set when the recording is finished
+    Wav {
+        codec: G711,
+        file: BufWriter<File>,
+        channels: [Channel; 2],
+        /// The number of sample frames written
+        written: usize,
+    },
+    Pcap(BufWriter<File>),
+}
+
+/// Records an RTP stream relayed between two legs.
+/// G.711 (PCMU or PCMA, found by the payload type of the first packet) is written as stereo WAV with a channel per leg,
+/// and other codecs as raw RTP in pcap. The extension (`.wav` or `.pcap`) is appended to the path
+/// # Examples
+/// ```
+/// use sip_server::Recorder;
+/// use std::fs;
+///
+/// let path = std::env::temp_dir().join("recorder-doctest");
+/// let mut recorder = Recorder::new(path.clone());
+/// let caller = "192.0.2.1:4000".parse().unwrap();
+/// let callee = "192.0.2.2:5000".parse().unwrap();
+/// let relay = "192.0.2.10:10000".parse().unwrap();
+/// // RTP version 2, payload type 0 (PCMU), 20 ms of audio
+/// let mut packet = vec![0x80, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+/// packet.extend_from_slice(&[0x7f; 160]);
+/// recorder.packet(0, caller, relay, &packet).unwrap();
+/// recorder.packet(1, callee, relay, &packet).unwrap();
+/// recorder.finish().unwrap();
+///
+/// let wav = fs::read(path.with_extension("wav")).unwrap();
+/// assert_eq!(&wav[..4], b"RIFF");
+/// assert!(wav.len() >= 2 * 160);
+/// fs::remove_file(path.with_extension("wav")).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Recorder {
+    path: PathBuf,
+    started: Instant,
+    sink: Sink,
+}
+
+impl Recorder {
+    /// Creates a recorder that writes to `path` with the extension of the format. The file is created by the first packet
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            started: Instant::now(),
+            sink: Sink::None,
+        }
+    }
+
+    /// Records an RTP packet `src` has sent from `leg` (`0` or `1`) to `dst`
+    pub fn packet(
+        &mut self,
+        leg: usize,
+        src: SocketAddr,
+        dst: SocketAddr,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        if let Sink::None = self.sink {
+            let payload_type = match Self::parse(packet) {
+                Some((payload_type, _, _)) => payload_type,
+                None => return Ok(()),
+            };
+            self.sink = match G711::from_payload_type(payload_type) {
+                Some(codec) => {
+                    let mut file = BufWriter::new(File::create(self.file_path("wav"))?);
+                    Self::write_wav_header(&mut file, codec, 0)?;
+                    Sink::Wav {
+                        codec,
+                        file,
+                        channels: Default::default(),
+                        written: 0,
+                    }
+                }
+                None => {
+                    let mut file = BufWriter::new(File::create(self.file_path("pcap"))?);
+                    Self::write_pcap_header(&mut file)?;
+                    Sink::Pcap(file)
+                }
+            };
+        }
+        match &mut self.sink {
+            Sink::None => Ok(()),
+            Sink::Wav {
+                codec,
+                file,
+                channels,
+                written,
+            } => {
+                let now = self.started.elapsed().as_millis() as usize * SAMPLES_PER_MS;
+                let ready = now.saturating_sub(JITTER).saturating_sub(*written);
+                Self::write_samples(file, codec.silence(), channels, ready)?;
+                *written += ready;
+                let (payload_type, timestamp, payload) = match Self::parse(packet) {
+                    Some(parsed) => parsed,
+                    None => return Ok(()),
+                };
+                // Other payload types (DTMF, comfort noise) can't be written
+                if payload_type != codec.payload_type() {
+                    return Ok(());
+                }
+                let channel = &mut channels[leg];
+                // Channels are aligned by the time their first packets arrive
+                let (start_timestamp, start_position) =
+                    *channel.start.get_or_insert((timestamp, now));
+                let offset = timestamp.wrapping_sub(start_timestamp) as i32 as isize;
+                let position = start_position as isize + offset - *written as isize;
+                // The beginning of a late packet falls on audio that has been written
+                let skip = (-position).max(0) as usize;
+                if skip >= payload.len() || position > (channel.samples.len() + MAX_GAP) as isize {
+                    return Ok(());
+                }
+                let position = position.max(0) as usize;
+                let payload = &payload[skip..];
+                let end = position + payload.len();
+                if channel.samples.len() < end {
+                    channel.samples.resize(end, codec.silence());
+                }
+                for (sample, s) in channel.samples.range_mut(position..end).zip(payload) {
+                    *sample = *s;
+                }
+                Ok(())
+            }
+            Sink::Pcap(file) => Self::write_pcap_packet(file, src, dst, packet),
+        }
+    }
+
+    /// Writes the rest of what has been recorded
+    pub fn finish(self) -> io::Result<()> {
+        match self.sink {
+            Sink::None => Ok(()),
+            Sink::Wav {
+                codec,
+                mut file,
+                mut channels,
+                written,
+            } => {
+                let rest = channels[0].samples.len().max(channels[1].samples.len());
+                Self::write_samples(&mut file, codec.silence(), &mut channels, rest)?;
+                file.seek(SeekFrom::Start(0))?;
+                Self::write_wav_header(&mut file, codec, written + rest)?;
+                file.flush()
+            }
+            Sink::Pcap(mut file) => file.flush(),
+        }
+    }
+
+    fn file_path(&self, extension: &str) -> PathBuf {
+        // `set_extension` would replace a part of a name with dots, like a Call-ID with a host
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(".");
+        path.push(extension);
+        PathBuf::from(path)
+    }
+
+    /// Returns the payload type, the timestamp and the payload of an RTP packet
+    fn parse(packet: &[u8]) -> Option<(u8, u32, &[u8])> {
+        if packet.len() < 12 || packet[0] >> 6 != 2 {
+            return None;
+        }
+        let payload_type = packet[1] & 0x7f;
+        let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let mut start = 12 + 4 * (packet[0] & 0x0f) as usize;
+        // The header extension
+        if packet[0] & 0x10 != 0 {
+            let length = packet.get(start + 2..start + 4)?;
+            start += 4 + 4 * u16::from_be_bytes([length[0], length[1]]) as usize;
+        }
+        let mut end = packet.len();
+        // Padding
+        if packet[0] & 0x20 != 0 {
+            end = end.checked_sub(*packet.last()? as usize)?;
+        }
+        Some((payload_type, timestamp, packet.get(start..end)?))
+    }
+
+    /// Writes the header of a stereo WAV file with `samples` sample frames
+    fn write_wav_header(file: &mut impl Write, codec: G711, samples: usize) -> io::Result<()> {
+        let data_size = samples as u32 * 2;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(4 + 8 + 18 + 8 + 4 + 8 + data_size).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&18u32.to_le_bytes())?;
+        file.write_all(&codec.wav_format().to_le_bytes())?;
+        // Channels, the sample rate, bytes per second, bytes per sample frame, bits per sample and no extra format bytes
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&8000u32.to_le_bytes())?;
+        file.write_all(&16000u32.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&8u16.to_le_bytes())?;
+        file.write_all(&0u16.to_le_bytes())?;
+        // Non-PCM formats have the number of samples per channel
+        file.write_all(b"fact")?;
+        file.write_all(&4u32.to_le_bytes())?;
+        file.write_all(&(samples as u32).to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&data_size.to_le_bytes())
+    }
+
+    /// Writes `count` sample frames taken from the channels. A channel without a sample has silence
+    fn write_samples(
+        file: &mut impl Write,
+        silence: u8,
+        channels: &mut [Channel; 2],
+        count: usize,
+    ) -> io::Result<()> {
+        let mut frames = Vec::with_capacity(2 * count);
+        for _ in 0..count {
+            for channel in channels.iter_mut() {
+                frames.push(channel.samples.pop_front().unwrap_or(silence));
+            }
+        }
+        file.write_all(&frames)
+    }
+
+    fn write_pcap_header(file: &mut impl Write) -> io::Result<()> {
+        file.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        // The time zone, the accuracy of timestamps and the maximum length of packets
+        file.write_all(&0i32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(&65535u32.to_le_bytes())?;
+        file.write_all(&PCAP_LINKTYPE_RAW.to_le_bytes())
+    }
+
+    /// Writes `packet` wrapped in UDP and IP headers
+    fn write_pcap_packet(
+        file: &mut impl Write,
+        src: SocketAddr,
+        dst: SocketAddr,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        let udp_length = 8 + packet.len();
+        let mut data = Vec::with_capacity(40 + udp_length);
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+                data.extend_from_slice(&[0x45, 0]);
+                data.extend_from_slice(&((20 + udp_length) as u16).to_be_bytes());
+                // No identification, "don't fragment", TTL 64, UDP and the checksum set below
+                data.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+                data.extend_from_slice(&src_ip.octets());
+                data.extend_from_slice(&dst_ip.octets());
+                let checksum = !data
+                    .chunks(2)
+                    .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+                    .fold(0u32, |sum, word| {
+                        let sum = sum + word;
+                        (sum & 0xffff) + (sum >> 16)
+                    }) as u16;
+                data[10..12].copy_from_slice(&checksum.to_be_bytes());
+            }
+            (src_ip, dst_ip) => {
+                let to_ipv6 = |ip: IpAddr| match ip {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+                data.extend_from_slice(&[0x60, 0, 0, 0]);
+                data.extend_from_slice(&(udp_length as u16).to_be_bytes());
+                // UDP and the hop limit
+                data.extend_from_slice(&[17, 64]);
+                data.extend_from_slice(&to_ipv6(src_ip).octets());
+                data.extend_from_slice(&to_ipv6(dst_ip).octets());
+            }
+        }
+        data.extend_from_slice(&src.port().to_be_bytes());
+        data.extend_from_slice(&dst.port().to_be_bytes());
+        data.extend_from_slice(&(udp_length as u16).to_be_bytes());
+        // No checksum
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(packet);
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        file.write_all(&(time.as_secs() as u32).to_le_bytes())?;
+        file.write_all(&time.subsec_micros().to_le_bytes())?;
+        file.write_all(&(data.len() as u32).to_le_bytes())?;
+        file.write_all(&(data.len() as u32).to_le_bytes())?;
+        file.write_all(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, time::Duration};
+
+    #[test]
+    fn wav_is_written_as_audio_arrives() {
+        let path = std::env::temp_dir().join("recorder-test");
+        let wav_path = path.with_extension("wav");
+        let mut recorder = Recorder::new(path);
+        let caller = "192.0.2.1:4000".parse().unwrap();
+        let callee = "192.0.2.2:5000".parse().unwrap();
+        let relay = "192.0.2.10:10000".parse().unwrap();
+        let mut packet = vec![0x80, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+        packet.extend_from_slice(&[0x7f; 160]);
+
+        // The caller's audio starts 2 seconds into the recording
+        recorder.started -= Duration::from_secs(2);
+        recorder.packet(0, caller, relay, &packet).unwrap();
+        // The second before the jitter has been written already
+        assert!(fs::metadata(&wav_path).unwrap().len() >= 58 + 2 * 8000);
+        recorder.started -= Duration::from_secs(2);
+        recorder.packet(1, callee, relay, &packet).unwrap();
+        recorder.finish().unwrap();
+
+        let wav = fs::read(&wav_path).unwrap();
+        fs::remove_file(&wav_path).unwrap();
+        let size = |at: usize| u32::from_le_bytes([wav[at], wav[at + 1], wav[at + 2], wav[at + 3]]);
+        assert_eq!(size(4) as usize, wav.len() - 8);
+        assert_eq!(size(54) as usize, wav.len() - 58);
+        assert_eq!(size(46) as usize, (wav.len() - 58) / 2);
+        let frames: Vec<_> = wav[58..].chunks(2).collect();
+        let caller_audio = frames.iter().position(|f| f == &[0x7f, 0xff]).unwrap();
+        let callee_audio = frames.iter().position(|f| f == &[0xff, 0x7f]).unwrap();
+        assert!((2 * 8000..2 * 8000 + 160).contains(&caller_audio));
+        assert!((4 * 8000..4 * 8000 + 160).contains(&callee_audio));
+        assert_eq!(frames.len(), callee_audio + 160);
+    }
+}
@@ -0,0 +1,209 @@
+use super::Binding;
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::Write,
+    hash::{Hash, Hasher},
+};
+
+/// The state of a contact as per https://tools.ietf.org/html/rfc3680#section-5.2
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RegContactState {
+    Active,
+    Terminated,
+}
+
+/// The event that caused the contact's state transition as per https://tools.ietf.org/html/rfc3680#section-5.2
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RegContactEvent {
+    Registered,
+    Created,
+    Refreshed,
+    Shortened,
+    Expired,
+    Unregistered,
+}
+
+impl RegContactEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            RegContactEvent::Registered => "registered",
+            RegContactEvent::Created => "created",
+            RegContactEvent::Refreshed => "refreshed",
+            RegContactEvent::Shortened => "shortened",
+            RegContactEvent::Expired => "expired",
+            RegContactEvent::Unregistered => "unregistered",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct RegContact {
+    uri: String,
+    state: RegContactState,
+    event: RegContactEvent,
+    expires: u32,
+    q: f32,
+    call_id: String,
+    cseq: u32,
+}
+
+impl RegContact {
+    fn new(binding: &Binding, state: RegContactState, event: RegContactEvent) -> Self {
+        Self {
+            uri: binding.uri().to_string(),
+            state,
+            event,
+            expires: if state == RegContactState::Active {
+                binding.expires()
+            } else {
+                0
+            },
+            q: binding.q(),
+            call_id: binding.call_id().clone(),
+            cseq: binding.cseq(),
+        }
+    }
+}
+
+/// Registration information document (`application/reginfo+xml`) as per https://tools.ietf.org/html/rfc3680#section-5
+/// # Examples
+/// ```
+/// use sip_server::RegInfo;
+///
+/// let reg_info = RegInfo::full("sip:joe@example.com", 0, &[]);
+/// let xml = reg_info.to_xml();
+///
+/// assert!(xml.contains(r#"<reginfo xmlns="urn:ietf:params:xml:ns:reginfo" version="0" state="full">"#));
+/// assert!(xml.contains(r#"aor="sip:joe@example.com""#));
+/// assert!(xml.contains(r#"state="terminated""#));
+/// ```
+#[derive(Clone, Debug)]
+pub struct RegInfo {
+    aor: String,
+    version: u32,
+    full: bool,
+    active: bool,
+    contacts: Vec<RegContact>,
+}
+
+impl RegInfo {
+    /// Creates a document with full state: every binding of `aor`
+    pub fn full(aor: &str, version: u32, bindings: &[&Binding]) -> Self {
+        let contacts = bindings
+            .iter()
+            .map(|b| RegContact::new(b, RegContactState::Active, RegContactEvent::Registered))
+            .collect();
+        Self {
+            aor: aor.to_string(),
+            version,
+            full: true,
+            active: !bindings.is_empty(),
+            contacts,
+        }
+    }
+
+    /// Creates a document with partial state: only the bindings that differ between `before` and `after`.
+    /// Returns `None` if nothing changed
+    pub fn partial(
+        aor: &str,
+        version: u32,
+        before: &[Binding],
+        after: &[&Binding],
+    ) -> Option<Self> {
+        let mut contacts = Vec::new();
+        for binding in after.iter() {
+            match before.iter().find(|b| b.uri() == binding.uri()) {
+                None => contacts.push(RegContact::new(
+                    binding,
+                    RegContactState::Active,
+                    RegContactEvent::Created,
+                )),
+                Some(old) if old.call_id() != binding.call_id() || old.cseq() != binding.cseq() => {
+                    let event = if binding.expires() < old.expires() {
+                        RegContactEvent::Shortened
+                    } else {
+                        RegContactEvent::Refreshed
+                    };
+                    contacts.push(RegContact::new(binding, RegContactState::Active, event));
+                }
+                Some(_) => {}
+            }
+        }
+        for binding in before.iter() {
+            if !after.iter().any(|b| b.uri() == binding.uri()) {
+                let event = if binding.expires() == 0 {
+                    RegContactEvent::Expired
+                } else {
+                    RegContactEvent::Unregistered
+                };
+                contacts.push(RegContact::new(binding, RegContactState::Terminated, event));
+            }
+        }
+        if contacts.is_empty() {
+            return None;
+        }
+        Some(Self {
+            aor: aor.to_string(),
+            version,
+            full: false,
+            active: !after.is_empty(),
+            contacts,
+        })
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::new();
+        // Writing to `String` never fails
+        let _ = writeln!(xml, r#"<?xml version="1.0"?>"#);
+        let _ = writeln!(
+            xml,
+            r#"<reginfo xmlns="urn:ietf:params:xml:ns:reginfo" version="{}" state="{}">"#,
+            self.version,
+            if self.full { "full" } else { "partial" }
+        );
+        let _ = writeln!(
+            xml,
+            r#"  <registration aor="{}" id="{}" state="{}">"#,
+            escape(&self.aor),
+            id(&self.aor),
+            if self.active { "active" } else { "terminated" }
+        );
+        for contact in self.contacts.iter() {
+            let _ = writeln!(
+                xml,
+                r#"    <contact id="{}" state="{}" event="{}" expires="{}" q="{:.3}" callid="{}" cseq="{}">"#,
+                id(&contact.uri),
+                match contact.state {
+                    RegContactState::Active => "active",
+                    RegContactState::Terminated => "terminated",
+                },
+                contact.event.as_str(),
+                contact.expires,
+                contact.q,
+                escape(&contact.call_id),
+                contact.cseq
+            );
+            let _ = writeln!(xml, "      <uri>{}</uri>", escape(&contact.uri));
+            let _ = writeln!(xml, "    </contact>");
+        }
+        let _ = writeln!(xml, "  </registration>");
+        let _ = writeln!(xml, "</reginfo>");
+        xml
+    }
+}
+
+/// Returns an identifier that is the same for the same value
+fn id(value: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    format!("{:x}", hasher.finish())
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,173 @@
+use super::Flow;
+use libsip::{NamedHeader, Uri};
+use log::info;
+use std::time::{Duration, Instant};
+
+/// How long an expired subscription is kept, so that the final NOTIFY can still be sent for it
+const EXPIRED_LIFETIME: Duration = Duration::from_secs(60);
+
+/// A subscription to the registration state of an address-of-record as per https://tools.ietf.org/html/rfc3680
+#[derive(Clone, Debug)]
+pub struct RegSubscription {
+    aor: String,
+    flow: Flow,
+    uri: Uri,
+    call_id: String,
+    from: NamedHeader,
+    to: NamedHeader,
+    cseq: u32,
+    version: u32,
+    expires_at: Instant,
+}
+
+impl RegSubscription {
+    /// # Parameters
+    /// * `aor` - The user whose registrations are watched
+    /// * `flow` - The connection NOTIFY requests are sent over
+    /// * `uri` - The Request-URI of NOTIFY requests (the subscriber's Contact)
+    /// * `call_id` - The subscription dialog's Call-ID
+    /// * `from` - `From` of NOTIFY requests (`To` of SUBSCRIBE with the server's tag)
+    /// * `to` - `To` of NOTIFY requests (`From` of SUBSCRIBE)
+    /// * `expires` - The number of seconds the subscription is valid for
+    pub fn new(
+        aor: String,
+        flow: Flow,
+        uri: Uri,
+        call_id: String,
+        from: NamedHeader,
+        to: NamedHeader,
+        expires: u32,
+    ) -> Self {
+        Self {
+            aor,
+            flow,
+            uri,
+            call_id,
+            from,
+            to,
+            cseq: 0,
+            version: 0,
+            expires_at: Instant::now() + Duration::from_secs(expires.into()),
+        }
+    }
+
+    pub fn aor(&self) -> &String {
+        &self.aor
+    }
+
+    pub fn flow(&self) -> Flow {
+        self.flow
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    pub fn call_id(&self) -> &String {
+        &self.call_id
+    }
+
+    pub fn from(&self) -> &NamedHeader {
+        &self.from
+    }
+
+    pub fn to(&self) -> &NamedHeader {
+        &self.to
+    }
+
+    /// Returns the number of seconds left until the subscription expires
+    pub fn expires(&self) -> u32 {
+        self.expires_at
+            .saturating_duration_since(Instant::now())
+            .as_secs() as u32
+    }
+
+    /// Returns the CSeq and the reginfo document version for the next NOTIFY
+    pub fn next_notify(&mut self) -> (u32, u32) {
+        self.cseq += 1;
+        let version = self.version;
+        self.version += 1;
+        (self.cseq, version)
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// Manages subscriptions to the "reg" event package
+#[derive(Debug, Default)]
+pub struct RegSubscriptions(Vec<RegSubscription>);
+
+impl RegSubscriptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a subscription or refreshes the existing one with the same Call-ID.
+    /// Returns the subscription
+    pub fn subscribe(&mut self, subscription: RegSubscription) -> &mut RegSubscription {
+        let now = Instant::now();
+        self.0.retain(|s| s.expires_at + EXPIRED_LIFETIME > now);
+        let index = match self
+            .0
+            .iter()
+            .position(|s| s.call_id == subscription.call_id)
+        {
+            Some(index) => {
+                self.0[index].expires_at = subscription.expires_at;
+                index
+            }
+            None => {
+                info!(
+                    "{} subscribed to registrations of \"{}\"",
+                    subscription.uri, subscription.aor
+                );
+                self.0.push(subscription);
+                self.0.len() - 1
+            }
+        };
+        &mut self.0[index]
+    }
+
+    /// Removes the subscription with a given Call-ID and returns it
+    pub fn unsubscribe(&mut self, call_id: &str) -> Option<RegSubscription> {
+        let index = self.0.iter().position(|s| s.call_id == call_id)?;
+        let subscription = self.0.swap_remove(index);
+        info!(
+            "{} unsubscribed from registrations of \"{}\"",
+            subscription.uri, subscription.aor
+        );
+        Some(subscription)
+    }
+
+    /// Removes the subscription with a given Call-ID and returns it if it has expired.
+    /// A subscription refreshed in the meantime is kept
+    pub fn remove_expired(&mut self, call_id: &str) -> Option<RegSubscription> {
+        let now = Instant::now();
+        let index = self
+            .0
+            .iter()
+            .position(|s| s.call_id == call_id && s.is_expired(now))?;
+        let subscription = self.0.swap_remove(index);
+        info!(
+            "subscription of {} to registrations of \"{}\" is expired",
+            subscription.uri, subscription.aor
+        );
+        Some(subscription)
+    }
+
+    /// Returns `true` if there is a subscription with a given Call-ID
+    pub fn contains(&self, call_id: &str) -> bool {
+        self.0.iter().any(|s| s.call_id == call_id)
+    }
+
+    /// Returns subscriptions to a given user that haven't expired yet
+    pub fn subscriptions_mut(&mut self, aor: &str) -> Vec<&mut RegSubscription> {
+        let now = Instant::now();
+        self.0
+            .iter_mut()
+            .filter(|s| s.aor == aor && !s.is_expired(now))
+            .collect()
+    }
+}
@@ -0,0 +1,87 @@
+use super::{Binding, ContactInfo, Flow, RegistrationError, Registrations};
+
+/// Keeps registrations.
+/// [`Registrations`](struct.Registrations.html) keeps them in memory only,
+/// [`FileRegistrationStore`](struct.FileRegistrationStore.html) keeps them in a file so that they survive a restart
+pub trait RegistrationStore: Send + Sync {
+    /// See [`Registrations::update`](struct.Registrations.html#method.update)
+    fn update(
+        &mut self,
+        user: &str,
+        call_id: &str,
+        cseq: u32,
+        contacts: Vec<ContactInfo>,
+    ) -> Result<(), RegistrationError>;
+
+    /// See [`Registrations::remove_all`](struct.Registrations.html#method.remove_all)
+    fn remove_all(&mut self, user: &str, call_id: &str, cseq: u32)
+        -> Result<(), RegistrationError>;
+
+    /// See [`Registrations::bindings`](struct.Registrations.html#method.bindings)
+    fn bindings(&self, user: &str) -> Vec<&Binding>;
+
+    /// See [`Registrations::remove_expired`](struct.Registrations.html#method.remove_expired)
+    fn remove_expired(&mut self) -> bool;
+
+    /// See [`Registrations::remove_expired_bindings`](struct.Registrations.html#method.remove_expired_bindings)
+    fn remove_expired_bindings(&mut self, user: &str) -> Vec<Binding>;
+
+    /// See [`Registrations::issue_temp_gruu`](struct.Registrations.html#method.issue_temp_gruu)
+    fn issue_temp_gruu(&mut self, user: &str, instance_id: &str) -> Option<String>;
+
+    /// See [`Registrations::binding_by_instance_id`](struct.Registrations.html#method.binding_by_instance_id)
+    fn binding_by_instance_id(&self, user: &str, instance_id: &str) -> Option<&Binding>;
+
+    /// See [`Registrations::binding_by_temp_gruu`](struct.Registrations.html#method.binding_by_temp_gruu)
+    fn binding_by_temp_gruu(&self, temp_gruu: &str) -> Option<&Binding>;
+
+    /// See [`Registrations::user_flows`](struct.Registrations.html#method.user_flows)
+    fn user_flows(&self, user: &str) -> Vec<Flow> {
+        self.bindings(user).into_iter().map(Binding::flow).collect()
+    }
+}
+
+impl RegistrationStore for Registrations {
+    fn update(
+        &mut self,
+        user: &str,
+        call_id: &str,
+        cseq: u32,
+        contacts: Vec<ContactInfo>,
+    ) -> Result<(), RegistrationError> {
+        Registrations::update(self, user, call_id, cseq, contacts)
+    }
+
+    fn remove_all(
+        &mut self,
+        user: &str,
+        call_id: &str,
+        cseq: u32,
+    ) -> Result<(), RegistrationError> {
+        Registrations::remove_all(self, user, call_id, cseq)
+    }
+
+    fn bindings(&self, user: &str) -> Vec<&Binding> {
+        Registrations::bindings(self, user)
+    }
+
+    fn remove_expired(&mut self) -> bool {
+        Registrations::remove_expired(self)
+    }
+
+    fn remove_expired_bindings(&mut self, user: &str) -> Vec<Binding> {
+        Registrations::remove_expired_bindings(self, user)
+    }
+
+    fn issue_temp_gruu(&mut self, user: &str, instance_id: &str) -> Option<String> {
+        Registrations::issue_temp_gruu(self, user, instance_id)
+    }
+
+    fn binding_by_instance_id(&self, user: &str, instance_id: &str) -> Option<&Binding> {
+        Registrations::binding_by_instance_id(self, user, instance_id)
+    }
+
+    fn binding_by_temp_gruu(&self, temp_gruu: &str) -> Option<&Binding> {
+        Registrations::binding_by_temp_gruu(self, temp_gruu)
+    }
+}
@@ -1,79 +1,582 @@
+use super::Flow;
+use libsip::Uri;
 use log::info;
+use rand::RngCore;
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    net::SocketAddr,
+    cmp::Ordering,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    time::{Duration, Instant},
 };
 
-/// Manages registrations
+/// Contact header parameters
+pub type ContactParams = HashMap<String, Option<String>>;
+
+/// Returns the value of "+sip.instance" without quotes and angle brackets as per https://tools.ietf.org/html/rfc5627#section-3.1
+fn instance_id(params: &ContactParams) -> Option<&str> {
+    params
+        .get("+sip.instance")
+        .and_then(|instance| instance.as_ref())
+        .map(|instance| {
+            instance
+                .trim_matches('"')
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+        })
+}
+
+fn reg_id(params: &ContactParams) -> Option<u32> {
+    params
+        .get("reg-id")
+        .and_then(|reg_id| reg_id.as_ref())
+        .and_then(|reg_id| reg_id.parse().ok())
+}
+
+/// A single contact binding of an address-of-record as per https://tools.ietf.org/html/rfc3261#section-10.3
+#[derive(Clone, Debug)]
+pub struct Binding {
+    uri: Uri,
+    params: ContactParams,
+    flow: Flow,
+    q: f32,
+    expires_at: Instant,
+    call_id: String,
+    cseq: u32,
+    temp_gruus: Vec<String>,
+    path: Vec<String>,
+}
+
+impl Binding {
+    /// Returns the contact URI. It's the Request-URI of requests sent to the binding
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Returns the contact's parameters as they were registered
+    pub fn params(&self) -> &ContactParams {
+        &self.params
+    }
+
+    /// Returns the connection the binding was registered over. Requests to the binding are sent over it
+    pub fn flow(&self) -> Flow {
+        self.flow
+    }
+
+    pub fn q(&self) -> f32 {
+        self.q
+    }
+
+    pub fn call_id(&self) -> &String {
+        &self.call_id
+    }
+
+    pub fn cseq(&self) -> u32 {
+        self.cseq
+    }
+
+    /// Returns the instance ID ("+sip.instance") of the device as per https://tools.ietf.org/html/rfc5627#section-3.1
+    pub fn instance_id(&self) -> Option<&str> {
+        instance_id(&self.params)
+    }
+
+    /// Returns "reg-id" as per https://tools.ietf.org/html/rfc5626#section-4.2
+    pub fn reg_id(&self) -> Option<u32> {
+        reg_id(&self.params)
+    }
+
+    /// Returns the user part of the most recently issued temporary GRUU
+    pub fn temp_gruu(&self) -> Option<&String> {
+        self.temp_gruus.last()
+    }
+
+    /// Returns the user parts of all temporary GRUUs issued since the binding was created
+    pub fn temp_gruus(&self) -> &Vec<String> {
+        &self.temp_gruus
+    }
+
+    /// Returns the Path vector as per https://tools.ietf.org/html/rfc3327#section-5.3.
+    /// It's the preloaded Route set of requests sent to the binding
+    pub fn path(&self) -> &Vec<String> {
+        &self.path
+    }
+
+    /// Returns the number of seconds left until the binding expires
+    pub fn expires(&self) -> u32 {
+        self.expires_at
+            .saturating_duration_since(Instant::now())
+            .as_secs() as u32
+    }
+
+    /// Restores a binding that was registered before
+    pub(crate) fn restore(
+        contact: ContactInfo,
+        call_id: String,
+        cseq: u32,
+        temp_gruus: Vec<String>,
+    ) -> Self {
+        Self {
+            uri: contact.uri,
+            params: contact.params,
+            flow: contact.flow,
+            q: contact.q,
+            expires_at: Instant::now() + Duration::from_secs(contact.expires.into()),
+            call_id,
+            cseq,
+            temp_gruus,
+            path: contact.path,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at <= now
+    }
+
+    /// A contact with an instance ID refers to the binding with the same instance ID and "reg-id" as per https://tools.ietf.org/html/rfc5627#section-7.1.2.1.
+    /// Otherwise, it refers to the binding with the same URI
+    fn matches(&self, contact: &ContactInfo) -> bool {
+        match (self.instance_id(), instance_id(&contact.params)) {
+            (Some(instance), Some(contact_instance)) => {
+                instance == contact_instance && self.reg_id() == reg_id(&contact.params)
+            }
+            _ => self.uri == contact.uri,
+        }
+    }
+}
+
+/// A contact provided by a REGISTER request
+#[derive(Clone, Debug)]
+pub struct ContactInfo {
+    uri: Uri,
+    params: ContactParams,
+    flow: Flow,
+    q: f32,
+    expires: u32,
+    path: Vec<String>,
+}
+
+impl ContactInfo {
+    /// # Parameters
+    /// * `uri` - The contact URI
+    /// * `params` - The contact's parameters. "q" is parsed from them and clamped to `0.0..=1.0` (1.0 if absent)
+    /// * `flow` - The connection the REGISTER request arrived on
+    /// * `expires` - The number of seconds the binding is valid for. `0` removes the binding
+    pub fn new(uri: Uri, params: ContactParams, flow: Flow, expires: u32) -> Self {
+        let q = params
+            .get("q")
+            .and_then(|q| q.as_ref())
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        Self {
+            uri,
+            params,
+            flow,
+            q: q.clamp(0.0, 1.0),
+            expires,
+            path: Vec::new(),
+        }
+    }
+
+    /// Sets the Path vector of the REGISTER request (the proxies' URIs with parameters, the nearest to the registrar first)
+    pub fn path(mut self, path: Vec<String>) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// Returns the instance ID ("+sip.instance") of the device
+    pub fn instance_id(&self) -> Option<&str> {
+        instance_id(&self.params)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RegistrationError {
+    /// The request has the same Call-ID as an existing binding, but its CSeq isn't higher.
+    /// The registrar must respond with 500 as per https://tools.ietf.org/html/rfc3261#section-10.3 (step 7)
+    OutOfOrder,
+}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistrationError::OutOfOrder => write!(f, "request is out of order"),
+        }
+    }
+}
+
+impl Error for RegistrationError {}
+
+/// Manages registrations. An address-of-record (a user) may have several contact bindings
 /// # Examples
 /// ```
-/// use sip_server::Registrations;
+/// use libsip::{Domain, Transport, Uri, UriSchema};
+/// use sip_server::{ContactInfo, Flow, Registrations};
+/// use std::collections::HashMap;
 ///
 /// let mut registrations = Registrations::new();
 /// let user = "joe";
-/// let address = "192.168.0.50:44374".parse().expect("failed to parse socket address");
+/// let desk_phone = "192.168.0.50:44374".parse().expect("failed to parse socket address");
+/// let desk_phone_flow = Flow::new(Transport::Udp, desk_phone);
+/// let desk_phone_uri = Uri::new(UriSchema::Sip, Domain::Ipv4("192.168.0.50".parse().unwrap(), Some(44374)));
+/// let softphone = "192.168.0.51:5060".parse().expect("failed to parse socket address");
+/// let softphone_flow = Flow::new(Transport::Tcp, softphone);
+/// let softphone_uri = Uri::new(UriSchema::Sip, Domain::Ipv4("10.0.0.1".parse().unwrap(), Some(5060)));
+/// let mut params = HashMap::new();
+/// params.insert("q".to_string(), Some("0.5".to_string()));
+///
+/// let contacts = vec![ContactInfo::new(desk_phone_uri.clone(), params.clone(), desk_phone_flow, 3600)];
+/// assert!(registrations.update(user, "call-1", 1, contacts).is_ok());
 ///
-/// assert!(registrations.register_user(user.to_string(), address));
+/// let contacts = vec![ContactInfo::new(softphone_uri.clone(), HashMap::new(), softphone_flow, 3600)];
+/// assert!(registrations.update(user, "call-2", 1, contacts).is_ok());
 ///
-/// assert!(!registrations.register_user(user.to_string(), address));
+/// assert_eq!(registrations.user_flows(user), vec![softphone_flow, desk_phone_flow]);
 ///
-/// assert_eq!(registrations.user_addr(user), Some(address));
+/// // The same Call-ID with a CSeq that isn't higher is rejected
+/// let contacts = vec![ContactInfo::new(desk_phone_uri.clone(), params.clone(), desk_phone_flow, 0)];
+/// assert!(registrations.update(user, "call-1", 1, contacts).is_err());
 ///
-/// assert!(registrations.unregister_user(user));
+/// let contacts = vec![ContactInfo::new(desk_phone_uri, params, desk_phone_flow, 0)];
+/// assert!(registrations.update(user, "call-1", 2, contacts).is_ok());
 ///
-/// assert_eq!(registrations.user_addr(user), None);
+/// assert_eq!(registrations.bindings(user)[0].uri(), &softphone_uri);
+///
+/// assert!(registrations.remove_all(user, "call-2", 2).is_ok());
+///
+/// assert!(registrations.user_flows(user).is_empty());
 /// ```
 #[derive(Clone, Default, Debug)]
-pub struct Registrations(HashMap<String, SocketAddr>);
+pub struct Registrations(HashMap<String, Vec<Binding>>);
 
 impl Registrations {
     pub fn new() -> Self {
         Self::default()
     }
 
-    /// Registers a new user or updates an existing user's address.
-    /// Returns `true` if `user` is new
-    pub fn register_user(&mut self, user: String, address: SocketAddr) -> bool {
-        match self.0.entry(user) {
-            Entry::Occupied(mut entry) => {
-                let addr_changed = {
-                    let user = entry.key();
-                    let current_addr = entry.get();
-                    if *current_addr != address {
-                        info!(
-                            "user \"{}\" address is changed: {} -> {}",
-                            user, current_addr, address
-                        );
-                        true
-                    } else {
-                        false
-                    }
-                };
-                if addr_changed {
-                    *entry.get_mut() = address;
+    /// Adds, refreshes and removes (if `expires` is 0) `user`'s bindings as per https://tools.ietf.org/html/rfc3261#section-10.3 (step 8).
+    /// Nothing is changed if any contact fails the Call-ID and CSeq check
+    pub fn update(
+        &mut self,
+        user: &str,
+        call_id: &str,
+        cseq: u32,
+        contacts: Vec<ContactInfo>,
+    ) -> Result<(), RegistrationError> {
+        let now = Instant::now();
+        let bindings = self.0.entry(user.to_string()).or_default();
+        bindings.retain(|b| !b.is_expired(now));
+        for contact in contacts.iter() {
+            if let Some(binding) = bindings.iter().find(|b| b.matches(contact)) {
+                if binding.call_id == call_id && binding.cseq >= cseq {
+                    return Err(RegistrationError::OutOfOrder);
                 }
-                false
             }
-            Entry::Vacant(entry) => {
-                info!("user \"{}\" is registered: {}", entry.key(), address);
-                entry.insert(address);
-                true
+        }
+        for contact in contacts {
+            let index = bindings.iter().position(|b| b.matches(&contact));
+            match (index, contact.expires) {
+                (Some(index), 0) => {
+                    let binding = bindings.swap_remove(index);
+                    info!("user \"{}\" is unregistered: {}", user, binding.uri);
+                }
+                (Some(index), expires) => {
+                    let binding = &mut bindings[index];
+                    // RFC 5627 7.1.2.1 Processing a REGISTER Request: a new Call-ID means the UA has restarted,
+                    // so the temporary GRUUs issued before are invalidated
+                    if binding.call_id != call_id {
+                        binding.temp_gruus.clear();
+                    }
+                    binding.uri = contact.uri;
+                    binding.params = contact.params;
+                    binding.flow = contact.flow;
+                    binding.q = contact.q;
+                    binding.expires_at = now + Duration::from_secs(expires.into());
+                    binding.call_id = call_id.to_string();
+                    binding.cseq = cseq;
+                    binding.path = contact.path;
+                }
+                (None, 0) => {}
+                (None, expires) => {
+                    info!("user \"{}\" is registered: {}", user, contact.uri);
+                    bindings.push(Binding {
+                        uri: contact.uri,
+                        params: contact.params,
+                        flow: contact.flow,
+                        q: contact.q,
+                        expires_at: now + Duration::from_secs(expires.into()),
+                        call_id: call_id.to_string(),
+                        cseq,
+                        temp_gruus: Vec::new(),
+                        path: contact.path,
+                    });
+                }
             }
         }
+        if bindings.is_empty() {
+            self.0.remove(user);
+        }
+        Ok(())
     }
 
-    /// Returns `true` if `user` was registered, but is no longer registered
-    pub fn unregister_user(&mut self, user: &str) -> bool {
+    /// Removes all `user`'s bindings as requested by `Contact: *` as per https://tools.ietf.org/html/rfc3261#section-10.3 (step 6)
+    pub fn remove_all(
+        &mut self,
+        user: &str,
+        call_id: &str,
+        cseq: u32,
+    ) -> Result<(), RegistrationError> {
+        if let Some(bindings) = self.0.get(user) {
+            if bindings
+                .iter()
+                .any(|b| b.call_id == call_id && b.cseq >= cseq)
+            {
+                return Err(RegistrationError::OutOfOrder);
+            }
+        }
         if self.0.remove(user).is_some() {
             info!("user \"{}\" is unregistered", user);
-            true
-        } else {
-            false
         }
+        Ok(())
+    }
+
+    /// Returns a given user's bindings that haven't expired yet. Bindings with a higher q-value go first
+    pub fn bindings(&self, user: &str) -> Vec<&Binding> {
+        let now = Instant::now();
+        let mut bindings: Vec<&Binding> = self
+            .0
+            .get(user)
+            .map(|b| b.iter().filter(|b| !b.is_expired(now)).collect())
+            .unwrap_or_default();
+        bindings.sort_by(|a, b| b.q.partial_cmp(&a.q).unwrap_or(Ordering::Equal));
+        bindings
+    }
+
+    /// Issues a new temporary GRUU for `user`'s binding with a given instance ID as per https://tools.ietf.org/html/rfc5627#section-7.1.2.1.
+    /// Returns its user part. Temporary GRUUs issued before remain valid until the binding expires or is refreshed with another Call-ID
+    pub fn issue_temp_gruu(&mut self, user: &str, instance_id: &str) -> Option<String> {
+        let now = Instant::now();
+        let binding = self
+            .0
+            .get_mut(user)?
+            .iter_mut()
+            .find(|b| !b.is_expired(now) && b.instance_id() == Some(instance_id))?;
+        let temp_gruu = format!(
+            "tgruu.{:x}{:x}",
+            rand::rngs::OsRng.next_u64(),
+            rand::rngs::OsRng.next_u64()
+        );
+        binding.temp_gruus.push(temp_gruu.clone());
+        Some(temp_gruu)
+    }
+
+    /// Returns `user`'s binding a public GRUU with a given instance ID refers to
+    pub fn binding_by_instance_id(&self, user: &str, instance_id: &str) -> Option<&Binding> {
+        self.bindings(user)
+            .into_iter()
+            .find(|b| b.instance_id() == Some(instance_id))
+    }
+
+    /// Returns the binding a temporary GRUU with a given user part refers to
+    pub fn binding_by_temp_gruu(&self, temp_gruu: &str) -> Option<&Binding> {
+        self.all_bindings()
+            .map(|(_, b)| b)
+            .find(|b| b.temp_gruus.iter().any(|t| t == temp_gruu))
+    }
+
+    /// Returns the connections a given user's bindings were registered over. Flows of bindings with a higher q-value go first
+    pub fn user_flows(&self, user: &str) -> Vec<Flow> {
+        self.bindings(user).into_iter().map(Binding::flow).collect()
+    }
+
+    /// Returns all bindings that haven't expired yet along with their users
+    pub(crate) fn all_bindings(&self) -> impl Iterator<Item = (&String, &Binding)> {
+        let now = Instant::now();
+        self.0.iter().flat_map(move |(user, bindings)| {
+            bindings
+                .iter()
+                .filter(move |b| !b.is_expired(now))
+                .map(move |b| (user, b))
+        })
+    }
+
+    /// Adds a binding that was registered before
+    pub(crate) fn restore(&mut self, user: String, binding: Binding) {
+        self.0.entry(user).or_default().push(binding);
+    }
+
+    /// Removes `user`'s expired bindings and returns them, so that subscribers can be told about them
+    pub fn remove_expired_bindings(&mut self, user: &str) -> Vec<Binding> {
+        let now = Instant::now();
+        let bindings = match self.0.get_mut(user) {
+            Some(bindings) => bindings,
+            None => return Vec::new(),
+        };
+        let (expired, active) = bindings.drain(..).partition(|b| b.is_expired(now));
+        *bindings = active;
+        if bindings.is_empty() {
+            self.0.remove(user);
+        }
+        for binding in expired.iter() {
+            info!("user \"{}\" registration is expired: {}", user, binding.uri);
+        }
+        expired
+    }
+
+    /// Removes all expired bindings. Returns `true` if any binding was removed
+    pub fn remove_expired(&mut self) -> bool {
+        let now = Instant::now();
+        let mut removed = false;
+        for (user, bindings) in self.0.iter_mut() {
+            bindings.retain(|b| {
+                if b.is_expired(now) {
+                    info!("user \"{}\" registration is expired: {}", user, b.uri);
+                    removed = true;
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        self.0.retain(|_, bindings| !bindings.is_empty());
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsip::{Domain, Transport, UriSchema};
+
+    fn contact(instance_id: &str, expires: u32) -> ContactInfo {
+        let uri = Uri::new(
+            UriSchema::Sip,
+            Domain::Ipv4("192.168.0.50".parse().unwrap(), Some(5060)),
+        );
+        let flow = Flow::new(Transport::Udp, "192.168.0.50:5060".parse().unwrap());
+        let mut params = ContactParams::new();
+        params.insert(
+            "+sip.instance".to_string(),
+            Some(format!("\"<{}>\"", instance_id)),
+        );
+        ContactInfo::new(uri, params, flow, expires)
     }
 
-    /// Returns a given user's address
-    pub fn user_addr(&self, user: &str) -> Option<SocketAddr> {
-        self.0.get(user).map(Clone::clone)
+    #[test]
+    fn temp_gruus_are_kept_on_refresh() {
+        let mut registrations = Registrations::new();
+        let instance = "urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6";
+        registrations
+            .update("joe", "call-1", 1, vec![contact(instance, 3600)])
+            .unwrap();
+        let temp_gruu = registrations.issue_temp_gruu("joe", instance).unwrap();
+        registrations
+            .update("joe", "call-1", 2, vec![contact(instance, 3600)])
+            .unwrap();
+        assert!(registrations.binding_by_temp_gruu(&temp_gruu).is_some());
+    }
+
+    #[test]
+    fn temp_gruus_are_invalidated_by_new_call_id() {
+        let mut registrations = Registrations::new();
+        let instance = "urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6";
+        registrations
+            .update("joe", "call-1", 1, vec![contact(instance, 3600)])
+            .unwrap();
+        let temp_gruu = registrations.issue_temp_gruu("joe", instance).unwrap();
+        registrations
+            .update("joe", "call-2", 1, vec![contact(instance, 3600)])
+            .unwrap();
+        assert!(registrations.binding_by_temp_gruu(&temp_gruu).is_none());
+        assert!(registrations
+            .binding_by_instance_id("joe", instance)
+            .is_some());
+    }
+
+    /// A contact of a device without an instance ID, so it's matched by the URI
+    fn device(port: u16, expires: u32) -> ContactInfo {
+        let uri = Uri::new(
+            UriSchema::Sip,
+            Domain::Ipv4("192.168.0.50".parse().unwrap(), Some(port)),
+        );
+        let flow = Flow::new(Transport::Udp, ([192, 168, 0, 50], port).into());
+        ContactInfo::new(uri, ContactParams::new(), flow, expires)
+    }
+
+    fn ports(registrations: &Registrations) -> Vec<u16> {
+        let mut ports: Vec<_> = registrations
+            .user_flows("joe")
+            .iter()
+            .map(|flow| flow.addr().port())
+            .collect();
+        ports.sort_unstable();
+        ports
+    }
+
+    #[test]
+    fn out_of_order_requests_change_nothing() {
+        let mut registrations = Registrations::new();
+        registrations
+            .update("joe", "call-1", 5, vec![device(5060, 3600)])
+            .unwrap();
+        // A request the registrar has seen, or an older one, even if it adds another device
+        for cseq in [4, 5].iter() {
+            assert_eq!(
+                registrations.update(
+                    "joe",
+                    "call-1",
+                    *cseq,
+                    vec![device(5070, 3600), device(5060, 0)]
+                ),
+                Err(RegistrationError::OutOfOrder)
+            );
+            assert_eq!(
+                registrations.remove_all("joe", "call-1", *cseq),
+                Err(RegistrationError::OutOfOrder)
+            );
+        }
+        assert_eq!(ports(&registrations), vec![5060]);
+        // CSeq is compared only within a Call-ID
+        registrations
+            .update("joe", "call-2", 1, vec![device(5070, 3600)])
+            .unwrap();
+        assert_eq!(ports(&registrations), vec![5060, 5070]);
+        registrations
+            .update("joe", "call-1", 6, vec![device(5060, 0)])
+            .unwrap();
+        assert_eq!(ports(&registrations), vec![5070]);
+    }
+
+    #[test]
+    fn expired_bindings_are_gone() {
+        let mut registrations = Registrations::new();
+        registrations
+            .update(
+                "joe",
+                "call-1",
+                1,
+                vec![device(5060, 3600), device(5070, 3600)],
+            )
+            .unwrap();
+        registrations.0.get_mut("joe").unwrap()[0].expires_at = Instant::now();
+        assert_eq!(ports(&registrations), vec![5070]);
+        assert_eq!(registrations.all_bindings().count(), 1);
+        let expired = registrations.remove_expired_bindings("joe");
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].flow().addr().port(), 5060);
+
+        registrations.0.get_mut("joe").unwrap()[0].expires_at = Instant::now();
+        assert!(registrations.remove_expired());
+        assert!(registrations.0.is_empty());
+        assert!(!registrations.remove_expired());
+
+        registrations
+            .update("joe", "call-2", 1, vec![device(5060, 3600)])
+            .unwrap();
+        registrations.0.get_mut("joe").unwrap()[0].expires_at = Instant::now();
+        // An expired binding doesn't hold the CSeq of its Call-ID
+        registrations
+            .update("joe", "call-2", 1, vec![device(5060, 3600)])
+            .unwrap();
+        assert_eq!(ports(&registrations), vec![5060]);
     }
 }
@@ -0,0 +1,223 @@
+use async_std_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    proto::{
+        rr::{RData, RecordType},
+        xfer::DnsRequestOptions,
+    },
+    AsyncStdResolver,
+};
+use async_trait::async_trait;
+use log::debug;
+use std::{collections::HashMap, net::IpAddr};
+
+/// A NAPTR record as per https://tools.ietf.org/html/rfc3403#section-4.1
+#[derive(Clone, Debug, PartialEq)]
+pub struct NaptrRecord {
+    pub order: u16,
+    pub preference: u16,
+    pub flags: String,
+    /// For example, `SIP+D2U`
+    pub service: String,
+    pub replacement: String,
+}
+
+/// An SRV record as per https://tools.ietf.org/html/rfc2782
+#[derive(Clone, Debug, PartialEq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Looks up DNS records that [`ServerLocator`](struct.ServerLocator.html) needs.
+/// A failed lookup is reported as no records, since the locator falls back to the next step anyway
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn naptr(&self, domain: &str) -> Vec<NaptrRecord>;
+
+    /// Returns SRV records of `name` (for example, `_sip._udp.example.com`)
+    async fn srv(&self, name: &str) -> Vec<SrvRecord>;
+
+    /// Returns A and AAAA records of `host`
+    async fn ip(&self, host: &str) -> Vec<IpAddr>;
+}
+
+/// [`Resolver`](trait.Resolver.html) that asks DNS servers
+pub struct DnsResolver(AsyncStdResolver);
+
+impl DnsResolver {
+    pub fn new(resolver: AsyncStdResolver) -> Self {
+        Self(resolver)
+    }
+
+    /// Creates a resolver that uses the system configuration (`/etc/resolv.conf` on Unix)
+    pub async fn from_system_conf() -> crate::Result<Self> {
+        let resolver = async_std_resolver::resolver_from_system_conf().await?;
+        Ok(Self::new(resolver))
+    }
+
+    /// Creates a resolver that asks Google's public DNS servers, for hosts without a resolver configuration
+    pub async fn with_default_conf() -> crate::Result<Self> {
+        let resolver =
+            async_std_resolver::resolver(ResolverConfig::default(), ResolverOpts::default())
+                .await?;
+        Ok(Self::new(resolver))
+    }
+
+    /// Removes the trailing `.` of a fully qualified name, so that names compare equal to the ones in SIP URIs
+    fn name_to_string(name: &str) -> String {
+        name.trim_end_matches('.').to_string()
+    }
+}
+
+#[async_trait]
+impl Resolver for DnsResolver {
+    async fn naptr(&self, domain: &str) -> Vec<NaptrRecord> {
+        let lookup = self
+            .0
+            .lookup(domain, RecordType::NAPTR, DnsRequestOptions::default())
+            .await;
+        match lookup {
+            Ok(lookup) => lookup
+                .iter()
+                .filter_map(|rdata| match rdata {
+                    RData::NAPTR(naptr) => Some(NaptrRecord {
+                        order: naptr.order(),
+                        preference: naptr.preference(),
+                        flags: String::from_utf8_lossy(naptr.flags()).into_owned(),
+                        service: String::from_utf8_lossy(naptr.services()).into_owned(),
+                        replacement: Self::name_to_string(&naptr.replacement().to_string()),
+                    }),
+                    _ => None,
+                })
+                .collect(),
+            Err(e) => {
+                debug!("NAPTR lookup of {} failed: {}", domain, e);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn srv(&self, name: &str) -> Vec<SrvRecord> {
+        match self.0.srv_lookup(name).await {
+            Ok(lookup) => lookup
+                .iter()
+                .map(|srv| SrvRecord {
+                    priority: srv.priority(),
+                    weight: srv.weight(),
+                    port: srv.port(),
+                    target: Self::name_to_string(&srv.target().to_string()),
+                })
+                .collect(),
+            Err(e) => {
+                debug!("SRV lookup of {} failed: {}", name, e);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn ip(&self, host: &str) -> Vec<IpAddr> {
+        match self.0.lookup_ip(host).await {
+            Ok(lookup) => lookup.iter().collect(),
+            Err(e) => {
+                debug!("A/AAAA lookup of {} failed: {}", host, e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// [`Resolver`](trait.Resolver.html) that keeps records in memory, for tests and for hosts that must not be looked up in DNS.
+/// Names are case-insensitive
+/// # Examples
+/// ```
+/// use sip_server::{Resolver, StaticResolver};
+/// use std::net::IpAddr;
+///
+/// let ip: IpAddr = "192.0.2.1".parse().unwrap();
+/// let mut zone = StaticResolver::new();
+/// zone.add_srv("_sip._udp.example.com", 10, 60, 5060, "sip1.example.com");
+/// zone.add_ip("sip1.example.com", ip);
+///
+/// async_std::task::block_on(async {
+///     let records = zone.srv("_SIP._UDP.example.com").await;
+///     assert_eq!(records[0].target, "sip1.example.com");
+///     assert_eq!(zone.ip("sip1.example.com").await, vec![ip]);
+///     assert!(zone.naptr("example.com").await.is_empty());
+/// });
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+    naptr: HashMap<String, Vec<NaptrRecord>>,
+    srv: HashMap<String, Vec<SrvRecord>>,
+    ip: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_naptr(
+        &mut self,
+        domain: &str,
+        order: u16,
+        preference: u16,
+        flags: &str,
+        service: &str,
+        replacement: &str,
+    ) {
+        self.naptr
+            .entry(domain.to_ascii_lowercase())
+            .or_default()
+            .push(NaptrRecord {
+                order,
+                preference,
+                flags: flags.to_string(),
+                service: service.to_string(),
+                replacement: replacement.to_string(),
+            });
+    }
+
+    pub fn add_srv(&mut self, name: &str, priority: u16, weight: u16, port: u16, target: &str) {
+        self.srv
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(SrvRecord {
+                priority,
+                weight,
+                port,
+                target: target.to_string(),
+            });
+    }
+
+    pub fn add_ip(&mut self, host: &str, ip: IpAddr) {
+        self.ip
+            .entry(host.to_ascii_lowercase())
+            .or_default()
+            .push(ip);
+    }
+
+    fn get<T: Clone>(records: &HashMap<String, Vec<T>>, name: &str) -> Vec<T> {
+        records
+            .get(&name.to_ascii_lowercase())
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn naptr(&self, domain: &str) -> Vec<NaptrRecord> {
+        Self::get(&self.naptr, domain)
+    }
+
+    async fn srv(&self, name: &str) -> Vec<SrvRecord> {
+        Self::get(&self.srv, name)
+    }
+
+    async fn ip(&self, host: &str) -> Vec<IpAddr> {
+        Self::get(&self.ip, host)
+    }
+}
@@ -0,0 +1,189 @@
+use crate::sip_parse;
+use libsip::{Domain, Uri, UriParam};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+/// An entry of `Route` or `Record-Route` as per https://tools.ietf.org/html/rfc3261#section-20.34
+/// # Examples
+/// ```
+/// use libsip::{Domain, Uri, UriParam, UriSchema};
+/// use sip_server::Route;
+///
+/// let uri = Uri::new(UriSchema::Sip, Domain::Ipv4("10.0.0.1".parse().unwrap(), Some(5080)));
+/// let route = Route::new(uri.parameter(UriParam::Other("lr".to_string(), None)));
+///
+/// assert!(route.is_loose());
+/// assert_eq!(route.addr(), Some("10.0.0.1:5080".parse().unwrap()));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    uri: Uri,
+}
+
+impl Route {
+    pub fn new(uri: Uri) -> Self {
+        Self { uri }
+    }
+
+    /// Parses a single value (`<sip:proxy.example.com;lr>`). Header parameters after `>` are ignored
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let uri = match (value.find('<'), value.rfind('>')) {
+            (Some(start), Some(end)) if start < end => &value[start + 1..end],
+            _ => value,
+        };
+        sip_parse::parse_uri(uri).map(Self::new)
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Returns `true` if the URI has "lr", so the next hop is a loose router.
+    /// Otherwise, it's a strict router (RFC 2543) that expects to find the route in the Request-URI
+    pub fn is_loose(&self) -> bool {
+        self.uri.parameters.iter().any(|p| match p {
+            UriParam::Other(name, _) => name.eq_ignore_ascii_case("lr"),
+            _ => false,
+        })
+    }
+
+    /// Returns `true` if `host` is the server's address `own` (the default port is 5060) or one of the server's `domains`
+    pub fn is_local_host(host: &Domain, own: &Domain, domains: &[String]) -> bool {
+        match (host, own) {
+            (Domain::Ipv4(ip, port), Domain::Ipv4(own_ip, own_port)) if ip == own_ip => {
+                port.unwrap_or(5060) == own_port.unwrap_or(5060)
+            }
+            (Domain::Domain(name, _), _) => domains
+                .iter()
+                .any(|domain| domain.eq_ignore_ascii_case(name)),
+            _ => false,
+        }
+    }
+
+    /// Processes the Route set of a received request with the Request-URI `uri` as per https://tools.ietf.org/html/rfc3261#section-16.4
+    /// and returns the Route set that is left. The server is `own` and its `domains` (see [`is_local_host`](#method.is_local_host))
+    pub fn preprocess(
+        uri: &mut Uri,
+        mut routes: Vec<Route>,
+        own: &Domain,
+        domains: &[String],
+    ) -> Vec<Route> {
+        // The previous hop is a strict router, so the Request-URI is the server's Record-Route entry.
+        // The original Request-URI is the last entry of the Route set
+        if Self::is_local_host(&uri.host, own, domains) && uri.auth.is_none() {
+            if let Some(last) = routes.pop() {
+                *uri = last.uri;
+            }
+        }
+        // The server's own entry is removed
+        if matches!(routes.first(), Some(route) if Self::is_local_host(&route.uri.host, own, domains))
+        {
+            routes.remove(0);
+        }
+        routes
+    }
+
+    /// Returns the address the request is sent to or `None` if the host isn't an IP address
+    pub fn addr(&self) -> Option<SocketAddr> {
+        let (ip, port) = match &self.uri.host {
+            Domain::Ipv4(ip, port) => (IpAddr::V4(*ip), *port),
+            Domain::Domain(host, port) => (host.parse().ok()?, *port),
+        };
+        Some(SocketAddr::new(ip, port.unwrap_or(5060)))
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{}>", self.uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsip::{UriAuth, UriSchema};
+
+    fn own() -> Domain {
+        Domain::Ipv4("192.0.2.10".parse().unwrap(), Some(5060))
+    }
+
+    fn domains() -> Vec<String> {
+        vec!["example.com".to_string()]
+    }
+
+    fn server(port: Option<u16>) -> Uri {
+        Uri::new(
+            UriSchema::Sip,
+            Domain::Ipv4("192.0.2.10".parse().unwrap(), port),
+        )
+    }
+
+    fn host(name: &str) -> Uri {
+        Uri::new(UriSchema::Sip, Domain::Domain(name.to_string(), None))
+    }
+
+    fn bob() -> Uri {
+        host("example.org").auth(UriAuth::new("bob"))
+    }
+
+    fn loose(uri: Uri) -> Route {
+        Route::new(uri.parameter(UriParam::Other("lr".to_string(), None)))
+    }
+
+    #[test]
+    fn own_entry_without_port_is_removed() {
+        let mut uri = bob();
+        let routes = vec![loose(server(None)), loose(host("proxy.example.org"))];
+        let left = Route::preprocess(&mut uri, routes, &own(), &domains());
+        assert_eq!(left, vec![loose(host("proxy.example.org"))]);
+        assert_eq!(uri, bob());
+    }
+
+    #[test]
+    fn own_entry_with_domain_is_removed() {
+        let mut uri = bob();
+        let routes = vec![loose(host("EXAMPLE.com"))];
+        assert!(Route::preprocess(&mut uri, routes, &own(), &domains()).is_empty());
+    }
+
+    #[test]
+    fn strict_routed_request_uri_is_replaced() {
+        // The previous hop has put the server's entry (without a port) in the Request-URI
+        let mut uri = server(None);
+        let routes = vec![loose(host("proxy.example.org")), Route::new(bob())];
+        let left = Route::preprocess(&mut uri, routes, &own(), &domains());
+        assert_eq!(uri, bob());
+        assert_eq!(left, vec![loose(host("proxy.example.org"))]);
+
+        let mut uri = host("example.com");
+        let left = Route::preprocess(&mut uri, vec![Route::new(bob())], &own(), &domains());
+        assert_eq!(uri, bob());
+        assert!(left.is_empty());
+    }
+
+    #[test]
+    fn other_ports_and_hosts_are_not_local() {
+        let (own, domains) = (own(), domains());
+        assert!(Route::is_local_host(
+            &server(Some(5060)).host,
+            &own,
+            &domains
+        ));
+        assert!(!Route::is_local_host(
+            &server(Some(5070)).host,
+            &own,
+            &domains
+        ));
+        assert!(!Route::is_local_host(
+            &host("example.org").host,
+            &own,
+            &domains
+        ));
+        let other = Domain::Ipv4("192.0.2.11".parse().unwrap(), None);
+        assert!(!Route::is_local_host(&other, &own, &domains));
+    }
+}
@@ -0,0 +1,579 @@
+use std::{fmt, ops::Range, str::FromStr};
+
+/// The order of session-level lines as per https://tools.ietf.org/html/rfc4566#section-5
+const SESSION_ORDER: &str = "vosiuepcbtrzkam";
+/// The order of media-level lines
+const MEDIA_ORDER: &str = "micbka";
+
+/// The direction of media streams (https://tools.ietf.org/html/rfc4566#section-6)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Direction {
+    /// Returns the direction the other side of a stream with this direction has
+    pub fn reverse(self) -> Self {
+        match self {
+            Direction::SendOnly => Direction::RecvOnly,
+            Direction::RecvOnly => Direction::SendOnly,
+            direction => direction,
+        }
+    }
+
+    fn from_attribute(name: &str) -> Option<Self> {
+        match name {
+            "sendrecv" => Some(Direction::SendRecv),
+            "sendonly" => Some(Direction::SendOnly),
+            "recvonly" => Some(Direction::RecvOnly),
+            "inactive" => Some(Direction::Inactive),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::SendRecv => "sendrecv",
+            Direction::SendOnly => "sendonly",
+            Direction::RecvOnly => "recvonly",
+            Direction::Inactive => "inactive",
+        }
+    }
+}
+
+/// `o=<username> <sess-id> <sess-version> <nettype> <addrtype> <unicast-address>`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    pub username: String,
+    pub session_id: String,
+    pub session_version: u64,
+    pub net_type: String,
+    pub addr_type: String,
+    pub address: String,
+}
+
+impl FromStr for Origin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid origin: {}", s);
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 6 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            username: fields[0].to_string(),
+            session_id: fields[1].to_string(),
+            session_version: fields[2].parse().map_err(|_| invalid())?,
+            net_type: fields[3].to_string(),
+            addr_type: fields[4].to_string(),
+            address: fields[5].to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {}",
+            self.username,
+            self.session_id,
+            self.session_version,
+            self.net_type,
+            self.addr_type,
+            self.address
+        )
+    }
+}
+
+/// `c=<nettype> <addrtype> <connection-address>`. A multicast address keeps its TTL and number of addresses (`/127/3`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Connection {
+    pub net_type: String,
+    pub addr_type: String,
+    pub address: String,
+}
+
+impl FromStr for Connection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(format!("invalid connection: {}", s));
+        }
+        Ok(Self {
+            net_type: fields[0].to_string(),
+            addr_type: fields[1].to_string(),
+            address: fields[2].to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.net_type, self.addr_type, self.address)
+    }
+}
+
+/// `a=<name>` or `a=<name>:<value>`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attribute {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl Attribute {
+    pub fn new(name: String, value: Option<String>) -> Self {
+        Self { name, value }
+    }
+
+    fn parse(s: &str) -> Self {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or_default().to_string();
+        Self::new(name, parts.next().map(str::to_string))
+    }
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}:{}", self.name, value),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// A media description: `m=<media> <port>[/<number of ports>] <proto> <fmt> ...` with the lines that follow it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Media {
+    pub media: String,
+    pub port: u16,
+    pub port_count: Option<u16>,
+    pub protocol: String,
+    pub formats: Vec<String>,
+    pub connection: Option<Connection>,
+    /// The direction attribute of the media. The session's one applies if it isn't set
+    pub direction: Option<Direction>,
+    /// Attributes except the direction
+    pub attributes: Vec<Attribute>,
+    /// Other lines (`i=`, `b=`, `k=`) with their types
+    pub other: Vec<(char, String)>,
+}
+
+impl Media {
+    /// Returns the value of the first attribute `name`
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        find_attribute(&self.attributes, name)
+    }
+}
+
+impl FromStr for Media {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid media: {}", s);
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() < 3 {
+            return Err(invalid());
+        }
+        let mut ports = fields[1].splitn(2, '/');
+        let port = ports
+            .next()
+            .and_then(|port| port.parse().ok())
+            .ok_or_else(invalid)?;
+        let port_count = match ports.next() {
+            Some(count) => Some(count.parse().map_err(|_| invalid())?),
+            None => None,
+        };
+        Ok(Self {
+            media: fields[0].to_string(),
+            port,
+            port_count,
+            protocol: fields[2].to_string(),
+            formats: fields[3..].iter().map(|f| f.to_string()).collect(),
+            connection: None,
+            direction: None,
+            attributes: Vec::new(),
+            other: Vec::new(),
+        })
+    }
+}
+
+impl fmt::Display for Media {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "m={} {}", self.media, self.port)?;
+        if let Some(port_count) = self.port_count {
+            write!(f, "/{}", port_count)?;
+        }
+        write!(f, " {}", self.protocol)?;
+        for format in self.formats.iter() {
+            write!(f, " {}", format)?;
+        }
+        write!(f, "\r\n")?;
+        write_lines(f, &self.other, MEDIA_ORDER, 'i', Some('i'))?;
+        if let Some(connection) = &self.connection {
+            write!(f, "c={}\r\n", connection)?;
+        }
+        write_lines(f, &self.other, MEDIA_ORDER, 'b', None)?;
+        write_attributes(f, &self.attributes, self.direction)
+    }
+}
+
+/// A session description as per https://tools.ietf.org/html/rfc4566. Lines the model doesn't cover are kept as they are,
+/// so a parsed description is written back in the order RFC 4566 defines
+/// # Examples
+/// ```
+/// use sip_server::{Direction, SessionDescription};
+///
+/// let offer = "v=0\r\n\
+///     o=alice 2890844526 2890844526 IN IP4 10.0.0.5\r\n\
+///     s=-\r\n\
+///     c=IN IP4 10.0.0.5\r\n\
+///     t=0 0\r\n\
+///     m=audio 49170 RTP/AVP 0 8\r\n\
+///     a=rtpmap:0 PCMU/8000\r\n\
+///     a=sendonly\r\n";
+/// let mut sdp: SessionDescription = offer.parse().unwrap();
+/// assert_eq!(sdp.origin.address, "10.0.0.5");
+/// assert_eq!(sdp.media[0].port, 49170);
+/// assert_eq!(sdp.media[0].formats, vec!["0", "8"]);
+/// assert_eq!(sdp.media[0].attribute("rtpmap"), Some("0 PCMU/8000"));
+/// assert_eq!(sdp.direction(&sdp.media[0]), Direction::SendOnly);
+/// assert_eq!(sdp.to_string(), offer);
+///
+/// sdp.connection.as_mut().unwrap().address = "203.0.113.1".to_string();
+/// sdp.media[0].port = 30000;
+/// assert!(sdp.to_string().contains("c=IN IP4 203.0.113.1\r\nt=0 0\r\nm=audio 30000 RTP/AVP 0 8\r\n"));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionDescription {
+    pub version: u32,
+    pub origin: Origin,
+    pub session_name: String,
+    pub connection: Option<Connection>,
+    /// The direction attribute of the session. It applies to the media that don't have their own
+    pub direction: Option<Direction>,
+    /// Session-level attributes except the direction
+    pub attributes: Vec<Attribute>,
+    pub media: Vec<Media>,
+    /// Other session-level lines (`i=`, `u=`, `e=`, `p=`, `b=`, `t=`, `r=`, `z=`, `k=`) with their types
+    pub other: Vec<(char, String)>,
+}
+
+impl SessionDescription {
+    /// Returns the connection of `media`: its own or the session's one
+    pub fn connection<'a>(&'a self, media: &'a Media) -> Option<&'a Connection> {
+        media.connection.as_ref().or(self.connection.as_ref())
+    }
+
+    /// Returns the direction of `media`: its own, the session's one or `sendrecv` (the default)
+    pub fn direction(&self, media: &Media) -> Direction {
+        media
+            .direction
+            .or(self.direction)
+            .unwrap_or(Direction::SendRecv)
+    }
+
+    /// Returns the value of the first session-level attribute `name`
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        find_attribute(&self.attributes, name)
+    }
+}
+
+impl SessionDescription {
+    /// Returns where the session description is in `body` of a message with `content_type`: the whole body of `application/sdp`
+    /// or the `application/sdp` part of a `multipart` body (RFC 2046 5.1). Returns `None` if the body has no session description
+    pub fn locate(content_type: &str, body: &[u8]) -> Option<Range<usize>> {
+        let mut params = content_type.split(';');
+        let media_type = params.next()?.trim().to_ascii_lowercase();
+        if media_type == "application/sdp" {
+            return Some(0..body.len());
+        }
+        if !media_type.starts_with("multipart/") {
+            return None;
+        }
+        let boundary = params.find_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            match (parts.next()?.trim(), parts.next()) {
+                (name, Some(value)) if name.eq_ignore_ascii_case("boundary") => {
+                    Some(value.trim().trim_matches('"'))
+                }
+                _ => None,
+            }
+        })?;
+        let delimiter = format!("--{}", boundary).into_bytes();
+        let mut start = find(body, &delimiter, 0)? + delimiter.len();
+        // A part is the rest of the delimiter line, its headers, an empty line and its content up to the line break before the next delimiter.
+        // The close delimiter has "--" after the boundary
+        while !body[start..].starts_with(b"--") {
+            let next = find(body, &delimiter, start)?;
+            let content_start = find(body, b"\r\n\r\n", start)? + 4;
+            let content_end = if body[..next].ends_with(b"\r\n") {
+                next - 2
+            } else {
+                next
+            };
+            if content_start <= content_end {
+                let headers = String::from_utf8_lossy(&body[start..content_start]);
+                let is_sdp = headers.lines().any(|line| {
+                    let mut parts = line.splitn(2, ':');
+                    match (parts.next(), parts.next()) {
+                        (Some(name), Some(value)) => {
+                            let name = name.trim();
+                            (name.eq_ignore_ascii_case("content-type")
+                                || name.eq_ignore_ascii_case("c"))
+                                && value
+                                    .split(';')
+                                    .next()
+                                    .unwrap_or_default()
+                                    .trim()
+                                    .eq_ignore_ascii_case("application/sdp")
+                        }
+                        _ => false,
+                    }
+                });
+                if is_sdp {
+                    return Some(content_start..content_end);
+                }
+            }
+            start = next + delimiter.len();
+        }
+        None
+    }
+}
+
+impl FromStr for SessionDescription {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut version, mut origin, mut session_name) = (None, None, None);
+        let mut connection = None;
+        let mut direction = None;
+        let mut attributes = Vec::new();
+        let mut media: Vec<Media> = Vec::new();
+        let mut other = Vec::new();
+        for line in s.lines().map(|line| line.trim_end_matches('\r')) {
+            if line.is_empty() {
+                continue;
+            }
+            // The type is a single letter, so the value starts at a character boundary
+            let (t, value) = match line.as_bytes() {
+                [t, b'=', ..] if t.is_ascii_alphabetic() => (*t as char, &line[2..]),
+                _ => return Err(format!("invalid line: {}", line)),
+            };
+            match (t, media.last_mut()) {
+                ('m', _) => media.push(value.parse()?),
+                ('c', Some(m)) => m.connection = Some(value.parse()?),
+                ('a', Some(m)) => match Direction::from_attribute(value) {
+                    Some(d) => m.direction = Some(d),
+                    None => m.attributes.push(Attribute::parse(value)),
+                },
+                (t, Some(m)) => m.other.push((t, value.to_string())),
+                ('v', None) => {
+                    version = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid version: {}", value))?,
+                    )
+                }
+                ('o', None) => origin = Some(value.parse()?),
+                ('s', None) => session_name = Some(value.to_string()),
+                ('c', None) => connection = Some(value.parse()?),
+                ('a', None) => match Direction::from_attribute(value) {
+                    Some(d) => direction = Some(d),
+                    None => attributes.push(Attribute::parse(value)),
+                },
+                (t, None) => other.push((t, value.to_string())),
+            }
+        }
+        Ok(Self {
+            version: version.ok_or("no version")?,
+            origin: origin.ok_or("no origin")?,
+            session_name: session_name.ok_or("no session name")?,
+            connection,
+            direction,
+            attributes,
+            media,
+            other,
+        })
+    }
+}
+
+impl fmt::Display for SessionDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v={}\r\n", self.version)?;
+        write!(f, "o={}\r\n", self.origin)?;
+        write!(f, "s={}\r\n", self.session_name)?;
+        write_lines(f, &self.other, SESSION_ORDER, 'i', Some('p'))?;
+        if let Some(connection) = &self.connection {
+            write!(f, "c={}\r\n", connection)?;
+        }
+        write_lines(f, &self.other, SESSION_ORDER, 'b', None)?;
+        write_attributes(f, &self.attributes, self.direction)?;
+        for media in self.media.iter() {
+            write!(f, "{}", media)?;
+        }
+        Ok(())
+    }
+}
+
+fn find_attribute<'a>(attributes: &'a [Attribute], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|a| a.name == name)
+        .map(|a| a.value.as_deref().unwrap_or(""))
+}
+
+/// Returns the position of `needle` in `haystack` at or after `from`
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| position + from)
+}
+
+/// Writes the lines whose types are from `first` to `last` (to the end if it's `None`) in `order`.
+/// Lines of the same type keep their order, and lines of unknown types go last.
+/// `r=` lines belong to the `t=` line before them (a time description), so they keep their places among `t=` lines
+fn write_lines(
+    f: &mut fmt::Formatter,
+    lines: &[(char, String)],
+    order: &str,
+    first: char,
+    last: Option<char>,
+) -> fmt::Result {
+    let rank = |t: char| {
+        let t = if t == 'r' { 't' } else { t };
+        order.find(t).unwrap_or(order.len())
+    };
+    let first = rank(first);
+    let last = last.map(rank).unwrap_or(order.len());
+    let mut selected: Vec<_> = lines
+        .iter()
+        .filter(|(t, _)| (first..=last).contains(&rank(*t)))
+        .collect();
+    selected.sort_by_key(|(t, _)| rank(*t));
+    for (t, value) in selected {
+        write!(f, "{}={}\r\n", t, value)?;
+    }
+    Ok(())
+}
+
+fn write_attributes(
+    f: &mut fmt::Formatter,
+    attributes: &[Attribute],
+    direction: Option<Direction>,
+) -> fmt::Result {
+    for attribute in attributes.iter() {
+        write!(f, "a={}\r\n", attribute)?;
+    }
+    if let Some(direction) = direction {
+        write!(f, "a={}\r\n", direction.as_str())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(sdp: &str) {
+        let parsed: SessionDescription = sdp.parse().unwrap();
+        assert_eq!(parsed.to_string(), sdp);
+    }
+
+    #[test]
+    fn descriptions_are_written_back_as_they_are() {
+        round_trip(
+            "v=0\r\n\
+            o=- 1 2 IN IP6 2001:db8::1\r\n\
+            s=Call\r\n\
+            i=A call\r\n\
+            u=http://example.com/\r\n\
+            e=alice@example.com\r\n\
+            c=IN IP6 2001:db8::1\r\n\
+            b=AS:64\r\n\
+            t=2873397496 2873404696\r\n\
+            r=7d 1h 0 25h\r\n\
+            t=2873404696 0\r\n\
+            r=1d 1h 0\r\n\
+            z=2882844526 -1h\r\n\
+            a=group:BUNDLE audio video\r\n\
+            a=recvonly\r\n\
+            m=audio 49170/2 RTP/AVP 0 101\r\n\
+            i=Voice\r\n\
+            c=IN IP6 2001:db8::2\r\n\
+            b=AS:32\r\n\
+            a=rtpmap:101 telephone-event/8000\r\n\
+            a=fmtp:101 0-15\r\n\
+            a=inactive\r\n\
+            m=video 0 RTP/AVP 31\r\n",
+        );
+    }
+
+    #[test]
+    fn malformed_descriptions_are_rejected() {
+        for sdp in [
+            "v=0\r\ns=-\r\nt=0 0\r\n",
+            "o=- 1 1 IN IP4 10.0.0.1\r\ns=-\r\n",
+            "v=x\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=-\r\n",
+            "v=0\r\no=- 1 1 IN IP4\r\ns=-\r\n",
+            "v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=-\r\nc=IN IP4\r\n",
+            "v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=-\r\nm=audio x RTP/AVP 0\r\n",
+            "v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=-\r\nm=audio 1/x RTP/AVP 0\r\n",
+            "v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=-\r\nno line type\r\n",
+            // Multi-byte characters where the type is expected
+            "v=0\r\n\u{20ac}=0\r\n",
+            "\u{e9}=0\r\n",
+        ]
+        .iter()
+        {
+            assert!(sdp.parse::<SessionDescription>().is_err(), "{}", sdp);
+        }
+    }
+
+    #[test]
+    fn sdp_is_located_by_content_type() {
+        let sdp = b"v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=-\r\n";
+        assert_eq!(
+            SessionDescription::locate("application/sdp", sdp),
+            Some(0..sdp.len())
+        );
+        assert_eq!(SessionDescription::locate("text/plain", sdp), None);
+
+        let body = b"--unique-boundary\r\n\
+            Content-Type: application/resource-lists+xml\r\n\
+            \r\n\
+            <resource-lists/>\r\n\
+            --unique-boundary\r\n\
+            Content-Type: Application/SDP; charset=utf-8\r\n\
+            \r\n\
+            v=0\r\n\
+            o=- 1 1 IN IP4 10.0.0.1\r\n\
+            s=-\r\n\
+            \r\n\
+            --unique-boundary--\r\n";
+        let range =
+            SessionDescription::locate("multipart/mixed; boundary=\"unique-boundary\"", body)
+                .unwrap();
+        assert_eq!(&body[range], &sdp[..]);
+
+        assert_eq!(
+            SessionDescription::locate("multipart/mixed; boundary=other", body),
+            None
+        );
+        assert_eq!(
+            SessionDescription::locate(
+                "multipart/mixed; boundary=unique-boundary",
+                b"--unique-boundary\r\n"
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,323 @@
+use super::{Flow, Resolver, SrvRecord};
+use libsip::{Domain, Transport, Uri, UriParam, UriSchema};
+use log::debug;
+use rand::Rng;
+use std::net::{IpAddr, SocketAddr};
+
+/// The transports the server supports with their NAPTR services and SRV prefixes
+const TRANSPORTS: [(Transport, &str, &str); 2] = [
+    (Transport::Udp, "SIP+D2U", "_sip._udp"),
+    (Transport::Tcp, "SIP+D2T", "_sip._tcp"),
+];
+
+const DEFAULT_PORT: u16 = 5060;
+
+/// Finds the servers a request to a SIP URI is sent to as per https://tools.ietf.org/html/rfc3263#section-4.
+/// The transport is taken from the URI or chosen with NAPTR records, the servers are found with SRV and A/AAAA records.
+/// Only UDP and TCP are supported, so SIPS URIs can't be located
+/// # Examples
+/// ```
+/// use libsip::{Domain, Transport, Uri, UriSchema};
+/// use sip_server::{Flow, ServerLocator, StaticResolver};
+///
+/// let mut zone = StaticResolver::new();
+/// zone.add_naptr("partner.example", 10, 50, "s", "SIP+D2T", "_sip._tcp.partner.example");
+/// zone.add_srv("_sip._tcp.partner.example", 0, 0, 5070, "sip1.partner.example");
+/// zone.add_srv("_sip._tcp.partner.example", 1, 0, 5070, "sip2.partner.example");
+/// zone.add_ip("sip1.partner.example", "192.0.2.1".parse().unwrap());
+/// zone.add_ip("sip2.partner.example", "192.0.2.2".parse().unwrap());
+/// let locator = ServerLocator::new(Box::new(zone));
+///
+/// let uri = Uri::new(UriSchema::Sip, Domain::Domain("partner.example".to_string(), None));
+/// let flows = async_std::task::block_on(locator.locate(&uri));
+///
+/// // The server with the lower priority comes first, the other one is for failover
+/// assert_eq!(
+///     flows,
+///     vec![
+///         Flow::new(Transport::Tcp, "192.0.2.1:5070".parse().unwrap()),
+///         Flow::new(Transport::Tcp, "192.0.2.2:5070".parse().unwrap()),
+///     ]
+/// );
+/// ```
+pub struct ServerLocator {
+    resolver: Box<dyn Resolver>,
+}
+
+impl ServerLocator {
+    pub fn new(resolver: Box<dyn Resolver>) -> Self {
+        Self { resolver }
+    }
+
+    /// Returns the flows to try in order: if sending to a flow fails, the request is sent to the next one
+    pub async fn locate(&self, uri: &Uri) -> Vec<Flow> {
+        if let Some(UriSchema::Sips) = uri.schema {
+            debug!("{} can't be located: TLS isn't supported", uri);
+            return Vec::new();
+        }
+        let transport = uri.parameters.iter().find_map(|p| match p {
+            UriParam::Transport(transport) => Some(*transport),
+            _ => None,
+        });
+        if let Some(transport) = transport {
+            if Self::srv_prefix(transport).is_none() {
+                debug!("{} can't be located: {:?} isn't supported", uri, transport);
+                return Vec::new();
+            }
+        }
+        let (host, port) = match &uri.host {
+            Domain::Ipv4(ip, port) => (ip.to_string(), *port),
+            Domain::Domain(host, port) => (host.clone(), *port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        // A numeric address or an explicit port means that no NAPTR and SRV lookups are done
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            let addr = SocketAddr::new(ip, port.unwrap_or(DEFAULT_PORT));
+            return vec![Flow::new(transport.unwrap_or(Transport::Udp), addr)];
+        }
+        if let Some(port) = port {
+            return self
+                .host_flows(host, port, transport.unwrap_or(Transport::Udp))
+                .await;
+        }
+        let mut flows = match transport {
+            Some(transport) => {
+                let name = format!("{}.{}", Self::srv_prefix(transport).unwrap(), host);
+                self.srv_flows(&name, transport).await
+            }
+            None => self.naptr_flows(host).await,
+        };
+        if flows.is_empty() {
+            flows = self
+                .host_flows(host, DEFAULT_PORT, transport.unwrap_or(Transport::Udp))
+                .await;
+        }
+        flows
+    }
+
+    /// Chooses transports with NAPTR records of `domain` or, if there are none, with SRV records of each supported transport
+    async fn naptr_flows(&self, domain: &str) -> Vec<Flow> {
+        let mut records: Vec<_> = self
+            .resolver
+            .naptr(domain)
+            .await
+            .into_iter()
+            .filter(|r| r.flags.eq_ignore_ascii_case("s"))
+            .filter_map(|r| {
+                let (transport, _, _) = TRANSPORTS
+                    .iter()
+                    .find(|(_, service, _)| r.service.eq_ignore_ascii_case(service))?;
+                Some((*transport, r))
+            })
+            .collect();
+        records.sort_by_key(|(_, r)| (r.order, r.preference));
+        let mut flows = Vec::new();
+        for (transport, record) in records {
+            flows.extend(self.srv_flows(&record.replacement, transport).await);
+        }
+        if flows.is_empty() {
+            for (transport, _, prefix) in TRANSPORTS.iter() {
+                let name = format!("{}.{}", prefix, domain);
+                flows.extend(self.srv_flows(&name, *transport).await);
+            }
+        }
+        Self::dedup(flows)
+    }
+
+    async fn srv_flows(&self, name: &str, transport: Transport) -> Vec<Flow> {
+        let records = Self::order_srv(self.resolver.srv(name).await);
+        let mut flows = Vec::new();
+        for record in records {
+            // "." means that the service isn't available at the domain
+            if record.target.is_empty() || record.target == "." {
+                continue;
+            }
+            flows.extend(
+                self.host_flows(&record.target, record.port, transport)
+                    .await,
+            );
+        }
+        flows
+    }
+
+    async fn host_flows(&self, host: &str, port: u16, transport: Transport) -> Vec<Flow> {
+        self.resolver
+            .ip(host)
+            .await
+            .into_iter()
+            .map(|ip| Flow::new(transport, SocketAddr::new(ip, port)))
+            .collect()
+    }
+
+    /// Orders SRV records by priority and, within the same priority, randomly in proportion to their weights
+    /// as per https://tools.ietf.org/html/rfc2782
+    fn order_srv(mut records: Vec<SrvRecord>) -> Vec<SrvRecord> {
+        // Records of weight 0 go first, so they have a small chance to be selected
+        records.sort_by_key(|r| (r.priority, r.weight != 0));
+        let mut rng = rand::thread_rng();
+        let mut ordered = Vec::with_capacity(records.len());
+        while !records.is_empty() {
+            let priority = records[0].priority;
+            let group_len = records
+                .iter()
+                .take_while(|r| r.priority == priority)
+                .count();
+            let mut group: Vec<_> = records.drain(..group_len).collect();
+            while !group.is_empty() {
+                let total: u32 = group.iter().map(|r| r.weight as u32).sum();
+                let selected = rng.gen_range(0, total + 1);
+                let mut running = 0;
+                let index = group
+                    .iter()
+                    .position(|r| {
+                        running += r.weight as u32;
+                        running >= selected
+                    })
+                    .unwrap_or(0);
+                ordered.push(group.remove(index));
+            }
+        }
+        ordered
+    }
+
+    fn srv_prefix(transport: Transport) -> Option<&'static str> {
+        TRANSPORTS
+            .iter()
+            .find(|(t, _, _)| *t == transport)
+            .map(|(_, _, prefix)| *prefix)
+    }
+
+    fn dedup(flows: Vec<Flow>) -> Vec<Flow> {
+        let mut unique = Vec::with_capacity(flows.len());
+        for flow in flows {
+            if !unique.contains(&flow) {
+                unique.push(flow);
+            }
+        }
+        unique
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StaticResolver;
+    use async_std::task;
+
+    fn domain_uri(host: &str) -> Uri {
+        Uri::new(UriSchema::Sip, Domain::Domain(host.to_string(), None))
+    }
+
+    fn flow(transport: Transport, addr: &str) -> Flow {
+        Flow::new(transport, addr.parse().unwrap())
+    }
+
+    fn srv(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port: 5060,
+            target: target.to_string(),
+        }
+    }
+
+    #[test]
+    fn srv_records_are_tried_by_priority() {
+        for _ in 0..20 {
+            let records = vec![
+                srv(20, 10, "c"),
+                srv(10, 0, "b"),
+                srv(10, 60, "a"),
+                srv(30, 0, "d"),
+            ];
+            let ordered = ServerLocator::order_srv(records);
+            let priorities: Vec<_> = ordered.iter().map(|r| r.priority).collect();
+            assert_eq!(priorities, vec![10, 10, 20, 30]);
+        }
+    }
+
+    #[test]
+    fn heavier_srv_record_usually_goes_first() {
+        let first_a = (0..200)
+            .filter(|_| {
+                let ordered = ServerLocator::order_srv(vec![srv(0, 1, "b"), srv(0, 99, "a")]);
+                ordered[0].target == "a"
+            })
+            .count();
+        assert!(first_a > 150);
+    }
+
+    #[test]
+    fn naptr_order_decides_transport_order() {
+        let mut zone = StaticResolver::new();
+        zone.add_naptr(
+            "example.com",
+            20,
+            10,
+            "s",
+            "SIP+D2U",
+            "_sip._udp.example.com",
+        );
+        zone.add_naptr(
+            "example.com",
+            10,
+            10,
+            "s",
+            "SIP+D2T",
+            "_sip._tcp.example.com",
+        );
+        // Not supported, so it's skipped
+        zone.add_naptr(
+            "example.com",
+            5,
+            10,
+            "s",
+            "SIPS+D2T",
+            "_sips._tcp.example.com",
+        );
+        zone.add_srv("_sip._udp.example.com", 0, 0, 5060, "sip.example.com");
+        zone.add_srv("_sip._tcp.example.com", 0, 0, 5060, "sip.example.com");
+        zone.add_srv("_sips._tcp.example.com", 0, 0, 5061, "sip.example.com");
+        zone.add_ip("sip.example.com", "192.0.2.1".parse().unwrap());
+        let locator = ServerLocator::new(Box::new(zone));
+        assert_eq!(
+            task::block_on(locator.locate(&domain_uri("example.com"))),
+            vec![
+                flow(Transport::Tcp, "192.0.2.1:5060"),
+                flow(Transport::Udp, "192.0.2.1:5060"),
+            ]
+        );
+    }
+
+    #[test]
+    fn every_address_of_server_is_tried_before_next_server() {
+        let mut zone = StaticResolver::new();
+        zone.add_srv("_sip._udp.example.com", 1, 0, 5060, "backup.example.com");
+        zone.add_srv("_sip._udp.example.com", 0, 0, 5060, "main.example.com");
+        // The service isn't offered over TCP
+        zone.add_srv("_sip._tcp.example.com", 0, 0, 0, ".");
+        zone.add_ip("main.example.com", "192.0.2.1".parse().unwrap());
+        zone.add_ip("main.example.com", "2001:db8::1".parse().unwrap());
+        zone.add_ip("backup.example.com", "192.0.2.2".parse().unwrap());
+        let locator = ServerLocator::new(Box::new(zone));
+        assert_eq!(
+            task::block_on(locator.locate(&domain_uri("example.com"))),
+            vec![
+                flow(Transport::Udp, "192.0.2.1:5060"),
+                flow(Transport::Udp, "[2001:db8::1]:5060"),
+                flow(Transport::Udp, "192.0.2.2:5060"),
+            ]
+        );
+    }
+
+    #[test]
+    fn host_is_used_without_srv_records() {
+        let mut zone = StaticResolver::new();
+        zone.add_ip("example.com", "192.0.2.1".parse().unwrap());
+        let locator = ServerLocator::new(Box::new(zone));
+        assert_eq!(
+            task::block_on(locator.locate(&domain_uri("example.com"))),
+            vec![flow(Transport::Udp, "192.0.2.1:5060")]
+        );
+    }
+}
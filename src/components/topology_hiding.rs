@@ -0,0 +1,202 @@
+use libsip::{Domain, Header, SipMessage, UriParam};
+
+/// Headers the server maintains itself when a message crosses legs, so they are never removed
+const MANAGED_HEADERS: [&str; 9] = [
+    "via",
+    "from",
+    "to",
+    "call-id",
+    "cseq",
+    "contact",
+    "max-forwards",
+    "content-length",
+    "route",
+];
+
+/// Headers passed through by default. They describe the session and the capabilities of the UAs, not the network.
+/// Refer-To and Referred-By carry contacts of the leg, so they have to be allowed explicitly
+const DEFAULT_ALLOWED_HEADERS: [&str; 24] = [
+    "accept",
+    "accept-encoding",
+    "accept-language",
+    "allow",
+    "allow-events",
+    "content-disposition",
+    "content-encoding",
+    "content-language",
+    "content-type",
+    "event",
+    "expires",
+    "min-expires",
+    "min-se",
+    "priority",
+    "rack",
+    "reason",
+    "replaces",
+    "require",
+    "retry-after",
+    "rseq",
+    "session-expires",
+    "subscription-state",
+    "supported",
+    "unsupported",
+];
+
+/// Hides the network of one leg of the B2BUA from the other one. When a message crosses legs,
+/// only the headers the server maintains and the allowed ones are kept. The hosts of `From` and `To` URIs are replaced with the server's host,
+/// and the URI parameters that can carry addresses (`maddr`, `received` and so on) are dropped, so only `user` is left.
+/// The B2BUA replaces the Via headers of a request with its own one anyway (see [`HiddenVias`](struct.HiddenVias.html))
+/// # Examples
+/// ```
+/// use sip_server::TopologyHiding;
+///
+/// let hiding = TopologyHiding::new();
+/// assert!(hiding.is_allowed("Content-Type"));
+/// assert!(hiding.is_allowed("Via"));
+/// assert!(!hiding.is_allowed("User-Agent"));
+/// assert!(!hiding.is_allowed("Record-Route"));
+/// assert!(!hiding.is_allowed("Refer-To"));
+///
+/// let hiding = TopologyHiding::new().allowed_headers(vec!["User-Agent".to_string()]);
+/// assert!(hiding.is_allowed("user-agent"));
+/// assert!(!hiding.is_allowed("Content-Type"));
+/// ```
+#[derive(Debug)]
+pub struct TopologyHiding {
+    /// Lower-case names of the headers passed through
+    allowed: Vec<String>,
+}
+
+impl Default for TopologyHiding {
+    fn default() -> Self {
+        Self {
+            allowed: DEFAULT_ALLOWED_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl TopologyHiding {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the default allow-list with `headers`
+    pub fn allowed_headers(mut self, headers: Vec<String>) -> Self {
+        self.allowed = headers
+            .into_iter()
+            .map(|name| name.trim().to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Returns `true` if the header `name` is kept when a message crosses legs
+    pub fn is_allowed(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        MANAGED_HEADERS.contains(&name.as_str()) || self.allowed.contains(&name)
+    }
+
+    /// Hides the leg `msg` comes from before the message is sent to the other leg
+    pub fn hide(&self, msg: &mut SipMessage, host: &Domain) {
+        msg.headers_mut()
+            .0
+            .retain(|h| self.is_allowed(&Self::header_name(h)));
+        for h in msg.headers_mut().0.iter_mut() {
+            match h {
+                // A host name can be internal as well as an IP address
+                Header::From(named) | Header::To(named) => {
+                    named.uri.host = host.clone();
+                    named.uri.parameters.retain(|p| match p {
+                        UriParam::Other(name, _) => name.eq_ignore_ascii_case("user"),
+                        _ => false,
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn header_name(h: &Header) -> String {
+        match h {
+            Header::Other(name, _) => name.clone(),
+            // A header is written as `Name: value`
+            h => h
+                .to_string()
+                .split(':')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsip::{Method, NamedHeader, RequestGenerator, Uri, UriAuth, UriSchema};
+
+    #[test]
+    fn from_and_to_hosts_are_hidden() {
+        let from = Uri::new(
+            UriSchema::Sip,
+            Domain::Domain("pbx.internal".to_string(), Some(5080)),
+        )
+        .auth(UriAuth::new("alice"))
+        .parameter(UriParam::Other(
+            "maddr".to_string(),
+            Some("10.0.0.7".to_string()),
+        ))
+        .parameter(UriParam::Received(Domain::Ipv4(
+            "10.0.0.7".parse().unwrap(),
+            None,
+        )));
+        let to = Uri::new(
+            UriSchema::Sip,
+            Domain::Ipv4("10.0.0.8".parse().unwrap(), None),
+        )
+        .auth(UriAuth::new("+15550100"))
+        .parameter(UriParam::Other(
+            "user".to_string(),
+            Some("phone".to_string()),
+        ));
+        let mut from = NamedHeader::new(from);
+        from.parameters
+            .insert("tag".to_string(), Some("a1".to_string()));
+        let mut msg = RequestGenerator::new()
+            .method(Method::Invite)
+            .uri(to.clone())
+            .header(Header::From(from))
+            .header(Header::To(NamedHeader::new(to)))
+            .header(Header::Other(
+                "Refer-To".to_string(),
+                "<sip:bob@10.0.0.9>".to_string(),
+            ))
+            .build()
+            .unwrap();
+        let host = Domain::Domain("sip.example.com".to_string(), None);
+        TopologyHiding::new().hide(&mut msg, &host);
+
+        let mut named = msg.headers().0.iter().filter_map(|h| match h {
+            Header::From(named) | Header::To(named) => Some(named),
+            _ => None,
+        });
+        let from = named.next().unwrap();
+        assert_eq!(from.uri.host, host);
+        assert!(from.uri.parameters.is_empty());
+        assert_eq!(from.uri.auth, Some(UriAuth::new("alice")));
+        assert_eq!(from.parameters.get("tag"), Some(&Some("a1".to_string())));
+        let to = named.next().unwrap();
+        assert_eq!(to.uri.host, host);
+        assert_eq!(
+            to.uri.parameters,
+            vec![UriParam::Other(
+                "user".to_string(),
+                Some("phone".to_string())
+            )]
+        );
+        assert_eq!(msg.headers().0.len(), 2);
+    }
+}
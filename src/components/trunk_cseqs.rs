@@ -0,0 +1,111 @@
+use libsip::Method;
+use std::collections::HashMap;
+
+/// The CSeq space of the leg of a call that goes out through a trunk.
+/// The server resends requests the trunk challenges with CSeq numbers the caller doesn't know about,
+/// so the caller's numbers are mapped to the leg's ones and back
+#[derive(Debug, Default)]
+pub struct TrunkCSeqs {
+    /// The last CSeq number the server has used on the trunk leg
+    last: u32,
+    /// The caller's CSeq numbers by the trunk leg's ones
+    callers: HashMap<u32, u32>,
+    /// CSeq numbers (the caller's and the trunk leg's) of the INVITE the trunk has accepted last. ACK gets the leg's one
+    accepted: Option<(u32, u32)>,
+}
+
+impl TrunkCSeqs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the CSeq number a request the caller has sent with `cseq` gets on the trunk leg.
+    /// The caller's number is kept as long as it's greater than the ones the server has used.
+    /// Retransmissions and CANCEL get the number of the request they belong to, ACK gets the number of the accepted INVITE
+    pub fn request(&mut self, method: Method, cseq: u32) -> u32 {
+        if let (Method::Ack, Some((caller, trunk))) = (method, self.accepted) {
+            if caller == cseq {
+                return trunk;
+            }
+        }
+        if let Some(trunk) = self
+            .callers
+            .iter()
+            .filter(|(_, caller)| **caller == cseq)
+            .map(|(trunk, _)| *trunk)
+            .max()
+        {
+            return trunk;
+        }
+        let trunk = if cseq > self.last {
+            cseq
+        } else {
+            self.last + 1
+        };
+        self.last = trunk;
+        self.callers.insert(trunk, cseq);
+        trunk
+    }
+
+    /// Returns the CSeq number of a request the server sends on the trunk leg again instead of the one sent with `cseq`
+    pub fn resend(&mut self, cseq: u32) -> u32 {
+        let caller = self.callers.get(&cseq).copied().unwrap_or(cseq);
+        self.last += 1;
+        self.callers.insert(self.last, caller);
+        self.last
+    }
+
+    /// Returns the caller's CSeq number of a response with `cseq` and `code` to a request with `method` on the trunk leg
+    pub fn response(&mut self, method: Method, code: u32, cseq: u32) -> u32 {
+        let caller = self.callers.get(&cseq).copied().unwrap_or(cseq);
+        if method == Method::Invite && (200..300).contains(&code) {
+            self.accepted = Some((caller, cseq));
+        }
+        caller
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caller_numbers_are_kept_until_a_request_is_resent() {
+        let mut cseqs = TrunkCSeqs::new();
+        assert_eq!(cseqs.request(Method::Invite, 5), 5);
+        assert_eq!(cseqs.response(Method::Invite, 200, 5), 5);
+        assert_eq!(cseqs.request(Method::Ack, 5), 5);
+        assert_eq!(cseqs.request(Method::Bye, 6), 6);
+        assert_eq!(cseqs.response(Method::Bye, 200, 6), 6);
+    }
+
+    #[test]
+    fn requests_after_a_challenge_get_numbers_of_the_trunk_leg() {
+        let mut cseqs = TrunkCSeqs::new();
+        assert_eq!(cseqs.request(Method::Invite, 1), 1);
+        assert_eq!(cseqs.response(Method::Invite, 407, 1), 1);
+        assert_eq!(cseqs.resend(1), 2);
+        // CANCEL goes with the resent INVITE
+        assert_eq!(cseqs.request(Method::Cancel, 1), 2);
+        assert_eq!(cseqs.response(Method::Invite, 200, 2), 1);
+        assert_eq!(cseqs.request(Method::Ack, 1), 2);
+        // The caller's re-INVITE has the number the resent INVITE has taken
+        assert_eq!(cseqs.request(Method::Invite, 2), 3);
+        assert_eq!(cseqs.response(Method::Invite, 200, 3), 2);
+        assert_eq!(cseqs.request(Method::Ack, 2), 3);
+        assert_eq!(cseqs.request(Method::Bye, 3), 4);
+        // A retransmission keeps its number
+        assert_eq!(cseqs.request(Method::Bye, 3), 4);
+        assert_eq!(cseqs.response(Method::Bye, 200, 4), 3);
+    }
+
+    #[test]
+    fn ack_gets_number_of_accepted_invite() {
+        let mut cseqs = TrunkCSeqs::new();
+        assert_eq!(cseqs.request(Method::Invite, 1), 1);
+        assert_eq!(cseqs.resend(1), 2);
+        // The next server has got the INVITE as the caller sent it
+        assert_eq!(cseqs.response(Method::Invite, 200, 1), 1);
+        assert_eq!(cseqs.request(Method::Ack, 1), 1);
+    }
+}
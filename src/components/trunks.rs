@@ -0,0 +1,197 @@
+use super::DigestCredentials;
+use libsip::{Domain, Transport, Uri, UriAuth, UriParam, UriSchema};
+
+/// Numbers a trunk takes
+#[derive(Clone, Debug, PartialEq)]
+pub enum NumberPattern {
+    /// Numbers starting with the prefix
+    Prefix(String),
+    /// Numbers the whole pattern matches: `X` is any digit, `Z` is 1-9, `N` is 2-9, `.` is one or more of any characters
+    /// (only at the end), other characters match themselves
+    Pattern(String),
+}
+
+impl NumberPattern {
+    pub fn matches(&self, number: &str) -> bool {
+        match self {
+            NumberPattern::Prefix(prefix) => number.starts_with(prefix.as_str()),
+            NumberPattern::Pattern(pattern) => {
+                let mut chars = number.chars();
+                for p in pattern.chars() {
+                    if p == '.' {
+                        return chars.next().is_some();
+                    }
+                    let c = if let Some(c) = chars.next() {
+                        c
+                    } else {
+                        return false;
+                    };
+                    let matched = match p.to_ascii_uppercase() {
+                        'X' => c.is_ascii_digit(),
+                        'Z' => ('1'..='9').contains(&c),
+                        'N' => ('2'..='9').contains(&c),
+                        p => p == c,
+                    };
+                    if !matched {
+                        return false;
+                    }
+                }
+                chars.next().is_none()
+            }
+        }
+    }
+}
+
+/// A carrier connection calls to numbers that aren't registered users go out through
+#[derive(Clone, Debug)]
+pub struct Trunk {
+    name: String,
+    /// The host (and the port) requests are sent to. It's located in DNS unless it's an IP address
+    host: Domain,
+    transport: Transport,
+    numbers: NumberPattern,
+    /// The credentials challenges of the trunk are answered with
+    credentials: Option<DigestCredentials>,
+    /// How many leading characters are removed from a number before it's sent to the trunk
+    strip: usize,
+    /// What is added in front of a number (after `strip`) before it's sent to the trunk
+    prepend: String,
+    /// The user of `From` the trunk expects (for example, the account's number)
+    from_user: Option<String>,
+}
+
+impl Trunk {
+    pub fn new(name: String, host: Domain, transport: Transport, numbers: NumberPattern) -> Self {
+        Self {
+            name,
+            host,
+            transport,
+            numbers,
+            credentials: None,
+            strip: 0,
+            prepend: String::new(),
+            from_user: None,
+        }
+    }
+
+    pub fn credentials(mut self, credentials: DigestCredentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn strip(mut self, strip: usize) -> Self {
+        self.strip = strip;
+        self
+    }
+
+    pub fn prepend(mut self, prepend: String) -> Self {
+        self.prepend = prepend;
+        self
+    }
+
+    pub fn from_user(mut self, from_user: String) -> Self {
+        self.from_user = Some(from_user);
+        self
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn host(&self) -> &Domain {
+        &self.host
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    pub fn digest_credentials(&self) -> Option<&DigestCredentials> {
+        self.credentials.as_ref()
+    }
+
+    pub fn matches(&self, number: &str) -> bool {
+        self.numbers.matches(number)
+    }
+
+    /// Returns `number` as the trunk expects it
+    pub fn dialed_number(&self, number: &str) -> String {
+        let rest = number.get(self.strip..).unwrap_or("");
+        format!("{}{}", self.prepend, rest)
+    }
+
+    /// Returns the Request-URI of a call to `number` through the trunk
+    pub fn request_uri(&self, number: &str) -> Uri {
+        let uri = Uri::new(UriSchema::Sip, self.host.clone())
+            .auth(UriAuth::new(self.dialed_number(number)));
+        match self.transport {
+            Transport::Udp => uri,
+            transport => uri.parameter(UriParam::Transport(transport)),
+        }
+    }
+
+    /// Rewrites the URI of `From` for the trunk: the host is the trunk's and the user is `from_user` if it's set
+    pub fn from_uri(&self, from: &Uri) -> Uri {
+        let mut uri = from.clone();
+        uri.host = self.host.clone();
+        if let Some(from_user) = &self.from_user {
+            uri.auth = Some(UriAuth::new(from_user.clone()));
+        }
+        uri
+    }
+}
+
+/// Trunks in the order they are tried
+/// # Examples
+/// ```
+/// use libsip::{Domain, Transport};
+/// use sip_server::{NumberPattern, Trunk, Trunks};
+///
+/// let host = Domain::Domain("sip.carrier.example".to_string(), None);
+/// let mut trunks = Trunks::new();
+/// trunks.add(Trunk::new("local".to_string(), host.clone(), Transport::Udp, NumberPattern::Pattern("NXXXXXX".to_string())));
+/// trunks.add(
+///     Trunk::new("international".to_string(), host, Transport::Tcp, NumberPattern::Prefix("00".to_string()))
+///         .strip(2)
+///         .prepend("+".to_string()),
+/// );
+///
+/// assert_eq!(trunks.find("5551234").unwrap().name(), "local");
+/// assert!(trunks.find("1551234").is_none());
+/// let trunk = trunks.find("004930123456").unwrap();
+/// assert_eq!(trunk.dialed_number("004930123456"), "+4930123456");
+///
+/// assert!(Trunks::is_number("+4930123456"));
+/// assert!(!Trunks::is_number("alice"));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Trunks(Vec<Trunk>);
+
+impl Trunks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, trunk: Trunk) {
+        self.0.push(trunk);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the first trunk that takes `number`
+    pub fn find(&self, number: &str) -> Option<&Trunk> {
+        self.0.iter().find(|trunk| trunk.matches(number))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Trunk> {
+        self.0.iter().find(|trunk| trunk.name() == name)
+    }
+
+    /// Returns `true` if `user` is a phone number: digits with an optional leading `+`
+    pub fn is_number(user: &str) -> bool {
+        let digits = user.strip_prefix('+').unwrap_or(user);
+        !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+    }
+}
@@ -6,6 +6,7 @@ mod client;
 mod client_worker;
 mod components;
 mod msg_router;
+mod responses;
 mod server;
 mod sip_parse;
 mod tcp_server;
@@ -14,7 +15,7 @@ mod utils;
 mod via_branch_generator;
 
 pub use self::{
-    client::{Client, ClientEvent, ClientEventHandler, ClientFactory},
+    client::{Access, Client, ClientEvent, ClientEventHandler, ClientFactory},
     components::*,
     server::Server,
     utils::Utils,
@@ -1,9 +1,12 @@
-use crate::{client_worker::ClientWorkerMessage, Receiver, Sender};
-use async_std::net::SocketAddr;
+use crate::{client_worker::ClientWorkerMessage, Flow, Receiver, Sender};
+use async_std::{net::SocketAddr, task};
 use futures::{SinkExt, StreamExt};
-use libsip::SipMessage;
+use libsip::{SipMessage, Transport};
 use log::error;
-use std::collections::{hash_map::Entry, HashMap};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::Duration,
+};
 
 /// Message handled by MsgRouter
 pub(crate) enum MsgRouterMsg {
@@ -14,31 +17,126 @@ pub(crate) enum MsgRouterMsg {
     },
     /// Message routed to be handled by another client worker
     RoutedMessage { addr: SocketAddr, msg: SipMessage },
+    /// Message routed to be handled by the client worker of `flow`, which is created by connecting to `flow` if it doesn't exist.
+    /// The message comes with the address of the client worker that has routed it. Without a message, only the connection is opened
+    Connect {
+        flow: Flow,
+        msg: Option<(SocketAddr, SipMessage)>,
+    },
+    /// Connecting to `flow` failed
+    ConnectFailed { flow: Flow },
+    /// The connection of the client worker of `addr` has been closed
+    Disconnected { addr: SocketAddr },
+    /// Timer of a client worker expired
+    Timer { addr: SocketAddr, id: u64 },
+}
+
+/// Sends [`MsgRouterMsg::Timer`](enum.MsgRouterMsg.html#variant.Timer) for the client worker of `addr` after `after`
+pub(crate) fn set_timer(
+    mut sender: Sender<MsgRouterMsg>,
+    addr: SocketAddr,
+    after: Duration,
+    id: u64,
+) {
+    task::spawn(async move {
+        task::sleep(after).await;
+        if let Err(e) = sender.send(MsgRouterMsg::Timer { addr, id }).await {
+            error!("send failed: {}", e);
+        }
+    });
 }
 
 /// Reads incoming messages and routes them to matching clients
 pub(crate) struct MsgRouter {
     receiver: Receiver<MsgRouterMsg>,
     senders: HashMap<SocketAddr, Sender<ClientWorkerMessage>>,
+    /// The sender it asks the UDP server to create a client worker for an address with
+    udp_connect_sender: Sender<SocketAddr>,
+    /// The sender it asks the TCP server to connect to an address with
+    tcp_connect_sender: Sender<SocketAddr>,
+    /// Messages waiting for connections to be established with the addresses of the client workers that have routed them
+    pending: HashMap<SocketAddr, Vec<(SocketAddr, SipMessage)>>,
 }
 
 impl MsgRouter {
-    pub fn new(receiver: Receiver<MsgRouterMsg>) -> Self {
+    pub fn new(
+        receiver: Receiver<MsgRouterMsg>,
+        udp_connect_sender: Sender<SocketAddr>,
+        tcp_connect_sender: Sender<SocketAddr>,
+    ) -> Self {
         Self {
             receiver,
             senders: HashMap::new(),
+            udp_connect_sender,
+            tcp_connect_sender,
+            pending: HashMap::new(),
         }
     }
 
     pub async fn run(mut self) {
         while let Some(msg) = self.receiver.next().await {
             match msg {
-                MsgRouterMsg::ClientWorker { addr, sender } => self.add_client_worker(addr, sender),
+                MsgRouterMsg::ClientWorker { addr, sender } => {
+                    self.add_client_worker(addr, sender);
+                    for (_, msg) in self.pending.remove(&addr).unwrap_or_default() {
+                        self.send_to_client_worker(addr, ClientWorkerMessage::Routed(msg))
+                            .await;
+                    }
+                }
                 MsgRouterMsg::RoutedMessage { addr, msg } => {
                     self.send_to_client_worker(addr, ClientWorkerMessage::Routed(msg))
                         .await;
                 }
+                MsgRouterMsg::Connect { flow, msg } => self.connect(flow, msg).await,
+                MsgRouterMsg::ConnectFailed { flow } => {
+                    // The messages go back to the clients that have routed them, so that they can try other targets
+                    for (origin, msg) in self.pending.remove(&flow.addr()).unwrap_or_default() {
+                        self.send_to_client_worker(
+                            origin,
+                            ClientWorkerMessage::RouteFailed { flow, msg },
+                        )
+                        .await;
+                    }
+                }
+                // Dropping the sender stops the client worker
+                MsgRouterMsg::Disconnected { addr } => {
+                    self.senders.remove(&addr);
+                }
+                MsgRouterMsg::Timer { addr, id } => {
+                    self.send_to_client_worker(addr, ClientWorkerMessage::Timer(id))
+                        .await;
+                }
+            }
+        }
+    }
+
+    async fn connect(&mut self, flow: Flow, msg: Option<(SocketAddr, SipMessage)>) {
+        let addr = flow.addr();
+        if self.senders.contains_key(&addr) {
+            if let Some((_, msg)) = msg {
+                self.send_to_client_worker(addr, ClientWorkerMessage::Routed(msg))
+                    .await;
+            }
+            return;
+        }
+        // The connection is already being established
+        let connecting = self.pending.contains_key(&addr);
+        self.pending.entry(addr).or_default().extend(msg);
+        if connecting {
+            return;
+        }
+        let sender = match flow.transport() {
+            Transport::Udp => &mut self.udp_connect_sender,
+            Transport::Tcp => &mut self.tcp_connect_sender,
+            transport => {
+                error!("can't connect to {} over {:?}", addr, transport);
+                self.pending.remove(&addr);
+                return;
             }
+        };
+        if let Err(e) = sender.send(addr).await {
+            error!("send failed: {}", e);
+            self.pending.remove(&addr);
         }
     }
 
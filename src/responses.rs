@@ -0,0 +1,36 @@
+use libsip::{Header, ResponseGenerator, SipMessage};
+use log::error;
+
+/// Creates a response with `code` to `req` without involving any client (for example, to reject a request).
+/// Returns `None` if `req` isn't a request
+pub(crate) fn create(req: &SipMessage, code: u32) -> Option<SipMessage> {
+    let headers = if let SipMessage::Request { headers, .. } = req {
+        headers
+    } else {
+        return None;
+    };
+    let headers = headers
+        .0
+        .iter()
+        .filter_map(|h| match h {
+            // 8.2.6.2 Headers and Tags
+            Header::Via(_) | Header::From(_) | Header::CallId(_) | Header::CSeq(_, _) => {
+                Some(h.clone())
+            }
+            Header::To(h) => Some(Header::To(h.clone().param("tag", Some("123456")))),
+            _ => None,
+        })
+        .collect();
+    match ResponseGenerator::new()
+        .code(code)
+        .headers(headers)
+        .header(Header::ContentLength(0))
+        .build()
+    {
+        Ok(res) => Some(res),
+        Err(e) => {
+            error!("failed to generate response: {}", e);
+            None
+        }
+    }
+}
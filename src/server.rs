@@ -1,6 +1,12 @@
-use crate::{msg_router::MsgRouter, tcp_server::TcpServer, udp_server::UdpServer, ClientFactory};
-use async_std::net::SocketAddr;
+use crate::{
+    msg_router::{MsgRouter, MsgRouterMsg},
+    tcp_server::TcpServer,
+    udp_server::UdpServer,
+    ClientFactory, Sender,
+};
+use async_std::{net::SocketAddr, task};
 use futures::{channel::mpsc, join};
+use log::error;
 
 pub struct Server;
 
@@ -10,7 +16,10 @@ impl Server {
         F: ClientFactory + 'static,
     {
         let (sender, receiver) = mpsc::unbounded();
-        let message_router_fut = MsgRouter::new(receiver).run();
+        let (udp_connect_sender, udp_connect_receiver) = mpsc::unbounded();
+        let (tcp_connect_sender, tcp_connect_receiver) = mpsc::unbounded();
+        let message_router_fut =
+            MsgRouter::new(receiver, udp_connect_sender, tcp_connect_sender).run();
 
         let factory = unsafe {
             let factory: *const F = &factory;
@@ -18,11 +27,41 @@ impl Server {
             factory
         };
 
-        let udp_server_fut = UdpServer::run(factory, addr, sender.clone());
+        let reconnect_fut = Self::connect_initial_flows(factory, sender.clone());
 
-        let tcp_server = TcpServer::new(factory, addr, sender);
+        let udp_server_fut = UdpServer::run(factory, addr, sender.clone(), udp_connect_receiver);
+
+        let tcp_server = TcpServer::new(factory, addr, sender, tcp_connect_receiver);
         let tcp_server_fut = tcp_server.run();
 
-        join!(message_router_fut, udp_server_fut, tcp_server_fut);
+        join!(
+            message_router_fut,
+            reconnect_fut,
+            udp_server_fut,
+            tcp_server_fut
+        );
+    }
+
+    /// Connects to the initial flows of the factory and keeps connecting to the ones without connections,
+    /// so that a flow that couldn't be reached or has been disconnected gets a client again
+    async fn connect_initial_flows<F: ClientFactory>(factory: &F, sender: Sender<MsgRouterMsg>) {
+        let flows = factory.initial_flows();
+        if flows.is_empty() {
+            return;
+        }
+        loop {
+            // Connecting to a flow that has a connection (or is being connected to) does nothing
+            for flow in flows.iter() {
+                let msg = MsgRouterMsg::Connect {
+                    flow: *flow,
+                    msg: None,
+                };
+                if let Err(e) = sender.unbounded_send(msg) {
+                    error!("send failed: {}", e);
+                    return;
+                }
+            }
+            task::sleep(factory.reconnect_interval()).await;
+        }
     }
 }
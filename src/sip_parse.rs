@@ -1,4 +1,4 @@
-use libsip::SipMessage;
+use libsip::{SipMessage, Uri};
 use log::error;
 use nom::{error::VerboseError, Err};
 
@@ -12,6 +12,16 @@ pub(crate) fn parse(buffer: &[u8]) -> Option<SipMessage> {
     }
 }
 
+pub(crate) fn parse_uri(input: &str) -> Option<Uri> {
+    match libsip::parse_uri::<VerboseError<&[u8]>>(input.as_bytes()) {
+        Ok((_, uri)) => Some(uri),
+        Err(e) => {
+            on_parse_error(e);
+            None
+        }
+    }
+}
+
 fn on_parse_error(err: Err<VerboseError<&[u8]>>) {
     if let nom::Err::Error(VerboseError { errors }) = err {
         for e in errors {
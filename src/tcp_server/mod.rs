@@ -1,13 +1,15 @@
-use crate::{msg_router::MsgRouterMsg, ClientFactory, Sender};
+use crate::{msg_router::MsgRouterMsg, Access, ClientFactory, Receiver, Sender};
 use async_std::{
     net::{SocketAddr, TcpListener, TcpStream},
     task::{self, JoinHandle},
 };
-use futures::StreamExt;
+use futures::{select, FutureExt, StreamExt};
 use log::{error, info};
 mod msg_read;
 mod tcp_client_event_handler;
+mod tcp_stream_connecting_worker;
 mod tcp_stream_reader;
+mod tcp_stream_rejecting_worker;
 mod tcp_stream_waiting_worker;
 mod tcp_stream_worker;
 
@@ -15,15 +17,23 @@ pub(crate) struct TcpServer<F: 'static> {
     factory: &'static F,
     addr: SocketAddr,
     sender: Sender<MsgRouterMsg>,
+    /// The receiver it gets addresses to connect to with
+    connect_receiver: Receiver<SocketAddr>,
     worker_handles: Vec<JoinHandle<()>>,
 }
 
 impl<F: ClientFactory + 'static> TcpServer<F> {
-    pub fn new(factory: &'static F, addr: SocketAddr, sender: Sender<MsgRouterMsg>) -> Self {
+    pub fn new(
+        factory: &'static F,
+        addr: SocketAddr,
+        sender: Sender<MsgRouterMsg>,
+        connect_receiver: Receiver<SocketAddr>,
+    ) -> Self {
         Self {
             factory,
             addr,
             sender,
+            connect_receiver,
             worker_handles: Vec::new(),
         }
     }
@@ -40,22 +50,52 @@ impl<F: ClientFactory + 'static> TcpServer<F> {
             .await
             .expect("failed to bind tcp listener");
         let mut incoming = listener.incoming();
-        while let Some(stream) = incoming.next().await {
-            match stream {
-                Ok(stream) => self.on_stream(stream),
-                Err(e) => error!("tcp stream error: {}", e),
+        loop {
+            select! {
+                stream = incoming.next().fuse() => match stream {
+                    Some(Ok(stream)) => self.on_stream(stream),
+                    Some(Err(e)) => error!("tcp stream error: {}", e),
+                    None => return,
+                },
+                addr = self.connect_receiver.next() => {
+                    if let Some(addr) = addr {
+                        self.connect(addr);
+                    }
+                }
             }
         }
     }
 
+    /// Opens a connection to `addr`, so that messages can be sent to an address that hasn't connected to the server
+    fn connect(&mut self, addr: SocketAddr) {
+        info!("connecting to {}", addr);
+        let fut = tcp_stream_connecting_worker::run(addr, self.factory, self.sender.clone());
+        self.worker_handles.push(task::spawn(fut));
+    }
+
     fn on_stream(&mut self, stream: TcpStream) {
         match stream.peer_addr() {
-            Ok(addr) => {
-                info!("new tcp connection: {}", addr);
-                let fut =
-                    tcp_stream_waiting_worker::run(addr, stream, self.factory, self.sender.clone());
-                self.worker_handles.push(task::spawn(fut));
+            Ok(addr) if self.factory.is_blocked(addr) => {
+                info!("dropped tcp connection from blocked {}", addr)
             }
+            Ok(addr) => match self.factory.access(addr) {
+                Access::Allow => {
+                    info!("new tcp connection: {}", addr);
+                    let fut = tcp_stream_waiting_worker::run(
+                        addr,
+                        stream,
+                        self.factory,
+                        self.sender.clone(),
+                    );
+                    self.worker_handles.push(task::spawn(fut));
+                }
+                // Dropping the stream closes the connection
+                Access::Drop => info!("dropped tcp connection: {}", addr),
+                Access::Reject => {
+                    info!("rejected tcp connection: {}", addr);
+                    task::spawn(tcp_stream_rejecting_worker::run(stream));
+                }
+            },
             Err(e) => {
                 error!("peer_addr failed: {}", e);
             }
@@ -3,9 +3,12 @@ use async_std::{io::ReadExt, net::TcpStream};
 use libsip::SipMessage;
 use log::error;
 
-pub async fn read_msg(stream: &mut &TcpStream, buffer: &mut [u8]) -> SipMessage {
+/// Reads messages until one can be parsed. Returns `None` if the connection is closed or broken
+pub async fn read_msg(stream: &mut &TcpStream, buffer: &mut [u8]) -> Option<SipMessage> {
     loop {
         let packet_len = match stream.read(buffer).await {
+            // The peer has closed the connection
+            Ok(0) => return None,
             Ok(n) => {
                 // It's impossible for SIP message to fit in 4 bytes.
                 // However, 3CXPhone sometimes (every 30 seconds) sends a packet whose content is "\r\n\r\n".
@@ -19,11 +22,11 @@ pub async fn read_msg(stream: &mut &TcpStream, buffer: &mut [u8]) -> SipMessage
             }
             Err(e) => {
                 error!("read failed: {}", e);
-                continue;
+                return None;
             }
         };
         if let Some(msg) = sip_parse::parse(&buffer[..packet_len]) {
-            return msg;
+            return Some(msg);
         } else {
             error!("parse failed");
         }
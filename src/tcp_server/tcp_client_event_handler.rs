@@ -1,17 +1,29 @@
-use crate::{msg_router::MsgRouterMsg, ClientEvent, ClientEventHandler, Sender};
-use async_std::{net::TcpStream, prelude::*, sync::Arc};
+use crate::{
+    msg_router::{self, MsgRouterMsg},
+    ClientEvent, ClientEventHandler, Sender,
+};
+use async_std::{
+    net::{SocketAddr, TcpStream},
+    prelude::*,
+    sync::Arc,
+};
 use async_trait::async_trait;
 use futures::SinkExt;
 use log::error;
 
 pub(crate) struct TcpClientEventHandler {
+    addr: SocketAddr,
     stream: Arc<TcpStream>,
     sender: Sender<MsgRouterMsg>,
 }
 
 impl<'a> TcpClientEventHandler {
-    pub fn new(stream: Arc<TcpStream>, sender: Sender<MsgRouterMsg>) -> Self {
-        Self { stream, sender }
+    pub fn new(addr: SocketAddr, stream: Arc<TcpStream>, sender: Sender<MsgRouterMsg>) -> Self {
+        Self {
+            addr,
+            stream,
+            sender,
+        }
     }
 }
 
@@ -25,6 +37,15 @@ impl ClientEventHandler for TcpClientEventHandler {
                     error!("send failed: {}", e);
                 }
             }
+            ClientEvent::Connect { flow, msg } => {
+                let msg = MsgRouterMsg::Connect {
+                    flow,
+                    msg: Some((self.addr, msg)),
+                };
+                if let Err(e) = self.sender.send(msg).await {
+                    error!("send failed: {}", e);
+                }
+            }
             ClientEvent::Send(message) => {
                 let message = message.to_string();
                 let bytes = message.as_bytes();
@@ -33,6 +54,9 @@ impl ClientEventHandler for TcpClientEventHandler {
                     error!("write_all failed: {}", e);
                 }
             }
+            ClientEvent::SetTimer { after, id } => {
+                msg_router::set_timer(self.sender.clone(), self.addr, after, id);
+            }
         }
     }
 }
@@ -0,0 +1,31 @@
+use super::tcp_stream_worker::TcpStreamWorker;
+use crate::{msg_router::MsgRouterMsg, ClientFactory, Flow, Sender};
+use async_std::net::{SocketAddr, TcpStream};
+use futures::SinkExt;
+use libsip::Transport;
+use log::{error, info};
+
+/// Opens a connection to `addr` and handles it like an accepted one.
+/// If connecting fails, [`MsgRouter`](../../msg_router/struct.MsgRouter.html) is told to return the messages waiting for the connection
+/// to the clients that have routed them
+pub(crate) async fn run<F: ClientFactory + 'static>(
+    addr: SocketAddr,
+    factory: &'static F,
+    mut sender: Sender<MsgRouterMsg>,
+) {
+    match TcpStream::connect(addr).await {
+        Ok(stream) => {
+            info!("new outgoing tcp connection: {}", addr);
+            let worker = TcpStreamWorker::new(addr, stream, factory, sender);
+            worker.run(None).await;
+        }
+        Err(e) => {
+            error!("connect to {} failed: {}", addr, e);
+            let flow = Flow::new(Transport::Tcp, addr);
+            factory.on_connect_failed(flow);
+            if let Err(e) = sender.send(MsgRouterMsg::ConnectFailed { flow }).await {
+                error!("send failed: {}", e);
+            }
+        }
+    }
+}
@@ -1,14 +1,36 @@
 use super::msg_read;
-use crate::{client_worker::ClientWorkerMessage, Sender};
-use async_std::{net::TcpStream, sync::Arc};
+use crate::{client_worker::ClientWorkerMessage, ClientFactory, Sender};
+use async_std::{
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::Arc,
+};
 use futures::SinkExt;
-use log::error;
+use log::{error, info};
 
-pub(crate) async fn run(stream: Arc<TcpStream>, mut sender: Sender<ClientWorkerMessage>) {
+pub(crate) async fn run<F: ClientFactory>(
+    addr: SocketAddr,
+    stream: Arc<TcpStream>,
+    factory: &F,
+    mut sender: Sender<ClientWorkerMessage>,
+) {
     let mut buffer = [0; 4096];
     let mut stream: &TcpStream = &stream;
     loop {
         let msg = msg_read::read_msg(&mut stream, &mut buffer).await;
+        // Checked after reading, so that a message that arrives after a ban isn't delivered
+        if factory.is_blocked(addr) {
+            info!("closed tcp connection from blocked {}", addr);
+            if let Err(e) = stream.shutdown(Shutdown::Both) {
+                error!("shutdown failed: {}", e);
+            }
+            return;
+        }
+        let msg = if let Some(msg) = msg {
+            msg
+        } else {
+            info!("tcp connection closed: {}", addr);
+            return;
+        };
         let msg = ClientWorkerMessage::Received(msg);
         if let Err(e) = sender.send(msg).await {
             error!("send failed: {}", e);
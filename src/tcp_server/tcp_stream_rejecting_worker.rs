@@ -0,0 +1,28 @@
+use crate::{responses, sip_parse};
+use async_std::{
+    net::{Shutdown, TcpStream},
+    prelude::*,
+};
+use log::error;
+
+/// Answers the first request of a connection that isn't allowed with 403 (Forbidden) and closes the connection.
+/// Only a single read is done, so that the connection is closed on EOF, an error or garbage
+pub(crate) async fn run(stream: TcpStream) {
+    let mut buffer = [0; 4096];
+    let mut stream = &stream;
+    match stream.read(&mut buffer).await {
+        Ok(0) => {}
+        Ok(n) => {
+            let res = sip_parse::parse(&buffer[..n]).and_then(|msg| responses::create(&msg, 403));
+            if let Some(res) = res {
+                if let Err(e) = stream.write_all(res.to_string().as_bytes()).await {
+                    error!("write_all failed: {}", e);
+                }
+            }
+        }
+        Err(e) => error!("read failed: {}", e),
+    }
+    if let Err(e) = stream.shutdown(Shutdown::Both) {
+        error!("shutdown failed: {}", e);
+    }
+}
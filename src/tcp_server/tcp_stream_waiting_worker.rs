@@ -1,6 +1,7 @@
 use super::{msg_read, tcp_stream_worker::TcpStreamWorker};
 use crate::{msg_router::MsgRouterMsg, ClientFactory, Sender};
 use async_std::net::{SocketAddr, TcpStream};
+use log::info;
 
 pub(crate) async fn run<F: ClientFactory + 'static>(
     addr: SocketAddr,
@@ -9,8 +10,13 @@ pub(crate) async fn run<F: ClientFactory + 'static>(
     sender: Sender<MsgRouterMsg>,
 ) {
     let mut buffer = [0; 4096];
-    let msg = msg_read::read_msg(&mut &stream, &mut buffer).await;
+    let msg = if let Some(msg) = msg_read::read_msg(&mut &stream, &mut buffer).await {
+        msg
+    } else {
+        info!("tcp connection closed: {}", addr);
+        return;
+    };
 
     let worker = TcpStreamWorker::new(addr, stream, factory, sender);
-    worker.run(msg).await;
+    worker.run(Some(msg)).await;
 }
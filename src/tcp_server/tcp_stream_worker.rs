@@ -2,7 +2,7 @@ use super::{tcp_client_event_handler::TcpClientEventHandler, tcp_stream_reader};
 use crate::{
     client_worker::{ClientWorker, ClientWorkerMessage},
     msg_router::MsgRouterMsg,
-    ClientFactory, Sender,
+    ClientFactory, Flow, Sender,
 };
 use async_std::{
     net::{SocketAddr, TcpStream},
@@ -10,7 +10,7 @@ use async_std::{
     task::{self, JoinHandle},
 };
 use futures::{channel::mpsc, SinkExt};
-use libsip::SipMessage;
+use libsip::{SipMessage, Transport};
 use log::error;
 
 pub(crate) struct TcpStreamWorker<F: 'static> {
@@ -35,12 +35,15 @@ impl<F: ClientFactory + 'static> TcpStreamWorker<F> {
         }
     }
 
-    pub async fn run(mut self, msg: SipMessage) {
+    /// Handles the connection whose first message is `msg` (`None` if the server has opened the connection)
+    pub async fn run(mut self, msg: Option<SipMessage>) {
         let (client_worker_handle, mut client_worker_sender) = self.spawn_client_worker();
 
-        let cwm = ClientWorkerMessage::Received(msg);
-        if let Err(e) = client_worker_sender.send(cwm).await {
-            error!("failed to send cwm: {}", e);
+        if let Some(msg) = msg {
+            let cwm = ClientWorkerMessage::Received(msg);
+            if let Err(e) = client_worker_sender.send(cwm).await {
+                error!("failed to send cwm: {}", e);
+            }
         }
 
         let mrm = MsgRouterMsg::ClientWorker {
@@ -51,17 +54,27 @@ impl<F: ClientFactory + 'static> TcpStreamWorker<F> {
             error!("failed to send mrm: {}", e);
         }
 
-        tcp_stream_reader::run(self.stream, client_worker_sender).await;
+        tcp_stream_reader::run(self.addr, self.stream, self.factory, client_worker_sender).await;
+
+        self.factory
+            .on_disconnected(Flow::new(Transport::Tcp, self.addr));
+        let mrm = MsgRouterMsg::Disconnected { addr: self.addr };
+        if let Err(e) = self.sender.send(mrm).await {
+            error!("failed to send mrm: {}", e);
+        }
 
         client_worker_handle.await;
     }
 
     fn spawn_client_worker(&self) -> (JoinHandle<()>, Sender<ClientWorkerMessage>) {
         let handler = Box::new(TcpClientEventHandler::new(
+            self.addr,
             self.stream.clone(),
             self.sender.clone(),
         ));
-        let client = self.factory.create_client(self.addr, handler);
+        let client = self
+            .factory
+            .create_client(self.addr, Transport::Tcp, handler);
 
         let (sender, receiver) = mpsc::unbounded();
 
@@ -3,7 +3,7 @@ mod udp_socket_reader;
 mod udp_socket_writer;
 
 use self::udp_socket_reader::UdpSocketReader;
-use crate::{msg_router::MsgRouterMsg, ClientFactory, Sender};
+use crate::{msg_router::MsgRouterMsg, ClientFactory, Receiver, Sender};
 use async_std::net::{SocketAddr, UdpSocket};
 use futures::{channel::mpsc, join};
 
@@ -14,6 +14,7 @@ impl UdpServer {
         factory: &F,
         address: SocketAddr,
         message_router_sender: Sender<MsgRouterMsg>,
+        connect_receiver: Receiver<SocketAddr>,
     ) {
         let socket = UdpSocket::bind(address)
             .await
@@ -26,6 +27,7 @@ impl UdpServer {
             message_router_sender.clone(),
             socket_writer_sender,
             factory,
+            connect_receiver,
         )
         .run();
 
@@ -1,5 +1,8 @@
 use super::udp_socket_writer::UdpSocketWriterMessage;
-use crate::{msg_router::MsgRouterMsg, ClientEvent, ClientEventHandler, Sender};
+use crate::{
+    msg_router::{self, MsgRouterMsg},
+    ClientEvent, ClientEventHandler, Sender,
+};
 use async_std::net::SocketAddr;
 use async_trait::async_trait;
 use futures::SinkExt;
@@ -34,6 +37,13 @@ impl ClientEventHandler for UdpClientEventHandler {
                 let msg = MsgRouterMsg::RoutedMessage { addr, msg };
                 self.message_router_sender.send(msg).await
             }
+            ClientEvent::Connect { flow, msg } => {
+                let msg = MsgRouterMsg::Connect {
+                    flow,
+                    msg: Some((self.addr, msg)),
+                };
+                self.message_router_sender.send(msg).await
+            }
             ClientEvent::Send(msg) => {
                 let msg = UdpSocketWriterMessage {
                     addr: self.addr,
@@ -41,6 +51,10 @@ impl ClientEventHandler for UdpClientEventHandler {
                 };
                 self.socket_writer_sender.send(msg).await
             }
+            ClientEvent::SetTimer { after, id } => {
+                msg_router::set_timer(self.message_router_sender.clone(), self.addr, after, id);
+                Ok(())
+            }
         };
         if let Err(e) = result {
             error!("UdpClientEventHandler::handle: send failed: {}", e);
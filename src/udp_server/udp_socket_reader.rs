@@ -4,14 +4,14 @@ use super::{
 use crate::{
     client_worker::{ClientWorker, ClientWorkerMessage},
     msg_router::MsgRouterMsg,
-    sip_parse, ClientFactory, Sender,
+    responses, sip_parse, Access, ClientFactory, Receiver, Sender,
 };
 use async_std::{
     net::{SocketAddr, UdpSocket},
     task::{self, JoinHandle},
 };
-use futures::{channel::mpsc, SinkExt};
-use libsip::SipMessage;
+use futures::{channel::mpsc, future::Either, pin_mut, select, FutureExt, SinkExt, StreamExt};
+use libsip::{SipMessage, Transport};
 use log::{debug, error};
 use std::collections::HashMap;
 
@@ -27,6 +27,8 @@ pub(crate) struct UdpSocketReader<'a, F> {
     factory: &'a F,
     /// List of connected clients used to send received messages to
     client_workers: HashMap<SocketAddr, (Sender<ClientWorkerMessage>, JoinHandle<()>)>,
+    /// The receiver it gets addresses to create clients for with, so that messages can be sent to addresses that haven't sent anything
+    connect_receiver: Receiver<SocketAddr>,
 }
 
 impl<'a, F: ClientFactory> UdpSocketReader<'a, F> {
@@ -35,6 +37,7 @@ impl<'a, F: ClientFactory> UdpSocketReader<'a, F> {
         message_router_sender: Sender<MsgRouterMsg>,
         socket_writer_sender: Sender<UdpSocketWriterMessage>,
         factory: &'a F,
+        connect_receiver: Receiver<SocketAddr>,
     ) -> Self {
         Self {
             socket,
@@ -42,6 +45,7 @@ impl<'a, F: ClientFactory> UdpSocketReader<'a, F> {
             socket_writer_sender,
             factory,
             client_workers: HashMap::new(),
+            connect_receiver,
         }
     }
 
@@ -55,18 +59,54 @@ impl<'a, F: ClientFactory> UdpSocketReader<'a, F> {
     async fn read_msgs(&mut self) {
         let mut buffer = [0; 4096];
         loop {
-            let (addr, msg) = self.read_msg(&mut buffer).await;
-            if !self.client_workers.contains_key(&addr) {
-                self.spawn_client_worker(addr).await;
+            let event = {
+                let read = Self::read_msg(self.socket, self.factory, &mut buffer).fuse();
+                pin_mut!(read);
+                select! {
+                    received = read => Either::Left(received),
+                    addr = self.connect_receiver.next() => Either::Right(addr),
+                }
+            };
+            match event {
+                Either::Left((addr, msg)) => self.on_msg(addr, msg).await,
+                Either::Right(Some(addr)) => {
+                    if !self.client_workers.contains_key(&addr) {
+                        self.spawn_client_worker(addr).await;
+                    }
+                }
+                Either::Right(None) => {}
+            }
+        }
+    }
+
+    async fn on_msg(&mut self, addr: SocketAddr, msg: SipMessage) {
+        if !self.client_workers.contains_key(&addr) {
+            match self.factory.access(addr) {
+                Access::Allow => self.spawn_client_worker(addr).await,
+                Access::Drop => {
+                    debug!("dropped message from {}", addr);
+                    return;
+                }
+                Access::Reject => {
+                    self.reject(addr, &msg).await;
+                    return;
+                }
             }
-            let (sender, _) = self.client_workers.get_mut(&addr).unwrap();
-            Self::send_to_client_worker(msg, sender).await;
         }
+        let (sender, _) = self.client_workers.get_mut(&addr).unwrap();
+        Self::send_to_client_worker(msg, sender).await;
     }
 
-    async fn read_msg(&self, buffer: &mut [u8]) -> (SocketAddr, SipMessage) {
+    async fn read_msg(
+        socket: &UdpSocket,
+        factory: &F,
+        buffer: &mut [u8],
+    ) -> (SocketAddr, SipMessage) {
         loop {
-            match self.socket.recv_from(buffer).await {
+            match socket.recv_from(buffer).await {
+                Ok((n, addr)) if factory.is_blocked(addr) => {
+                    debug!("dropped {} bytes from blocked {}", n, addr);
+                }
                 Ok((n, addr)) => {
                     // It's impossible for SIP message to fit in 4 bytes.
                     // However, 3CXPhone sometimes (every 30 seconds) sends a packet whose content is "\r\n\r\n".
@@ -94,7 +134,9 @@ impl<'a, F: ClientFactory> UdpSocketReader<'a, F> {
             self.message_router_sender.clone(),
             self.socket_writer_sender.clone(),
         ));
-        let client = self.factory.create_client(addr, event_handler);
+        let client = self
+            .factory
+            .create_client(addr, Transport::Udp, event_handler);
 
         let (sender, receiver) = mpsc::unbounded();
 
@@ -106,6 +148,17 @@ impl<'a, F: ClientFactory> UdpSocketReader<'a, F> {
         self.client_workers.insert(addr, (sender, handle));
     }
 
+    /// Answers a request from a source that isn't allowed with 403 (Forbidden)
+    async fn reject(&mut self, addr: SocketAddr, msg: &SipMessage) {
+        debug!("rejected message from {}", addr);
+        if let Some(msg) = responses::create(msg, 403) {
+            let msg = UdpSocketWriterMessage { addr, msg };
+            if let Err(e) = self.socket_writer_sender.send(msg).await {
+                error!("send failed: {}", e);
+            }
+        }
+    }
+
     async fn send_to_client_worker(msg: SipMessage, sender: &mut Sender<ClientWorkerMessage>) {
         let msg = ClientWorkerMessage::Received(msg);
         if let Err(e) = sender.send(msg).await {
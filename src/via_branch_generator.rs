@@ -1,3 +1,9 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+};
+
 #[derive(Default)]
 pub struct ViaBranchGenerator {
     counter: u64,
@@ -15,4 +21,14 @@ impl ViaBranchGenerator {
         self.counter += 1;
         branch
     }
+
+    /// Returns the branch a proxy uses to forward a request with `branch` to `target`.
+    /// The same request (as well as its CANCEL and ACK for a non-2xx response) always gets the same branch,
+    /// while the requests to different targets get different branches, as per https://tools.ietf.org/html/rfc3261#section-16.11
+    pub fn derive(branch: &str, target: SocketAddr) -> String {
+        let mut hasher = DefaultHasher::new();
+        branch.hash(&mut hasher);
+        target.hash(&mut hasher);
+        format!("z9hG4bK-{:x}", hasher.finish())
+    }
 }